@@ -0,0 +1,136 @@
+//! Device configuration persisted in the last flash sector.
+//!
+//! `main.rs` answers `CONFIG_GET`/`CONFIG_SET` here before a request reaches
+//! the transport. Stored values are power-up defaults: the transports read
+//! them once at boot and runtime requests such as `TELEMETRY_SET` do not
+//! change them.
+
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::Peri;
+use rp2040_fred_protocol::bridge_proto::{
+    ConfigGetPayload, ConfigPayload, ConfigSetPayload, MsgType, NackReason, Packet, PacketRef,
+};
+use rp2040_fred_protocol::config::{
+    ConfigEntries, ConfigRecordError, DeviceConfig, CONFIG_RECORD_MAX_SIZE,
+};
+use rp2040_fred_protocol::device_log::{LogId, LogLevel};
+
+use crate::device_log;
+use crate::transport::ReplySink;
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Excluded from the image by `memory.x`.
+const CONFIG_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+pub struct ConfigStore {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+    config: DeviceConfig,
+}
+
+impl ConfigStore {
+    /// Loads the stored record, falling back to defaults if the sector is
+    /// blank, corrupt or from newer firmware.
+    pub fn new(flash: Peri<'static, FLASH>) -> Self {
+        let mut flash = Flash::new_blocking(flash);
+        let mut record = [0u8; CONFIG_RECORD_MAX_SIZE];
+        let config = match flash.blocking_read(CONFIG_OFFSET, &mut record) {
+            Ok(()) => match DeviceConfig::decode_record(&record) {
+                Ok(config) => config,
+                Err(ConfigRecordError::Missing) => DeviceConfig::default(),
+                Err(e) => {
+                    let version = match e {
+                        ConfigRecordError::UnsupportedVersion(v) => v as u32,
+                        _ => 0,
+                    };
+                    device_log::record(LogLevel::Warn, LogId::ConfigUnreadable, version);
+                    DeviceConfig::default()
+                }
+            },
+            Err(_) => DeviceConfig::default(),
+        };
+        Self { flash, config }
+    }
+
+    pub fn config(&self) -> &DeviceConfig {
+        &self.config
+    }
+
+    pub fn handles(msg_type: MsgType) -> bool {
+        matches!(msg_type, MsgType::ConfigGet | MsgType::ConfigSet)
+    }
+
+    pub async fn handle_request(&mut self, req: PacketRef<'_>, replies: &mut impl ReplySink) {
+        match req.msg_type {
+            MsgType::ConfigGet => {
+                let Ok(get) = req.decode_payload::<ConfigGetPayload>() else {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::ConfigGet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                    return;
+                };
+                let entries = match get.key {
+                    Some(key) => {
+                        let mut entries = ConfigEntries::new();
+                        entries.push(self.config.get(key).into());
+                        entries
+                    }
+                    None => self.config.entries(),
+                };
+                replies
+                    .send_with_ack(
+                        Packet::from_payload(req.seq, &ConfigPayload { entries }),
+                        MsgType::ConfigGet,
+                    )
+                    .await;
+            }
+            MsgType::ConfigSet => {
+                let mut next = self.config;
+                let applied = req
+                    .decode_payload::<ConfigSetPayload>()
+                    .ok()
+                    .and_then(|set| next.apply(set.entries.as_slice()).ok());
+                if applied.is_none() {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::ConfigSet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                    return;
+                }
+                if next != self.config && self.save(&next).is_err() {
+                    device_log::record(LogLevel::Error, LogId::ConfigSaveFailed, 0);
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::ConfigSet as u8,
+                            NackReason::Storage,
+                        ))
+                        .await;
+                    return;
+                }
+                self.config = next;
+                replies
+                    .send(Packet::ack(req.seq, MsgType::ConfigSet, 0))
+                    .await;
+            }
+            _ => {}
+        }
+    }
+
+    /// Erasing stalls XIP for tens of milliseconds; embassy-rp parks core1
+    /// meanwhile, so trace samples are dropped while a save is in progress.
+    fn save(&mut self, config: &DeviceConfig) -> Result<(), embassy_rp::flash::Error> {
+        let mut record = [0xFFu8; CONFIG_RECORD_MAX_SIZE];
+        let len = config.encode_record(&mut record);
+        self.flash
+            .blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + ERASE_SIZE as u32)?;
+        self.flash.blocking_write(CONFIG_OFFSET, &record[..len])
+    }
+}
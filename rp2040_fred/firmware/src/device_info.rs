@@ -0,0 +1,51 @@
+//! Build-time identity reported in `DEVICE_INFO`.
+
+use rp2040_fred_protocol::bridge_proto::{
+    DeviceInfoPayload, DeviceTransport, MsgTypeSet, PAYLOAD_SIZE, PROTOCOL_VERSION,
+    TRACE_LAYOUT_VERSION,
+};
+
+const FIRMWARE_VERSION: [u8; 3] = [
+    parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+/// Set by `build.rs`; absent when building outside a git checkout.
+const GIT_HASH: &str = match option_env!("FRED_GIT_HASH") {
+    Some(hash) => hash,
+    None => "unknown",
+};
+
+pub fn device_info(
+    transport: DeviceTransport,
+    pin_map_id: u8,
+    supported: MsgTypeSet,
+) -> DeviceInfoPayload {
+    let mut git_hash = [0u8; 8];
+    let hash = GIT_HASH.as_bytes();
+    let len = hash.len().min(git_hash.len());
+    git_hash[..len].copy_from_slice(&hash[..len]);
+
+    DeviceInfoPayload {
+        firmware_version: FIRMWARE_VERSION,
+        git_hash,
+        transport,
+        protocol_version: PROTOCOL_VERSION,
+        max_payload: PAYLOAD_SIZE as u16,
+        trace_layout_version: TRACE_LAYOUT_VERSION,
+        pin_map_id,
+        supported,
+    }
+}
+
+const fn parse_u8(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut value = 0u8;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
@@ -0,0 +1,126 @@
+//! Log records for the host, as well as defmt-RTT.
+//!
+//! [`record`] and [`record_text`] log to defmt and keep the record in a
+//! ring that survives USB disconnects. Once a host sends `LOG_SET`,
+//! `main.rs` drains the ring through [`LogForwarder`] as `LOG` packets ahead
+//! of transport traffic, so a failure that dropped the last connection is
+//! reported on the next one.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_time::Instant;
+use rp2040_fred_protocol::bridge_proto::{
+    LogPayload, LogSetPayload, MsgType, NackReason, Packet, PacketRef,
+};
+use rp2040_fred_protocol::device_log::{LogId, LogLevel, LogRecord, LogRing};
+
+use crate::transport::ReplySink;
+
+/// About 2.5 KiB of RAM.
+const LOG_RING_LEN: usize = 32;
+
+static LOG_RING: Mutex<RefCell<LogRing<LOG_RING_LEN>>> = Mutex::new(RefCell::new(LogRing::new()));
+
+pub fn record(level: LogLevel, id: LogId, arg: u32) {
+    match level {
+        LogLevel::Error => defmt::error!("{} ({})", id.text(), arg),
+        LogLevel::Warn => defmt::warn!("{} ({})", id.text(), arg),
+        LogLevel::Info => defmt::info!("{} ({})", id.text(), arg),
+        LogLevel::Debug => defmt::debug!("{} ({})", id.text(), arg),
+    }
+    push(LogRecord::event(level, id, arg, Instant::now().as_micros()));
+}
+
+/// For one-off messages; anything logged routinely should get a [`LogId`].
+pub fn record_text(level: LogLevel, text: &str) {
+    match level {
+        LogLevel::Error => defmt::error!("{=str}", text),
+        LogLevel::Warn => defmt::warn!("{=str}", text),
+        LogLevel::Info => defmt::info!("{=str}", text),
+        LogLevel::Debug => defmt::debug!("{=str}", text),
+    }
+    push(LogRecord::text(level, text, Instant::now().as_micros()));
+}
+
+fn push(record: LogRecord) {
+    critical_section::with(|cs| LOG_RING.borrow_ref_mut(cs).push(record));
+}
+
+fn pop() -> Option<(LogRecord, u32)> {
+    critical_section::with(|cs| {
+        let mut ring = LOG_RING.borrow_ref_mut(cs);
+        let dropped = ring.take_dropped();
+        ring.pop().map(|record| (record, dropped))
+    })
+}
+
+/// The `LOG` stream. Off after boot and after every disconnect, so a host
+/// that never sends `LOG_SET` sees no `LOG` packets.
+pub struct LogForwarder {
+    enabled: bool,
+    max_level: LogLevel,
+    seq: u16,
+    /// Overflow not yet reported because the records were filtered out.
+    dropped: u32,
+}
+
+impl LogForwarder {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            max_level: LogLevel::Info,
+            seq: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn handles(msg_type: MsgType) -> bool {
+        msg_type == MsgType::LogSet
+    }
+
+    pub async fn handle_request(&mut self, req: PacketRef<'_>, replies: &mut impl ReplySink) {
+        let Ok(set) = req.decode_payload::<LogSetPayload>() else {
+            replies
+                .send(Packet::nack(
+                    req.seq,
+                    MsgType::LogSet as u8,
+                    NackReason::InvalidPayload,
+                ))
+                .await;
+            return;
+        };
+        self.enabled = set.enable;
+        if let Some(level) = set.max_level {
+            self.max_level = level;
+        }
+        self.seq = 0;
+        replies.send(Packet::ack(req.seq, MsgType::LogSet, 0)).await;
+    }
+
+    pub fn disconnect(&mut self) {
+        self.enabled = false;
+    }
+
+    /// The next buffered record no more verbose than the forwarded level;
+    /// more verbose ones are discarded.
+    pub fn poll_packet(&mut self) -> Option<Packet> {
+        if !self.enabled {
+            return None;
+        }
+        while let Some((record, dropped)) = pop() {
+            self.dropped = self.dropped.saturating_add(dropped);
+            if record.level > self.max_level {
+                continue;
+            }
+            let payload = LogPayload {
+                record,
+                dropped: self.dropped.min(u16::MAX as u32) as u16,
+            };
+            self.dropped = 0;
+            self.seq = self.seq.wrapping_add(1);
+            return Some(Packet::from_payload(self.seq, &payload));
+        }
+        None
+    }
+}
@@ -4,6 +4,9 @@
 #[macro_use]
 mod resources;
 
+mod config_store;
+mod device_info;
+mod device_log;
 mod transport;
 
 use embassy_executor::Spawner;
@@ -13,18 +16,23 @@ use embassy_rp::{bind_interrupts, usb};
 use embassy_rp::{clocks::ClockConfig, gpio};
 use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::class::cmsis_dap_v2::{CmsisDapV2Class, State as CmsisState};
+use embassy_usb::driver::Driver;
 use embassy_usb::msos;
 use embassy_usb::{Builder, Config};
 use gpio::{Level, Output};
 use panic_probe as _;
-use rp2040_fred_protocol::bridge_proto::{Packet, MIN_PACKET_SIZE, PACKET_SIZE};
+use rp2040_fred_protocol::bridge_proto::{Packet, PacketRef, MIN_PACKET_SIZE, PACKET_SIZE};
+use rp2040_fred_protocol::device_log::{LogId, LogLevel};
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+use crate::config_store::ConfigStore;
+use crate::device_log::LogForwarder;
 use crate::resources::{
-    AssignedResources, Core1Resources, MainResources, SnifferResources, UsbResources,
+    AssignedResources, Core1Resources, FlashResources, MainResources, SnifferResources,
+    UsbResources,
 };
-use crate::transport::Transport;
+use crate::transport::{ReplySink, Transport};
 
 macro_rules! log_info {
     ($($arg:tt)*) => {
@@ -32,12 +40,6 @@ macro_rules! log_info {
     };
 }
 
-macro_rules! log_warn {
-    ($($arg:tt)*) => {
-        defmt::warn!($($arg)*);
-    };
-}
-
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => usb::InterruptHandler<embassy_rp::peripherals::USB>;
 });
@@ -47,6 +49,28 @@ const USB_BACKLOG_POLL_US: u64 = 50;
 const USB_OUTGOING_BURST_PACKETS: usize = 16;
 const USB_DECODE_BURST_SAMPLES: usize = 512;
 
+/// Writes each reply to USB as the handler sends it. After a failed write
+/// the rest of the reply is dropped and the connection is restarted.
+struct UsbReplies<'a, 'd, D: Driver<'d>> {
+    usb: &'a mut CmsisDapV2Class<'d, D>,
+    tx_buf: &'a mut [u8; PACKET_SIZE],
+    failed: bool,
+}
+
+impl<'d, D: Driver<'d>> ReplySink for UsbReplies<'_, 'd, D> {
+    async fn send(&mut self, pkt: Packet) {
+        if self.failed {
+            return;
+        }
+        let encoded_len = pkt.encode_into(self.tx_buf);
+        let encoded = &self.tx_buf[..encoded_len];
+        if self.usb.write_packet(encoded).await.is_err() {
+            device_log::record(LogLevel::Warn, LogId::UsbReplyWriteFailed, 0);
+            self.failed = true;
+        }
+    }
+}
+
 // fn create_transport(core1_resources: Core1Resources, sniffer_resources: SnifferResources) -> impl Transport {
 
 //     transport
@@ -57,11 +81,15 @@ async fn main(_spawner: Spawner) {
     ClockConfig::system_freq(125_000_000).expect("set clock failed?");
     let p = embassy_rp::init(Default::default());
     let r = split_resources!(p);
+    device_log::record(LogLevel::Info, LogId::FirmwareStarted, 0);
+
+    let mut config_store = ConfigStore::new(r.flash.flash);
 
     #[cfg(feature = "mock-bus")]
-    let mut transport = transport::transport_mock::MockTransport::new();
+    let mut transport = transport::transport_mock::MockTransport::new(config_store.config());
     #[cfg(feature = "pio-real")]
-    let mut transport = transport::transport_pio::PioTransport::new(r.core1, r.sniffer);
+    let mut transport =
+        transport::transport_pio::PioTransport::new(r.core1, r.sniffer, config_store.config());
 
     let mut led = Output::new(r.main.led, Level::Low);
     led.set_high();
@@ -100,12 +128,13 @@ async fn main(_spawner: Spawner) {
     let usb_fut = usb_device.run();
     let bridge_fut = async {
         let mut rx_buf = [0u8; PACKET_SIZE];
-        let mut replies = [Packet::ping(0), Packet::ping(0)];
+        let mut tx_buf = [0u8; PACKET_SIZE];
+        let mut log_forwarder = LogForwarder::new();
 
         loop {
             log_info!("waiting for USB host connection");
             usb.wait_connection().await;
-            log_info!("USB host connected");
+            device_log::record(LogLevel::Info, LogId::UsbConnected, 0);
 
             'connected: loop {
                 let now_ms = Instant::now().as_millis();
@@ -121,48 +150,59 @@ async fn main(_spawner: Spawner) {
                 {
                     Either::First(Ok(n)) => {
                         if n >= MIN_PACKET_SIZE {
-                            let reply_count = match Packet::decode(&rx_buf[..n]) {
-                                Ok(req) => transport.handle_request(req, &mut replies),
-                                Err(_) => {
-                                    replies[0] = Packet::nack(0, 0xFF, 0x02);
-                                    1
-                                }
+                            let mut replies = UsbReplies {
+                                usb: &mut usb,
+                                tx_buf: &mut tx_buf,
+                                failed: false,
                             };
-
-                            for pkt in replies.iter().take(reply_count) {
-                                let encoded = pkt.encode();
-                                let encoded_len = pkt.encoded_len();
-                                if usb.write_packet(&encoded[..encoded_len]).await.is_err() {
-                                    log_warn!("USB write failed; dropping connection");
-                                    break 'connected;
-                                } else {
-                                    // log_info!("wrote USB packet OK");
+                            match PacketRef::decode(&rx_buf[..n]) {
+                                Ok(req) if ConfigStore::handles(req.msg_type) => {
+                                    config_store.handle_request(req, &mut replies).await
                                 }
+                                Ok(req) if LogForwarder::handles(req.msg_type) => {
+                                    log_forwarder.handle_request(req, &mut replies).await
+                                }
+                                Ok(req) => {
+                                    let now_ms = Instant::now().as_millis();
+                                    transport.handle_request(req, now_ms, &mut replies).await
+                                }
+                                Err(err) => {
+                                    replies
+                                        .send(Packet::undecodable_nack(&rx_buf[..n], err))
+                                        .await;
+                                }
+                            }
+                            if replies.failed {
+                                break 'connected;
                             }
                         }
                     }
                     Either::First(Err(_)) => {
-                        log_warn!("USB read failed; dropping connection");
+                        device_log::record(LogLevel::Warn, LogId::UsbReadFailed, 0);
                         break;
                     }
                     Either::Second(()) => {}
                 }
 
-                transport.process_pending_work(USB_DECODE_BURST_SAMPLES);
+                transport
+                    .process_pending_work(Instant::now().as_millis(), USB_DECODE_BURST_SAMPLES);
 
                 for _ in 0..USB_OUTGOING_BURST_PACKETS {
                     let now_ms = Instant::now().as_millis();
-                    let Some(pkt) = transport.poll_outgoing_packet(now_ms) else {
+                    let Some(pkt) = log_forwarder
+                        .poll_packet()
+                        .or_else(|| transport.poll_outgoing_packet(now_ms))
+                    else {
                         break;
                     };
-                    let encoded = pkt.encode();
-                    let encoded_len = pkt.encoded_len();
-                    if usb.write_packet(&encoded[..encoded_len]).await.is_err() {
-                        log_warn!("USB telemetry write failed; dropping connection");
+                    let encoded_len = pkt.encode_into(&mut tx_buf);
+                    if usb.write_packet(&tx_buf[..encoded_len]).await.is_err() {
+                        device_log::record(LogLevel::Warn, LogId::UsbStreamWriteFailed, 0);
                         break 'connected;
                     }
                 }
             }
+            log_forwarder.disconnect();
         }
     };
 
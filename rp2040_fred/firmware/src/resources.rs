@@ -36,4 +36,7 @@ assign_resources! {
     main: MainResources {
         led: PIN_25,
     }
+    flash: FlashResources {
+        flash: FLASH,
+    }
 }
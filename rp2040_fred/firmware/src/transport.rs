@@ -3,11 +3,30 @@ pub mod transport_mock;
 #[cfg(feature = "pio-real")]
 pub mod transport_pio;
 
-use rp2040_fred_protocol::bridge_proto::Packet;
+use rp2040_fred_protocol::bridge_proto::{MsgType, Packet, PacketRef};
+
+/// Where a request handler writes its replies. `main.rs` writes each packet
+/// to USB before `send` returns, so a handler can answer with any number of
+/// packets and waits while the endpoint is busy.
+pub trait ReplySink {
+    async fn send(&mut self, pkt: Packet);
+
+    /// Sends `data` followed by the ACK of `acked_type`.
+    async fn send_with_ack(&mut self, data: Packet, acked_type: MsgType) {
+        let seq = data.seq;
+        self.send(data).await;
+        self.send(Packet::ack(seq, acked_type, 0)).await;
+    }
+}
 
 pub trait Transport {
-    fn handle_request(&mut self, req: Packet, out: &mut [Packet; 2]) -> usize;
-    fn process_pending_work(&mut self, budget: usize);
+    async fn handle_request(
+        &mut self,
+        req: PacketRef<'_>,
+        now_ms: u64,
+        replies: &mut impl ReplySink,
+    );
+    fn process_pending_work(&mut self, now_ms: u64, budget: usize);
     fn poll_outgoing_packet(&mut self, now_ms: u64) -> Option<Packet>;
     fn has_decode_work(&self) -> bool;
     fn has_outgoing_packet(&self, now_ms: u64) -> bool;
@@ -1,5 +1,9 @@
-use crate::transport::Transport;
-use rp2040_fred_protocol::bridge_proto::{MsgType, Packet};
+use crate::device_info::device_info;
+use crate::transport::{ReplySink, Transport};
+use rp2040_fred_protocol::bridge_proto::{
+    DeviceTransport, MsgType, MsgTypeSet, Packet, PacketRef, PIN_MAP_NONE,
+};
+use rp2040_fred_protocol::config::DeviceConfig;
 
 mod bridge_service;
 mod mock_bus;
@@ -7,40 +11,78 @@ mod protocol;
 
 use bridge_service::BridgeService;
 
+const SUPPORTED_MSG_TYPES: MsgTypeSet = MsgTypeSet::from_types(&[
+    MsgType::Ping,
+    MsgType::Hello,
+    MsgType::TelemetrySet,
+    MsgType::UnitCfg,
+    MsgType::SnapshotReq,
+    MsgType::CaptureSet,
+    MsgType::HealthSet,
+    MsgType::ConfigGet,
+    MsgType::ConfigSet,
+    MsgType::LogSet,
+    MsgType::Ack,
+    MsgType::Nack,
+    MsgType::Telemetry,
+    MsgType::Health,
+    MsgType::TraceSample,
+    MsgType::Snapshot,
+    MsgType::DeviceInfo,
+    MsgType::TraceSampleCompressed,
+    MsgType::TraceSampleTimed,
+    MsgType::Config,
+    MsgType::Log,
+]);
+
 pub struct MockTransport {
     bridge: BridgeService,
     next_due_ms: u64,
 }
 
 impl MockTransport {
-    pub fn new() -> Self {
+    pub fn new(config: &DeviceConfig) -> Self {
         Self {
-            bridge: BridgeService::new(),
+            bridge: BridgeService::with_config(config),
             next_due_ms: 0,
         }
     }
 }
 
 impl Transport for MockTransport {
-    fn handle_request(&mut self, req: Packet, out: &mut [Packet; 2]) -> usize {
+    async fn handle_request(
+        &mut self,
+        req: PacketRef<'_>,
+        now_ms: u64,
+        replies: &mut impl ReplySink,
+    ) {
         self.next_due_ms = 0;
         match req.msg_type {
             MsgType::Ping => {
-                out[0] = Packet::ack(req.seq, MsgType::Ping, 0);
-                1
+                replies.send(Packet::ack(req.seq, MsgType::Ping, 0)).await;
+            }
+            MsgType::Hello => {
+                let info = device_info(DeviceTransport::MockBus, PIN_MAP_NONE, SUPPORTED_MSG_TYPES);
+                replies
+                    .send_with_ack(Packet::from_payload(req.seq, &info), MsgType::Hello)
+                    .await;
             }
-            _ => self.bridge.handle_request(req, out),
+            _ => self.bridge.handle_request(req, now_ms, replies).await,
         }
     }
 
-    fn process_pending_work(&mut self, _budget: usize) {}
+    fn process_pending_work(&mut self, _now_ms: u64, _budget: usize) {}
 
     fn poll_outgoing_packet(&mut self, now_ms: u64) -> Option<Packet> {
+        if let Some(pkt) = self.bridge.poll_health(now_ms) {
+            return Some(pkt);
+        }
+
         if now_ms < self.next_due_ms {
             return None;
         }
 
-        let pkt = self.bridge.poll_outgoing_packet()?;
+        let pkt = self.bridge.poll_outgoing_packet(now_ms)?;
         if pkt.msg_type == MsgType::Telemetry {
             self.next_due_ms = now_ms + self.bridge.telemetry_period_ms().max(1) as u64;
         } else {
@@ -54,6 +96,6 @@ impl Transport for MockTransport {
     }
 
     fn has_outgoing_packet(&self, now_ms: u64) -> bool {
-        now_ms >= self.next_due_ms
+        now_ms >= self.next_due_ms || self.bridge.health_due(now_ms)
     }
 }
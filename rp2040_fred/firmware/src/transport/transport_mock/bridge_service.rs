@@ -1,127 +1,221 @@
 #![allow(dead_code)]
 
-use super::mock_bus::MockBusRunner;
-use rp2040_fred_protocol::bridge_proto::{MsgType, Packet};
-use rp2040_fred_protocol::dro_decode::{DroAssembler, DroSnapshot};
+use super::mock_bus::{MockBusFrame, MockBusRunner};
+use rp2040_fred_protocol::bridge_proto::{
+    CaptureSetPayload, HealthPayload, HealthSetPayload, LinearUnits, MsgType, NackReason, Packet,
+    PacketRef, SnapshotPayload, TelemetrySetPayload, TraceBatchEncoder, UnitConfig, XAxisMode,
+    HEALTH_IDLE_NEVER, TELEMETRY_FLAG_ENABLED,
+};
+use rp2040_fred_protocol::command_map::CADENCE_LEN;
+use rp2040_fred_protocol::config::{DeviceConfig, PowerUpMode};
+use rp2040_fred_protocol::dro_decode::{rpm_display, DroAssembler, DroEncoding, DroSnapshot};
 
-pub const FLAG_ENABLED: u8 = 1 << 0;
+use crate::transport::ReplySink;
 
 pub struct BridgeService {
     capture_enabled: bool,
+    capture_sample_times: bool,
     telemetry_enabled: bool,
     telemetry_period_ms: u16,
+    units: UnitConfig,
     tick: u32,
     telemetry_seq: u16,
     bus_cycles: u32,
-    tx_timeout_count: u32,
-    rx_timeout_count: u32,
+    health_enabled: bool,
+    health_period_ms: u16,
+    next_health_due_ms: u64,
+    health_seq: u16,
+    last_bus_ms: Option<u64>,
     mock: MockBusRunner,
     dro: DroAssembler,
+    snapshot_valid: bool,
+    snapshot_ms: u64,
 }
 
 impl BridgeService {
     pub const fn new() -> Self {
         Self {
             capture_enabled: false,
+            capture_sample_times: false,
             telemetry_enabled: false,
             telemetry_period_ms: 100,
+            units: UnitConfig {
+                units: LinearUnits::Metric,
+                x_mode: XAxisMode::Diameter,
+            },
             tick: 0,
             telemetry_seq: 1,
             bus_cycles: 0,
-            tx_timeout_count: 0,
-            rx_timeout_count: 0,
+            health_enabled: false,
+            health_period_ms: 1000,
+            next_health_due_ms: 0,
+            health_seq: 1,
+            last_bus_ms: None,
             mock: MockBusRunner::new(),
-            dro: DroAssembler::new(),
+            dro: DroAssembler::with_encoding(DroEncoding::Bcd),
+            snapshot_valid: false,
+            snapshot_ms: 0,
         }
     }
 
-    pub fn handle_request(&mut self, req: Packet, out: &mut [Packet; 2]) -> usize {
+    /// Starts in the configured power-up mode.
+    pub fn with_config(config: &DeviceConfig) -> Self {
+        let mut svc = Self::new();
+        svc.capture_enabled = config.power_up_mode == PowerUpMode::Capture;
+        svc.telemetry_enabled = config.power_up_mode == PowerUpMode::Telemetry;
+        svc.telemetry_period_ms = config.telemetry_period_ms;
+        svc.units = config.units;
+        svc.health_enabled = config.health_period_ms != 0;
+        if svc.health_enabled {
+            svc.health_period_ms = config.health_period_ms;
+        }
+        svc.mock = MockBusRunner::with_command_map(config.command_map);
+        svc.dro = DroAssembler::with_command_map(DroEncoding::Bcd, config.command_map);
+        svc
+    }
+
+    pub async fn handle_request(
+        &mut self,
+        req: PacketRef<'_>,
+        now_ms: u64,
+        replies: &mut impl ReplySink,
+    ) {
         match req.msg_type {
             MsgType::Ping => {
-                out[0] = Packet::ack(req.seq, MsgType::Ping, 0);
-                1
+                replies.send(Packet::ack(req.seq, MsgType::Ping, 0)).await;
             }
             MsgType::TelemetrySet => {
-                if req.payload_len < 1 {
-                    out[0] = Packet::nack(req.seq, MsgType::TelemetrySet as u8, 1);
-                    return 1;
-                }
-                self.telemetry_enabled = req.payload[0] != 0;
+                let Ok(set) = req.decode_payload::<TelemetrySetPayload>() else {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::TelemetrySet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                    return;
+                };
+                self.telemetry_enabled = set.enable;
                 defmt::debug!("telemetry_enabled: {}", self.telemetry_enabled);
-                if req.payload_len >= 3 {
-                    self.telemetry_period_ms = u16::from_le_bytes([req.payload[1], req.payload[2]]);
+                if let Some(period_ms) = set.period_ms {
+                    self.telemetry_period_ms = period_ms;
                 }
-                out[0] = Packet::ack(req.seq, MsgType::TelemetrySet, 0);
-                1
+                replies
+                    .send(Packet::ack(req.seq, MsgType::TelemetrySet, 0))
+                    .await;
             }
             MsgType::CaptureSet => {
-                if req.payload_len < 1 {
-                    out[0] = Packet::nack(req.seq, MsgType::CaptureSet as u8, 1);
-                    return 1;
-                }
-                self.capture_enabled = req.payload[0] != 0;
+                let Ok(set) = req.decode_payload::<CaptureSetPayload>() else {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::CaptureSet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                    return;
+                };
+                self.capture_enabled = set.enable;
+                self.capture_sample_times = set.sample_times;
                 defmt::debug!("capture_enabled: {}", self.capture_enabled);
-                if req.payload_len >= 3 {
-                    self.telemetry_period_ms = u16::from_le_bytes([req.payload[1], req.payload[2]]);
+                replies
+                    .send(Packet::ack(req.seq, MsgType::CaptureSet, 0))
+                    .await;
+            }
+            MsgType::UnitCfg => {
+                let Ok(units) = req.decode_payload::<UnitConfig>() else {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::UnitCfg as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                    return;
+                };
+                self.units = units;
+                replies
+                    .send(Packet::ack(req.seq, MsgType::UnitCfg, 0))
+                    .await;
+            }
+            MsgType::HealthSet => {
+                let Ok(set) = req.decode_payload::<HealthSetPayload>() else {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::HealthSet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                    return;
+                };
+                self.health_enabled = set.enable;
+                if let Some(period_ms) = set.period_ms {
+                    self.health_period_ms = period_ms;
                 }
-                out[0] = Packet::ack(req.seq, MsgType::CaptureSet, 0);
-                1
+                self.next_health_due_ms = 0;
+                replies
+                    .send(Packet::ack(req.seq, MsgType::HealthSet, 0))
+                    .await;
+            }
+            MsgType::SnapshotReq => {
+                if !self.telemetry_enabled && !self.capture_enabled {
+                    // Nothing is clocking the mock bus; run one cadence on demand.
+                    for _ in 0..CADENCE_LEN {
+                        self.step_bus(now_ms);
+                    }
+                }
+                let s = self.snapshot();
+                let snapshot = SnapshotPayload {
+                    age_ms: now_ms.saturating_sub(self.snapshot_ms).min(u32::MAX as u64) as u32,
+                    sample_index: self.bus_cycles,
+                    x_counts: s.x_counts,
+                    z_counts: s.z_counts,
+                    rpm_raw: s.rpm,
+                    rpm_display: rpm_display(s.rpm),
+                    valid: self.snapshot_valid,
+                    flags: self.flags(),
+                };
+                replies
+                    .send_with_ack(Packet::snapshot(req.seq, &snapshot), MsgType::SnapshotReq)
+                    .await;
             }
-            // MsgType::SnapshotReq => {
-            //     if self.telemetry_enabled {
-            //         out[0] = Packet::telemetry(
-            //             req.seq,
-            //             self.tick,
-            //             self.snapshot().x_counts,
-            //             self.snapshot().z_counts,
-            //             self.snapshot().rpm,
-            //             self.flags(),
-            //         );
-            //         out[1] = Packet::ack(req.seq, MsgType::SnapshotReq, 0);
-            //         return 2;
-            //     }
-            //     if self.capture_enabled {
-            //         out[0] = Packet::trace_sample(req.seq, sample_bits);
-            //         out[1] = Packet::ack(req.seq, MsgType::SnapshotReq, 0);
-            //         return 2;
-            //     }
-            //     out[0] = Packet::nack(req.seq, req.msg_type as u8, 0xFE);
-            //     1
-            // }
             _ => {
-                out[0] = Packet::nack(req.seq, req.msg_type as u8, 0xFE);
-                1
+                replies
+                    .send(Packet::nack(
+                        req.seq,
+                        req.msg_type as u8,
+                        NackReason::Unsupported,
+                    ))
+                    .await;
             }
         }
     }
 
-    pub fn poll_outgoing_packet(&mut self) -> Option<Packet> {
+    pub fn poll_outgoing_packet(&mut self, now_ms: u64) -> Option<Packet> {
         if !self.telemetry_enabled && !self.capture_enabled {
             return None;
         }
 
-        let frame = self.mock.step();
-        self.tick = self.tick.wrapping_add(1);
-        self.bus_cycles = self.bus_cycles.wrapping_add(1);
-        self.dro.on_fc80_fcf1(frame.cmd_fc80, frame.response_fcf1);
+        let frame = self.step_bus(now_ms);
 
         // Emit one telemetry packet per full DRO command cadence.
         if self.capture_enabled {
-            return Some(Packet::trace_samples(
-                self.bus_cycles as u16,
-                0,
-                0,
-                &frame.sample_words(),
-            ));
+            let mut batch = TraceBatchEncoder::new();
+            batch.set_sample_times(self.capture_sample_times);
+            for sample in frame.sample_words() {
+                batch.push(sample, now_ms * 1000);
+            }
+            return Some(batch.finish(self.bus_cycles as u16, 0, 0));
         }
-        if frame.cmd_fc80 == 0x0C {
+        if self.ends_cadence(&frame) {
             let s = self.snapshot();
             let pkt = Packet::telemetry(
                 self.telemetry_seq,
                 self.tick,
                 s.x_counts,
                 s.z_counts,
-                s.rpm,
+                rpm_display(s.rpm),
                 self.flags(),
             );
             self.telemetry_seq = self.telemetry_seq.wrapping_add(1);
@@ -133,17 +227,51 @@ impl BridgeService {
         None
     }
 
-    pub fn health_packet(&mut self) -> Packet {
-        let pkt = Packet::health(
-            self.telemetry_seq,
-            self.tx_timeout_count,
-            self.rx_timeout_count,
-            self.bus_cycles,
-        );
-        self.telemetry_seq = self.telemetry_seq.wrapping_add(1);
+    fn step_bus(&mut self, now_ms: u64) -> MockBusFrame {
+        let frame = self.mock.step();
+        self.tick = self.tick.wrapping_add(1);
+        self.bus_cycles = self.bus_cycles.wrapping_add(1);
+        self.dro.on_fc80_fcf1(frame.cmd_fc80, frame.response_fcf1);
+        self.last_bus_ms = Some(now_ms);
+        if self.ends_cadence(&frame) {
+            self.snapshot_valid = true;
+            self.snapshot_ms = now_ms;
+        }
+        frame
+    }
+
+    fn ends_cadence(&self, frame: &MockBusFrame) -> bool {
+        frame.cmd_fc80 == self.dro.command_map().last_command()
+    }
+
+    /// The mock bus has no sniffer ring, so only the transaction count and
+    /// idle time carry information.
+    pub fn health_packet(&mut self, now_ms: u64) -> Packet {
+        let counters = HealthPayload {
+            decoded_transactions: self.bus_cycles,
+            idle_ms: match self.last_bus_ms {
+                Some(ms) => now_ms.saturating_sub(ms).min(u32::MAX as u64 - 1) as u32,
+                None => HEALTH_IDLE_NEVER,
+            },
+            ..HealthPayload::default()
+        };
+        let pkt = Packet::health(self.health_seq, &counters);
+        self.health_seq = self.health_seq.wrapping_add(1);
         pkt
     }
 
+    pub fn poll_health(&mut self, now_ms: u64) -> Option<Packet> {
+        if !self.health_due(now_ms) {
+            return None;
+        }
+        self.next_health_due_ms = now_ms + self.health_period_ms.max(1) as u64;
+        Some(self.health_packet(now_ms))
+    }
+
+    pub fn health_due(&self, now_ms: u64) -> bool {
+        self.health_enabled && now_ms >= self.next_health_due_ms
+    }
+
     pub fn telemetry_period_ms(&self) -> u16 {
         self.telemetry_period_ms
     }
@@ -152,27 +280,50 @@ impl BridgeService {
         self.dro.snapshot()
     }
 
+    pub fn units(&self) -> UnitConfig {
+        self.units
+    }
+
     fn flags(&self) -> u8 {
-        if self.telemetry_enabled {
-            FLAG_ENABLED
+        let enabled = if self.telemetry_enabled {
+            TELEMETRY_FLAG_ENABLED
         } else {
             0
-        }
+        };
+        enabled | self.units.telemetry_flags()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::bridge_proto::{MsgType, Packet};
+    use crate::bridge_proto::{
+        HealthPayload, LinearUnits, MsgType, NackPayload, NackReason, Packet, SnapshotPayload,
+        UnitConfig, XAxisMode, HEALTH_IDLE_NEVER, TELEMETRY_FLAG_IMPERIAL, TELEMETRY_FLAG_RADIUS,
+    };
 
     use super::BridgeService;
+    use crate::config::{ConfigValue, DeviceConfig, PowerUpMode};
+    use crate::transport::ReplySink;
+
+    struct Replies(Vec<Packet>);
+
+    impl ReplySink for Replies {
+        async fn send(&mut self, pkt: Packet) {
+            self.0.push(pkt);
+        }
+    }
+
+    fn handle(svc: &mut BridgeService, req: Packet, now_ms: u64) -> Vec<Packet> {
+        let mut replies = Replies(Vec::new());
+        embassy_futures::block_on(svc.handle_request(req.as_packet_ref(), now_ms, &mut replies));
+        replies.0
+    }
 
     #[test]
     fn ping_is_acked() {
         let mut svc = BridgeService::new();
-        let mut out = [Packet::ping(0), Packet::ping(0)];
-        let n = svc.handle_request(Packet::ping(7), &mut out);
-        assert_eq!(n, 1);
+        let out = handle(&mut svc, Packet::ping(7), 0);
+        assert_eq!(out.len(), 1);
         assert_eq!(out[0].msg_type, MsgType::Ack);
         assert_eq!(out[0].seq, 7);
     }
@@ -180,9 +331,8 @@ mod tests {
     #[test]
     fn telemetry_enable_changes_state_and_emits_events() {
         let mut svc = BridgeService::new();
-        let mut out = [Packet::ping(0), Packet::ping(0)];
-        let n = svc.handle_request(Packet::telemetry_set(9, true, 25), &mut out);
-        assert_eq!(n, 1);
+        let out = handle(&mut svc, Packet::telemetry_set(9, true, 25), 0);
+        assert_eq!(out.len(), 1);
         assert_eq!(out[0].msg_type, MsgType::Ack);
         assert_eq!(svc.telemetry_period_ms(), 25);
 
@@ -194,4 +344,78 @@ mod tests {
         }
         assert!(seen >= 2);
     }
+
+    #[test]
+    fn snapshot_req_replies_without_streaming() {
+        let mut svc = BridgeService::new();
+        let out = handle(&mut svc, Packet::snapshot_req(12), 500);
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].msg_type, MsgType::Snapshot);
+        assert_eq!(out[0].seq, 12);
+        let snapshot = out[0]
+            .decode_payload::<SnapshotPayload>()
+            .expect("snapshot payload");
+        assert_eq!(snapshot.age_ms, 0);
+        assert!(snapshot.valid);
+        assert_eq!(out[1].msg_type, MsgType::Ack);
+        assert_eq!(out[1].seq, 12);
+    }
+
+    #[test]
+    fn health_set_enables_periodic_health() {
+        let mut svc = BridgeService::new();
+        assert!(svc.poll_health(0).is_none());
+
+        let out = handle(&mut svc, Packet::health_set(6, true, 500), 0);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].msg_type, MsgType::Ack);
+
+        let health = svc.poll_health(10).expect("health due");
+        let counters = health
+            .decode_payload::<HealthPayload>()
+            .expect("health payload");
+        assert_eq!(counters.idle_ms, HEALTH_IDLE_NEVER);
+        assert!(svc.poll_health(200).is_none());
+        assert!(svc.poll_health(510).is_some());
+    }
+
+    #[test]
+    fn unit_cfg_is_acked_and_reported_in_telemetry_flags() {
+        let mut svc = BridgeService::new();
+        let units = UnitConfig {
+            units: LinearUnits::Imperial,
+            x_mode: XAxisMode::Radius,
+        };
+        let out = handle(&mut svc, Packet::unit_cfg(4, units), 0);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].msg_type, MsgType::Ack);
+        assert_eq!(svc.units(), units);
+        assert_eq!(
+            svc.flags() & TELEMETRY_FLAG_IMPERIAL,
+            TELEMETRY_FLAG_IMPERIAL
+        );
+        assert_eq!(svc.flags() & TELEMETRY_FLAG_RADIUS, TELEMETRY_FLAG_RADIUS);
+
+        let out = handle(&mut svc, Packet::new(MsgType::UnitCfg, 5, &[9]).unwrap(), 0);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].msg_type, MsgType::Nack);
+        let nack: NackPayload = out[0].decode_payload().expect("nack payload");
+        assert_eq!(nack.nack_reason(), Some(NackReason::InvalidPayload));
+        assert_eq!(svc.units(), units);
+    }
+
+    #[test]
+    fn power_up_config_selects_mode_and_health() {
+        let mut config = DeviceConfig::default();
+        config.set(ConfigValue::PowerUpMode(PowerUpMode::Telemetry));
+        config.set(ConfigValue::TelemetryPeriodMs(40));
+        config.set(ConfigValue::HealthPeriodMs(250));
+        let mut svc = BridgeService::with_config(&config);
+        assert_eq!(svc.telemetry_period_ms(), 40);
+        assert!(svc.poll_health(0).is_some());
+        assert!(svc.poll_health(100).is_none());
+        assert!((0..20).any(|_| svc
+            .poll_outgoing_packet(0)
+            .is_some_and(|pkt| pkt.msg_type == MsgType::Telemetry)));
+    }
 }
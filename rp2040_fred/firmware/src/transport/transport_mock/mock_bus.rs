@@ -1,6 +1,6 @@
-use super::protocol::{DroProtocolEngine, FredReply};
+use rp2040_fred_protocol::command_map::{CommandMap, CADENCE_LEN};
 
-pub const DRO_CADENCE: [u8; 10] = [0x03, 0x02, 0x01, 0x00, 0x07, 0x06, 0x05, 0x04, 0x0D, 0x0C];
+use super::protocol::{DroProtocolEngine, FredReply};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MockBusFrame {
@@ -36,20 +36,27 @@ impl MockBusFrame {
 
 pub struct MockBusRunner {
     engine: DroProtocolEngine,
+    cadence: [u8; CADENCE_LEN],
     idx: usize,
 }
 
 impl MockBusRunner {
     pub const fn new() -> Self {
+        Self::with_command_map(CommandMap::TCL125_V202)
+    }
+
+    /// Plays a ROM that polls the commands of `map`.
+    pub const fn with_command_map(map: CommandMap) -> Self {
         Self {
-            engine: DroProtocolEngine::new(),
+            engine: DroProtocolEngine::with_command_map(map),
+            cadence: map.cadence(),
             idx: 0,
         }
     }
 
     pub fn step(&mut self) -> MockBusFrame {
-        let cmd = DRO_CADENCE[self.idx];
-        self.idx = (self.idx + 1) % DRO_CADENCE.len();
+        let cmd = self.cadence[self.idx];
+        self.idx = (self.idx + 1) % CADENCE_LEN;
 
         self.engine.step_telemetry();
         let FredReply {
@@ -67,14 +74,17 @@ impl MockBusRunner {
 
 #[cfg(test)]
 mod tests {
-    use super::{MockBusRunner, DRO_CADENCE};
+    use super::MockBusRunner;
+    use rp2040_fred_protocol::bcd;
+    use rp2040_fred_protocol::command_map::{CommandMap, CADENCE_LEN};
 
     #[test]
     fn cadence_repeats_in_expected_order() {
+        let cadence = CommandMap::TCL125_V202.cadence();
         let mut sim = MockBusRunner::new();
-        for i in 0..(DRO_CADENCE.len() * 3) {
+        for i in 0..(CADENCE_LEN * 3) {
             let frame = sim.step();
-            assert_eq!(frame.cmd_fc80, DRO_CADENCE[i % DRO_CADENCE.len()]);
+            assert_eq!(frame.cmd_fc80, cadence[i % CADENCE_LEN]);
         }
     }
 
@@ -99,7 +109,7 @@ mod tests {
         assert_eq!(hi.cmd_fc80, 0x0D);
         assert_eq!(lo.cmd_fc80, 0x0C);
 
-        let rpm = ((hi.response_fcf1 as u16) << 8) | lo.response_fcf1 as u16;
+        let rpm = bcd::decode_rpm([hi.response_fcf1, lo.response_fcf1]).expect("packed BCD");
         assert!((800..=2200).contains(&rpm));
     }
 }
@@ -1,5 +1,8 @@
 #![allow(dead_code)]
 
+use rp2040_fred_protocol::bcd;
+use rp2040_fred_protocol::command_map::{CommandMap, DroAxis, DroField};
+
 #[derive(Clone, Copy, Debug)]
 pub struct DroTelemetry {
     pub x_counts: i32,
@@ -24,13 +27,20 @@ pub struct FredReply {
 }
 
 pub struct DroProtocolEngine {
+    map: CommandMap,
     telemetry: DroTelemetry,
     tick: u32,
 }
 
 impl DroProtocolEngine {
     pub const fn new() -> Self {
+        Self::with_command_map(CommandMap::TCL125_V202)
+    }
+
+    /// Answers the commands of `map` rather than TCL125 v2.02's.
+    pub const fn with_command_map(map: CommandMap) -> Self {
         Self {
+            map,
             telemetry: DroTelemetry {
                 x_counts: 0,
                 z_counts: 0,
@@ -54,24 +64,21 @@ impl DroProtocolEngine {
         // Current status model: always ready.
         let status = 0x00;
 
-        let response = match cmd_fc80 {
-            // X sign + 6 hex digits across 02/01/00
-            0x03 => sign_byte(self.telemetry.x_counts),
-            0x02 => byte2(abs24(self.telemetry.x_counts)),
-            0x01 => byte1(abs24(self.telemetry.x_counts)),
-            0x00 => byte0(abs24(self.telemetry.x_counts)),
+        // Packed BCD, as the lathe sends it: six digits per axis after a
+        // sign byte, four speed digits.
+        let x = axis_bcd(self.telemetry.x_counts);
+        let z = axis_bcd(self.telemetry.z_counts);
+        let rpm = bcd::encode_rpm(self.telemetry.rpm.min(bcd::RPM_MAX)).unwrap_or([0; 2]);
+        let response = match self.map.field(cmd_fc80) {
+            Some(DroField::Sign(DroAxis::X)) => sign_byte(self.telemetry.x_counts),
+            Some(DroField::Digits(DroAxis::X, idx)) => x[idx],
 
-            // Z sign + 6 hex digits across 06/05/04
-            0x07 => sign_byte(self.telemetry.z_counts),
-            0x06 => byte2(abs24(self.telemetry.z_counts)),
-            0x05 => byte1(abs24(self.telemetry.z_counts)),
-            0x04 => byte0(abs24(self.telemetry.z_counts)),
+            Some(DroField::Sign(DroAxis::Z)) => sign_byte(self.telemetry.z_counts),
+            Some(DroField::Digits(DroAxis::Z, idx)) => z[idx],
 
-            // Speed digits (ROM callback forces the very last digit to zero on 0x0C path).
-            0x0D => (self.telemetry.rpm >> 8) as u8,
-            0x0C => (self.telemetry.rpm & 0x00FF) as u8,
+            Some(DroField::Rpm(idx)) => rpm[idx],
 
-            _ => 0x00,
+            None => 0x00,
         };
 
         FredReply {
@@ -81,9 +88,9 @@ impl DroProtocolEngine {
     }
 }
 
-const fn abs24(v: i32) -> u32 {
-    let mag = if v < 0 { (-v) as u32 } else { v as u32 };
-    mag & 0x00FF_FFFF
+/// Magnitudes beyond six digits cannot be sent and are clamped.
+fn axis_bcd(counts: i32) -> [u8; 3] {
+    bcd::encode_axis(counts.unsigned_abs().min(bcd::AXIS_MAX)).unwrap_or([0; 3])
 }
 
 const fn sign_byte(v: i32) -> u8 {
@@ -93,15 +100,3 @@ const fn sign_byte(v: i32) -> u8 {
         0x00
     }
 }
-
-const fn byte2(v: u32) -> u8 {
-    ((v >> 16) & 0xFF) as u8
-}
-
-const fn byte1(v: u32) -> u8 {
-    ((v >> 8) & 0xFF) as u8
-}
-
-const fn byte0(v: u32) -> u8 {
-    (v & 0xFF) as u8
-}
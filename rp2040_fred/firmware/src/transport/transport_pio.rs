@@ -9,14 +9,25 @@ use embassy_rp::pio::{
     Config, Direction, InterruptHandler, Pio, PioBatch, ShiftConfig, ShiftDirection,
 };
 use embassy_rp::pio_programs::clock_divider::calculate_pio_clock_divider_value;
+use embassy_time::Instant;
 use heapless::spsc::{Consumer, Producer, Queue};
 use portable_atomic::{AtomicBool, AtomicU32, Ordering};
 use static_cell::StaticCell;
 
+use crate::device_info::device_info;
 use crate::resources::{Core1Resources, SnifferResources};
-use crate::transport::Transport;
-use rp2040_fred_protocol::bridge_proto::{MsgType, Packet, TRACE_SAMPLES_PER_PACKET};
-use rp2040_fred_protocol::trace_decode::{AxisSnapshot, FeedbackDecoder, FeedbackSnapshot};
+use crate::transport::{ReplySink, Transport};
+use rp2040_fred_protocol::bridge_proto::{
+    CaptureSetPayload, DeviceTransport, HealthPayload, HealthSetPayload, MsgType, MsgTypeSet,
+    NackReason, Packet, PacketRef, SnapshotPayload, TelemetrySetPayload, TraceBatchEncoder,
+    UnitConfig, HEALTH_IDLE_NEVER, PIN_MAP_PASSIVE_SNIFFER, TELEMETRY_FLAG_CORRECTED,
+    TELEMETRY_FLAG_ENABLED,
+};
+use rp2040_fred_protocol::config::{DeviceConfig, PowerUpMode};
+use rp2040_fred_protocol::dro_decode::DroEncoding;
+use rp2040_fred_protocol::trace_decode::{
+    AxisSnapshot, FeedbackDecoder, FeedbackFilter, FeedbackSnapshot,
+};
 
 macro_rules! log_info {
     ($($arg:tt)*) => {
@@ -28,32 +39,87 @@ bind_interrupts!(struct PioIrqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
 });
 
-const FLAG_ENABLED: u8 = 1 << 0;
-
 const TRACE_SAMPLE_RING_LEN: usize = 16_384;
 const CORE1_STACK_SIZE: usize = 4096;
 
+const SUPPORTED_MSG_TYPES: MsgTypeSet = MsgTypeSet::from_types(&[
+    MsgType::Ping,
+    MsgType::Hello,
+    MsgType::TelemetrySet,
+    MsgType::UnitCfg,
+    MsgType::SnapshotReq,
+    MsgType::CaptureSet,
+    MsgType::HealthSet,
+    MsgType::ConfigGet,
+    MsgType::ConfigSet,
+    MsgType::LogSet,
+    MsgType::Ack,
+    MsgType::Nack,
+    MsgType::Telemetry,
+    MsgType::Health,
+    MsgType::TraceSample,
+    MsgType::Snapshot,
+    MsgType::DeviceInfo,
+    MsgType::TraceSampleCompressed,
+    MsgType::TraceSampleTimed,
+    MsgType::Config,
+    MsgType::Log,
+]);
+
+/// Ring entries from core1 are either a bus sample (GPIO0..17) with the µs
+/// since the previous entry in bits 18..31, or a time marker. The sampled
+/// 1MHZE level is not trusted, so bit 17 is forced high on every bus sample
+/// (trace packing assumes it anyway) and a clear bit 17 marks the latter;
+/// it carries the low 31 bits of the timer and is queued whenever the delta
+/// would not fit.
+const RING_SAMPLE_MASK: u32 = 0x0003_FFFF;
+const RING_CLOCK_HIGH: u32 = 1 << 17;
+const RING_DELTA_SHIFT: u32 = 18;
+const RING_DELTA_MAX: u64 = (1 << (32 - RING_DELTA_SHIFT)) - 1;
+
 static TRACE_CAPTURE_ENABLED: AtomicBool = AtomicBool::new(true);
+static TRACE_TIME_RESYNC: AtomicBool = AtomicBool::new(false);
 static TRACE_QUEUE_DROP_COUNT: AtomicU32 = AtomicU32::new(0);
 static TRACE_RXSTALL_COUNT: AtomicU32 = AtomicU32::new(0);
+static TRACE_ACTIVITY_SEEN: AtomicBool = AtomicBool::new(false);
+static TRACE_LAST_ACTIVITY_MS: AtomicU32 = AtomicU32::new(0);
 static TRACE_SAMPLE_RING: StaticCell<Queue<u32, TRACE_SAMPLE_RING_LEN>> = StaticCell::new();
 static mut CORE1_STACK: Stack<CORE1_STACK_SIZE> = Stack::new();
 
 pub struct PioTransport {
     trace_samples: Consumer<'static, u32>,
+    trace_batch: TraceBatchEncoder,
     capture_enabled: bool,
     telemetry_enabled: bool,
     packet_seq: u16,
     sample_seq: u64,
     decoder: FeedbackDecoder,
+    max_counts_per_s: u32,
+    filter: FeedbackFilter,
     current_snapshot: FeedbackSnapshot,
     snapshot_valid: bool,
+    /// The torn-read filter replaced an axis value in `current_snapshot`.
+    snapshot_corrected: bool,
+    snapshot_ms: u64,
+    snapshot_us: u64,
+    /// Capture time of the last sample taken off the ring.
+    sample_clock_us: Option<u64>,
     telemetry_period_ms: u16,
     next_telemetry_due_ms: u64,
+    units: UnitConfig,
+    health_enabled: bool,
+    health_period_ms: u16,
+    next_health_due_ms: u64,
+    health_seq: u16,
+    ring_high_water: u16,
 }
 
 impl PioTransport {
-    pub fn new(core1_resources: Core1Resources, sniffer_resources: SnifferResources) -> Self {
+    pub fn new(
+        core1_resources: Core1Resources,
+        sniffer_resources: SnifferResources,
+        config: &DeviceConfig,
+    ) -> Self {
         let trace_ring = TRACE_SAMPLE_RING.init(Queue::new());
         let (producer, consumer) = trace_ring.split();
 
@@ -69,11 +135,14 @@ impl PioTransport {
 
         Self {
             trace_samples: consumer,
-            capture_enabled: false,
-            telemetry_enabled: false,
+            trace_batch: TraceBatchEncoder::new(),
+            capture_enabled: config.power_up_mode == PowerUpMode::Capture,
+            telemetry_enabled: config.power_up_mode == PowerUpMode::Telemetry,
             packet_seq: 1,
             sample_seq: 0,
-            decoder: FeedbackDecoder::new(),
+            decoder: FeedbackDecoder::with_command_map(DroEncoding::Bcd, config.command_map),
+            max_counts_per_s: config.max_counts_per_s,
+            filter: FeedbackFilter::new(config.max_counts_per_s),
             current_snapshot: FeedbackSnapshot {
                 sample_index: 0,
                 x: AxisSnapshot {
@@ -86,21 +155,66 @@ impl PioTransport {
                 },
                 rpm_display: 0,
                 rpm_raw: 0,
+                clean: false,
             },
             snapshot_valid: false,
-            telemetry_period_ms: 100,
+            snapshot_corrected: false,
+            snapshot_ms: 0,
+            snapshot_us: 0,
+            sample_clock_us: None,
+            telemetry_period_ms: config.telemetry_period_ms,
             next_telemetry_due_ms: 0,
+            units: config.units,
+            health_enabled: config.health_period_ms != 0,
+            health_period_ms: match config.health_period_ms {
+                0 => 1000,
+                ms => ms,
+            },
+            next_health_due_ms: 0,
+            health_seq: 1,
+            ring_high_water: 0,
         }
     }
 
     fn clear_trace_samples(&mut self) {
+        TRACE_TIME_RESYNC.store(true, Ordering::Relaxed);
         while self.trace_samples.dequeue().is_some() {}
+        self.sample_clock_us = None;
+    }
+
+    /// Next bus sample on the ring and its capture time, consuming any time
+    /// markers in front of it. The sample stays queued until `take_sample`.
+    fn peek_sample(&mut self) -> Option<(u32, u64)> {
+        loop {
+            let entry = *self.trace_samples.peek()?;
+            if entry & RING_CLOCK_HIGH == 0 {
+                let _ = self.trace_samples.dequeue();
+                self.sample_clock_us = Some(marker_time_us(entry, Instant::now().as_micros()));
+                continue;
+            }
+
+            let delta_us = (entry >> RING_DELTA_SHIFT) as u64;
+            // Entries queued before a resync marker have no anchor; fall
+            // back to the time they are read.
+            let at_us = match self.sample_clock_us {
+                Some(clock_us) => clock_us + delta_us,
+                None => Instant::now().as_micros(),
+            };
+            return Some((entry & RING_SAMPLE_MASK, at_us));
+        }
+    }
+
+    fn take_sample(&mut self, at_us: u64) {
+        let _ = self.trace_samples.dequeue();
+        self.sample_clock_us = Some(at_us);
     }
 
     fn reset_stream_state(&mut self) {
         self.packet_seq = 1;
         self.sample_seq = 0;
-        self.decoder = FeedbackDecoder::new();
+        self.decoder =
+            FeedbackDecoder::with_command_map(DroEncoding::Bcd, *self.decoder.command_map());
+        self.filter = FeedbackFilter::new(self.max_counts_per_s);
         self.current_snapshot = FeedbackSnapshot {
             sample_index: 0,
             x: AxisSnapshot {
@@ -113,113 +227,275 @@ impl PioTransport {
             },
             rpm_display: 0,
             rpm_raw: 0,
+            clean: false,
         };
         self.snapshot_valid = false;
+        self.snapshot_corrected = false;
         self.next_telemetry_due_ms = 0;
+        self.ring_high_water = 0;
         TRACE_QUEUE_DROP_COUNT.store(0, Ordering::Relaxed);
         TRACE_RXSTALL_COUNT.store(0, Ordering::Relaxed);
         self.clear_trace_samples();
     }
 
+    fn note_ring_fill(&mut self) {
+        let fill = self.trace_samples.len() as u16;
+        if fill > self.ring_high_water {
+            self.ring_high_water = fill;
+        }
+    }
+
+    fn health_counters(&self, now_ms: u64) -> HealthPayload {
+        let idle_ms = if TRACE_ACTIVITY_SEEN.load(Ordering::Relaxed) {
+            (now_ms as u32).wrapping_sub(TRACE_LAST_ACTIVITY_MS.load(Ordering::Relaxed))
+        } else {
+            HEALTH_IDLE_NEVER
+        };
+
+        let decoder_stats = self.decoder.stats();
+        HealthPayload {
+            queue_drop_count: TRACE_QUEUE_DROP_COUNT.load(Ordering::Relaxed),
+            rx_stall_count: TRACE_RXSTALL_COUNT.load(Ordering::Relaxed),
+            ring_fill: self.trace_samples.len() as u16,
+            ring_high_water: self.ring_high_water,
+            ring_capacity: self.trace_samples.capacity() as u16,
+            decoded_transactions: self.decoder.transactions(),
+            decoder_errors: decoder_stats.total(),
+            idle_ms,
+            decoder_stats,
+        }
+    }
+
+    fn health_due(&self, now_ms: u64) -> bool {
+        self.health_enabled && now_ms >= self.next_health_due_ms
+    }
+
+    /// Torn snapshots are dropped so telemetry keeps the last position
+    /// that came from a single cadence; carries torn within one cadence are
+    /// mended by the filter.
+    fn decode_sample(&mut self, now_ms: u64, sample: u32, at_us: u64) {
+        if let Some(mut snapshot) = self
+            .decoder
+            .ingest_sample(self.sample_seq, sample)
+            .filter(|snapshot| snapshot.clean)
+        {
+            let (x, z) = self.filter.apply(&mut snapshot, at_us);
+            self.snapshot_corrected = x.is_correction() || z.is_correction();
+            self.current_snapshot = snapshot;
+            self.snapshot_valid = true;
+            self.snapshot_ms = now_ms;
+            self.snapshot_us = at_us;
+        }
+        self.sample_seq = self.sample_seq.wrapping_add(1);
+    }
+
     fn flags(&self) -> u8 {
-        if self.telemetry_enabled {
-            FLAG_ENABLED
+        let enabled = if self.telemetry_enabled {
+            TELEMETRY_FLAG_ENABLED
         } else {
             0
-        }
+        };
+        let corrected = if self.snapshot_corrected {
+            TELEMETRY_FLAG_CORRECTED
+        } else {
+            0
+        };
+        enabled | corrected | self.units.telemetry_flags()
     }
 }
 
 impl Transport for PioTransport {
-    fn handle_request(&mut self, req: Packet, out: &mut [Packet; 2]) -> usize {
+    async fn handle_request(
+        &mut self,
+        req: PacketRef<'_>,
+        now_ms: u64,
+        replies: &mut impl ReplySink,
+    ) {
         match req.msg_type {
             MsgType::Ping => {
-                out[0] = Packet::ack(req.seq, MsgType::Ping, 0);
-                1
+                replies.send(Packet::ack(req.seq, MsgType::Ping, 0)).await;
             }
-            MsgType::CaptureSet => {
-                if req.payload_len < 1 {
-                    out[0] = Packet::nack(req.seq, MsgType::CaptureSet as u8, 1);
-                } else {
-                    self.telemetry_enabled = req.payload[0] == 0;
-                    self.capture_enabled = req.payload[0] != 0;
+            MsgType::Hello => {
+                let info = device_info(
+                    DeviceTransport::PioReal,
+                    PIN_MAP_PASSIVE_SNIFFER,
+                    SUPPORTED_MSG_TYPES,
+                );
+                replies
+                    .send_with_ack(Packet::from_payload(req.seq, &info), MsgType::Hello)
+                    .await;
+            }
+            MsgType::CaptureSet => match req.decode_payload::<CaptureSetPayload>() {
+                Ok(set) => {
+                    self.telemetry_enabled = !set.enable;
+                    self.capture_enabled = set.enable;
                     TRACE_CAPTURE_ENABLED.store(self.capture_enabled, Ordering::Relaxed);
                     self.reset_stream_state();
-                    out[0] = Packet::ack(req.seq, MsgType::CaptureSet, 0);
+                    self.trace_batch.set_sample_times(set.sample_times);
+                    replies
+                        .send(Packet::ack(req.seq, MsgType::CaptureSet, 0))
+                        .await;
                 }
-                1
-            }
+                Err(_) => {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::CaptureSet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                }
+            },
             MsgType::TelemetrySet => {
-                if req.payload_len < 1 {
-                    out[0] = Packet::nack(req.seq, MsgType::TelemetrySet as u8, 1);
-                } else {
-                    self.capture_enabled = req.payload[0] == 0;
-                    self.telemetry_enabled = req.payload[0] != 0;
-                    TRACE_CAPTURE_ENABLED.store(self.telemetry_enabled, Ordering::Relaxed); // weird, but must capture to decode
-                    self.reset_stream_state();
-                    if req.payload_len >= 3 {
-                        self.telemetry_period_ms =
-                            u16::from_le_bytes([req.payload[1], req.payload[2]]);
+                match req.decode_payload::<TelemetrySetPayload>() {
+                    Ok(set) => {
+                        self.capture_enabled = !set.enable;
+                        self.telemetry_enabled = set.enable;
+                        TRACE_CAPTURE_ENABLED.store(self.telemetry_enabled, Ordering::Relaxed); // weird, but must capture to decode
+                        self.reset_stream_state();
+                        if let Some(period_ms) = set.period_ms {
+                            self.telemetry_period_ms = period_ms;
+                        }
+                        replies
+                            .send(Packet::ack(req.seq, MsgType::TelemetrySet, 0))
+                            .await;
+                    }
+                    Err(_) => {
+                        replies
+                            .send(Packet::nack(
+                                req.seq,
+                                MsgType::TelemetrySet as u8,
+                                NackReason::InvalidPayload,
+                            ))
+                            .await;
+                    }
+                }
+            }
+            MsgType::HealthSet => match req.decode_payload::<HealthSetPayload>() {
+                Ok(set) => {
+                    self.health_enabled = set.enable;
+                    if let Some(period_ms) = set.period_ms {
+                        self.health_period_ms = period_ms;
                     }
-                    out[0] = Packet::ack(req.seq, MsgType::TelemetrySet, 0);
+                    self.next_health_due_ms = 0;
+                    replies
+                        .send(Packet::ack(req.seq, MsgType::HealthSet, 0))
+                        .await;
                 }
-                1
+                Err(_) => {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::HealthSet as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                }
+            },
+            MsgType::SnapshotReq => {
+                let age_ms = now_ms.saturating_sub(self.snapshot_ms);
+                let snapshot = SnapshotPayload {
+                    age_ms: age_ms.min(u32::MAX as u64) as u32,
+                    sample_index: self.current_snapshot.sample_index as u32,
+                    x_counts: self.current_snapshot.x.count(),
+                    z_counts: self.current_snapshot.z.count(),
+                    rpm_raw: self.current_snapshot.rpm_raw,
+                    rpm_display: self.current_snapshot.rpm_display,
+                    valid: self.snapshot_valid,
+                    flags: self.flags(),
+                };
+                replies
+                    .send_with_ack(Packet::snapshot(req.seq, &snapshot), MsgType::SnapshotReq)
+                    .await;
             }
+            MsgType::UnitCfg => match req.decode_payload::<UnitConfig>() {
+                Ok(units) => {
+                    self.units = units;
+                    replies
+                        .send(Packet::ack(req.seq, MsgType::UnitCfg, 0))
+                        .await;
+                }
+                Err(_) => {
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            MsgType::UnitCfg as u8,
+                            NackReason::InvalidPayload,
+                        ))
+                        .await;
+                }
+            },
             _ => {
                 if self.capture_enabled {
-                    out[0] = Packet::nack(req.seq, req.msg_type as u8, 0x10);
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            req.msg_type as u8,
+                            NackReason::CaptureActive,
+                        ))
+                        .await;
                 } else {
-                    out[0] = Packet::nack(req.seq, req.msg_type as u8, 0x11);
+                    replies
+                        .send(Packet::nack(
+                            req.seq,
+                            req.msg_type as u8,
+                            NackReason::CaptureInactive,
+                        ))
+                        .await;
                 }
-                1
             }
         }
     }
 
-    fn process_pending_work(&mut self, budget: usize) {
-        if !self.telemetry_enabled {
+    fn process_pending_work(&mut self, now_ms: u64, budget: usize) {
+        // In capture mode the samples belong to the USB stream; they are
+        // decoded as they are packetised instead.
+        if self.capture_enabled {
             return;
         }
 
+        self.note_ring_fill();
         let mut processed = 0usize;
         while processed < budget {
-            let Some(sample) = self.trace_samples.dequeue() else {
+            let Some((sample, at_us)) = self.peek_sample() else {
                 break;
             };
 
-            if let Some(snapshot) = self.decoder.ingest_sample(self.sample_seq, sample) {
-                self.current_snapshot = snapshot;
-                self.snapshot_valid = true;
-            }
-            self.sample_seq = self.sample_seq.wrapping_add(1);
+            self.take_sample(at_us);
+            self.decode_sample(now_ms, sample, at_us);
             processed += 1;
         }
     }
 
     fn poll_outgoing_packet(&mut self, now_ms: u64) -> Option<Packet> {
-        if self.capture_enabled {
-            let mut batch = [0u32; TRACE_SAMPLES_PER_PACKET];
-            let mut used = 0usize;
+        if self.health_due(now_ms) {
+            let pkt = Packet::health(self.health_seq, &self.health_counters(now_ms));
+            self.health_seq = self.health_seq.wrapping_add(1);
+            self.next_health_due_ms = now_ms + self.health_period_ms.max(1) as u64;
+            return Some(pkt);
+        }
 
-            while used < batch.len() {
-                let Some(sample) = self.trace_samples.dequeue() else {
+        if self.capture_enabled {
+            self.note_ring_fill();
+            // Busy polls repeat for hundreds of samples; keep taking them
+            // while either trace encoding still has room.
+            while let Some((sample, at_us)) = self.peek_sample() {
+                if !self.trace_batch.push(sample, at_us) {
                     break;
-                };
-                batch[used] = sample;
-                used += 1;
+                }
+                self.take_sample(at_us);
+                // Keep the snapshot current so SNAPSHOT_REQ works during capture.
+                self.decode_sample(now_ms, sample, at_us);
             }
 
-            if used == 0 {
+            if self.trace_batch.is_empty() {
                 return None;
             }
 
-            let dropped_samples_total = TRACE_QUEUE_DROP_COUNT.load(Ordering::Relaxed);
-            let rx_stall_count_total = TRACE_RXSTALL_COUNT.load(Ordering::Relaxed);
-            let pkt = Packet::trace_samples(
+            let pkt = self.trace_batch.finish(
                 self.packet_seq,
-                dropped_samples_total,
-                rx_stall_count_total,
-                &batch[..used],
+                TRACE_QUEUE_DROP_COUNT.load(Ordering::Relaxed),
+                TRACE_RXSTALL_COUNT.load(Ordering::Relaxed),
             );
             self.packet_seq = self.packet_seq.wrapping_add(1);
             return Some(pkt);
@@ -232,7 +508,7 @@ impl Transport for PioTransport {
 
             let pkt = Packet::telemetry(
                 self.packet_seq,
-                0,
+                self.snapshot_us as u32,
                 self.current_snapshot.x.count(),
                 self.current_snapshot.z.count(),
                 self.current_snapshot.rpm_display,
@@ -247,10 +523,13 @@ impl Transport for PioTransport {
     }
 
     fn has_decode_work(&self) -> bool {
-        self.telemetry_enabled && self.trace_samples.ready()
+        !self.capture_enabled && self.trace_samples.ready()
     }
 
     fn has_outgoing_packet(&self, now_ms: u64) -> bool {
+        if self.health_due(now_ms) {
+            return true;
+        }
         if self.capture_enabled {
             return self.trace_samples.ready();
         }
@@ -329,21 +608,52 @@ fn capture_core1_loop(
     let _ = pio.sm2.rx().stalled();
     log_info!("PIO initialised on core1");
 
+    // Timer value of the last entry queued; `None` forces a time marker.
+    let mut last_queued_us: Option<u64> = None;
+
     loop {
         let mut drained = false;
         while let Some(raw_sample) = pio.sm2.rx().try_pull() {
             drained = true;
 
+            if TRACE_TIME_RESYNC.swap(false, Ordering::Relaxed) {
+                last_queued_us = None;
+            }
             if !TRACE_CAPTURE_ENABLED.load(Ordering::Relaxed) {
+                last_queued_us = None;
                 continue;
             }
 
+            // Samples are stamped as they leave the RX FIFO, a few µs at
+            // most after the bus cycle.
+            let now_us = Instant::now().as_micros();
+            let delta_us = last_queued_us
+                .map(|last| now_us - last)
+                .filter(|&delta| delta <= RING_DELTA_MAX);
             let sample = encode_trace_sample(raw_sample);
-            if trace_samples.enqueue(sample).is_err() {
+            let entry = match delta_us {
+                Some(delta) => sample | (delta as u32) << RING_DELTA_SHIFT,
+                None => {
+                    if trace_samples.enqueue(time_marker(now_us)).is_err() {
+                        TRACE_QUEUE_DROP_COUNT.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    sample
+                }
+            };
+            if trace_samples.enqueue(entry).is_err() {
                 TRACE_QUEUE_DROP_COUNT.fetch_add(1, Ordering::Relaxed);
+                last_queued_us = None;
+            } else {
+                last_queued_us = Some(now_us);
             }
         }
 
+        if drained {
+            TRACE_LAST_ACTIVITY_MS.store(Instant::now().as_millis() as u32, Ordering::Relaxed);
+            TRACE_ACTIVITY_SEEN.store(true, Ordering::Relaxed);
+        }
+
         if pio.sm2.rx().stalled() {
             TRACE_RXSTALL_COUNT.fetch_add(1, Ordering::Relaxed);
         }
@@ -357,6 +667,20 @@ fn capture_core1_loop(
 #[inline]
 fn encode_trace_sample(raw_sample: u32) -> u32 {
     // The non-consecutive hardware map keeps bus bits on GPIO0..17 and uses
-    // GPIO20 for FRED_N, leaving GPIO18/19 intentionally unused.
-    raw_sample
+    // GPIO20 for FRED_N, leaving GPIO18/19 intentionally unused. Bit 17
+    // is set so a sample taken with 1MHZE low is not read as a marker.
+    (raw_sample & RING_SAMPLE_MASK) | RING_CLOCK_HIGH
+}
+
+#[inline]
+fn time_marker(now_us: u64) -> u32 {
+    let low = now_us as u32 & 0x7FFF_FFFF;
+    (low & 0x0001_FFFF) | (low >> 17) << RING_DELTA_SHIFT
+}
+
+/// Expands a marker's 31-bit timestamp against the current 64-bit timer.
+fn marker_time_us(marker: u32, now_us: u64) -> u64 {
+    let low = (marker & 0x0001_FFFF) | (marker >> RING_DELTA_SHIFT) << 17;
+    let age = (now_us as u32).wrapping_sub(low) & 0x7FFF_FFFF;
+    now_us - age as u64
 }
@@ -2,27 +2,82 @@ use std::io;
 use std::io::{ErrorKind, Read, Write};
 
 use rp2040_fred_protocol::bridge_proto::{
-    pack_trace_sample, unpack_trace_sample, TraceSamples, TRACE_PACKED_SAMPLE_SIZE,
+    pack_trace_sample, unpack_trace_sample, TraceSamples, TRACE_COMPRESSED_MAX_SAMPLES,
+    TRACE_PACKED_SAMPLE_SIZE,
 };
 
+use crate::sequence::SeqEvent;
+
 const CAPTURE_MAGIC: [u8; 8] = *b"FREDCAP\0";
-const CAPTURE_VERSION: u32 = 2;
+const CAPTURE_VERSION: u32 = 4;
 const RESERVED: u32 = 0;
-const MAX_BATCH_SAMPLES: usize = 4096;
+const MAX_BATCH_SAMPLES: usize = TRACE_COMPRESSED_MAX_SAMPLES;
+const BATCH_FLAG_SAMPLE_TIMES: u32 = 1 << 0;
+const BATCH_FLAG_SEQ: u32 = 1 << 1;
+const BATCH_FLAG_SEQ_GAP: u32 = 1 << 2;
+const BATCH_FLAG_SEQ_RESET: u32 = 1 << 3;
+const BATCH_FLAG_SEQ_DUPLICATE: u32 = 1 << 4;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum CaptureEncoding {
     Raw32,
     Packed3,
+    /// `Packed3` plus the batch timestamp and optional per-sample offsets.
+    Timed,
+    /// `Timed` plus the packet sequence number and any gap before it.
+    Sequenced,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct CaptureBatch {
     pub dropped_samples_total: u32,
     pub rx_stall_count_total: u32,
+    /// Device µs timestamp of the first sample; `None` in pre-v3 files.
+    pub first_sample_us: Option<u64>,
+    /// Per-sample offsets from `first_sample_us`, or empty if the capture
+    /// was taken without sample times.
+    pub sample_offsets_us: Vec<u32>,
+    /// Trace packet `seq`; `None` in pre-v4 files.
+    pub seq: Option<u16>,
+    /// How `seq` followed the previous packet. A gap here is USB-level loss,
+    /// distinct from `dropped_samples_total` on the device.
+    pub seq_event: SeqEvent,
     pub samples: Vec<u32>,
 }
 
+impl CaptureBatch {
+    pub fn from_trace(trace: TraceSamples<'_>) -> Self {
+        let mut samples = Vec::with_capacity(trace.sample_count());
+        let mut sample_offsets_us = Vec::new();
+        for timed in trace.iter_timed_samples() {
+            samples.push(timed.sample);
+            if let (true, Some(at_us)) = (trace.has_sample_times(), timed.at_us) {
+                sample_offsets_us.push(at_us.wrapping_sub(trace.first_sample_us) as u32);
+            }
+        }
+
+        Self {
+            dropped_samples_total: trace.dropped_samples_total,
+            rx_stall_count_total: trace.rx_stall_count_total,
+            first_sample_us: Some(trace.first_sample_us),
+            sample_offsets_us,
+            seq: None,
+            seq_event: SeqEvent::InOrder,
+            samples,
+        }
+    }
+
+    /// Device time of sample `index`, when the batch records it.
+    pub fn sample_time_us(&self, index: usize) -> Option<u64> {
+        let first = self.first_sample_us?;
+        match self.sample_offsets_us.get(index) {
+            Some(&offset) => Some(first + offset as u64),
+            None if index == 0 => Some(first),
+            None => None,
+        }
+    }
+}
+
 pub struct CaptureWriter<W> {
     inner: W,
 }
@@ -35,37 +90,58 @@ impl<W: Write> CaptureWriter<W> {
         Ok(Self { inner })
     }
 
+    /// Writes one trace packet as a batch. Compressed packets are stored
+    /// expanded, so readers never see the wire encoding.
     pub fn write_trace(&mut self, trace: TraceSamples<'_>) -> io::Result<()> {
-        let sample_count = u32::try_from(trace.sample_count()).map_err(|_| {
-            io::Error::new(ErrorKind::InvalidInput, "too many samples in capture batch")
-        })?;
-
-        self.inner
-            .write_all(&trace.dropped_samples_total.to_le_bytes())?;
-        self.inner
-            .write_all(&trace.rx_stall_count_total.to_le_bytes())?;
-        self.inner.write_all(&sample_count.to_le_bytes())?;
-        self.inner.write_all(trace.packed_sample_bytes())?;
-        Ok(())
+        self.write_batch(&CaptureBatch::from_trace(trace))
     }
 
-    pub fn write_batch(
-        &mut self,
-        dropped_samples_total: u32,
-        rx_stall_count_total: u32,
-        samples: &[u32],
-    ) -> io::Result<()> {
-        let sample_count = u32::try_from(samples.len()).map_err(|_| {
+    pub fn write_batch(&mut self, batch: &CaptureBatch) -> io::Result<()> {
+        let sample_count = u32::try_from(batch.samples.len()).map_err(|_| {
             io::Error::new(ErrorKind::InvalidInput, "too many samples in capture batch")
         })?;
+        let timed = !batch.sample_offsets_us.is_empty();
+        if timed && batch.sample_offsets_us.len() != batch.samples.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "capture batch needs one time offset per sample",
+            ));
+        }
+        let mut flags = if timed { BATCH_FLAG_SAMPLE_TIMES } else { 0 };
+        if batch.seq.is_some() {
+            flags |= BATCH_FLAG_SEQ;
+        }
+        let expected = match batch.seq_event {
+            SeqEvent::InOrder => 0,
+            SeqEvent::Gap { expected, .. } => {
+                flags |= BATCH_FLAG_SEQ_GAP;
+                expected
+            }
+            SeqEvent::Reset { expected } => {
+                flags |= BATCH_FLAG_SEQ_RESET;
+                expected
+            }
+            SeqEvent::Duplicate => {
+                flags |= BATCH_FLAG_SEQ_DUPLICATE;
+                0
+            }
+        };
 
-        self.inner.write_all(&dropped_samples_total.to_le_bytes())?;
-        self.inner.write_all(&rx_stall_count_total.to_le_bytes())?;
-        self.inner.write_all(&sample_count.to_le_bytes())?;
-        for sample in samples {
-            self.inner.write_all(&pack_trace_sample(*sample))?;
+        let mut bytes = Vec::with_capacity(24 + batch.samples.len() * 7);
+        bytes.extend_from_slice(&batch.dropped_samples_total.to_le_bytes());
+        bytes.extend_from_slice(&batch.rx_stall_count_total.to_le_bytes());
+        bytes.extend_from_slice(&sample_count.to_le_bytes());
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes.extend_from_slice(&batch.first_sample_us.unwrap_or(0).to_le_bytes());
+        bytes.extend_from_slice(&batch.seq.unwrap_or(0).to_le_bytes());
+        bytes.extend_from_slice(&expected.to_le_bytes());
+        for sample in &batch.samples {
+            bytes.extend_from_slice(&pack_trace_sample(*sample));
+        }
+        for offset in &batch.sample_offsets_us {
+            bytes.extend_from_slice(&offset.to_le_bytes());
         }
-        Ok(())
+        self.inner.write_all(&bytes)
     }
 }
 
@@ -88,7 +164,9 @@ impl<R: Read> CaptureReader<R> {
         let version = read_u32(&mut inner)?;
         let encoding = match version {
             1 => CaptureEncoding::Raw32,
-            CAPTURE_VERSION => CaptureEncoding::Packed3,
+            2 => CaptureEncoding::Packed3,
+            3 => CaptureEncoding::Timed,
+            CAPTURE_VERSION => CaptureEncoding::Sequenced,
             _ => {
                 return Err(io::Error::new(
                     ErrorKind::InvalidData,
@@ -114,27 +192,63 @@ impl<R: Read> CaptureReader<R> {
             ));
         }
 
-        let mut samples = Vec::with_capacity(sample_count);
+        let mut batch = CaptureBatch {
+            dropped_samples_total,
+            rx_stall_count_total,
+            samples: Vec::with_capacity(sample_count),
+            ..CaptureBatch::default()
+        };
+        let mut flags = 0;
+        if matches!(
+            self.encoding,
+            CaptureEncoding::Timed | CaptureEncoding::Sequenced
+        ) {
+            flags = read_u32(&mut self.inner)?;
+            batch.first_sample_us = Some(read_u64(&mut self.inner)?);
+        }
+        if self.encoding == CaptureEncoding::Sequenced {
+            let seq = read_u16(&mut self.inner)?;
+            let expected = read_u16(&mut self.inner)?;
+            if flags & BATCH_FLAG_SEQ != 0 {
+                batch.seq = Some(seq);
+            }
+            batch.seq_event = if flags & BATCH_FLAG_SEQ_GAP != 0 {
+                SeqEvent::Gap {
+                    expected,
+                    lost: seq.wrapping_sub(expected),
+                }
+            } else if flags & BATCH_FLAG_SEQ_RESET != 0 {
+                SeqEvent::Reset { expected }
+            } else if flags & BATCH_FLAG_SEQ_DUPLICATE != 0 {
+                SeqEvent::Duplicate
+            } else {
+                SeqEvent::InOrder
+            };
+        }
+
         match self.encoding {
             CaptureEncoding::Raw32 => {
                 for _ in 0..sample_count {
-                    samples.push(read_u32(&mut self.inner)?);
+                    batch.samples.push(read_u32(&mut self.inner)?);
                 }
             }
-            CaptureEncoding::Packed3 => {
+            CaptureEncoding::Packed3 | CaptureEncoding::Timed | CaptureEncoding::Sequenced => {
                 for _ in 0..sample_count {
                     let mut packed = [0u8; TRACE_PACKED_SAMPLE_SIZE];
                     self.inner.read_exact(&mut packed)?;
-                    samples.push(unpack_trace_sample(packed));
+                    batch.samples.push(unpack_trace_sample(packed));
                 }
             }
         }
 
-        Ok(Some(CaptureBatch {
-            dropped_samples_total,
-            rx_stall_count_total,
-            samples,
-        }))
+        if flags & BATCH_FLAG_SAMPLE_TIMES != 0 {
+            batch.sample_offsets_us.reserve(sample_count);
+            for _ in 0..sample_count {
+                batch.sample_offsets_us.push(read_u32(&mut self.inner)?);
+            }
+        }
+
+        Ok(Some(batch))
     }
 }
 
@@ -144,6 +258,18 @@ fn read_u32<R: Read>(inner: &mut R) -> io::Result<u32> {
     Ok(u32::from_le_bytes(buf))
 }
 
+fn read_u16<R: Read>(inner: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    inner.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(inner: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    inner.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
 fn read_u32_or_eof<R: Read>(inner: &mut R) -> io::Result<Option<u32>> {
     let mut buf = [0u8; 4];
     let mut filled = 0usize;
@@ -168,31 +294,100 @@ fn read_u32_or_eof<R: Read>(inner: &mut R) -> io::Result<Option<u32>> {
 mod tests {
     use std::io::Cursor;
 
-    use super::{CaptureReader, CaptureWriter, CAPTURE_MAGIC};
+    use rp2040_fred_protocol::bridge_proto::{MsgType, TraceBatchEncoder};
 
-    #[test]
-    fn roundtrip_capture_batches() {
+    use super::{CaptureBatch, CaptureReader, CaptureWriter, CAPTURE_MAGIC};
+    use crate::sequence::SeqEvent;
+
+    fn roundtrip(batches: &[CaptureBatch]) -> Vec<CaptureBatch> {
         let mut bytes = Vec::new();
         {
             let mut writer = CaptureWriter::new(&mut bytes).expect("writer");
-            writer
-                .write_batch(12, 3, &[0x0003_8003, 0x0003_F132, 0x0003_8002])
-                .expect("batch 1");
-            writer.write_batch(19, 4, &[0x0003_F107]).expect("batch 2");
+            for batch in batches {
+                writer.write_batch(batch).expect("write");
+            }
         }
 
         let mut reader = CaptureReader::new(Cursor::new(bytes)).expect("reader");
-        let batch1 = reader.read_batch().expect("read 1").expect("batch 1");
-        assert_eq!(batch1.dropped_samples_total, 12);
-        assert_eq!(batch1.rx_stall_count_total, 3);
-        assert_eq!(batch1.samples.len(), 3);
+        let mut read = Vec::new();
+        while let Some(batch) = reader.read_batch().expect("read") {
+            read.push(batch);
+        }
+        read
+    }
+
+    #[test]
+    fn roundtrip_capture_batches() {
+        let batches = [
+            CaptureBatch {
+                dropped_samples_total: 12,
+                rx_stall_count_total: 3,
+                first_sample_us: Some(1_000),
+                samples: vec![0x0003_8003, 0x0003_F132, 0x0003_8002],
+                ..CaptureBatch::default()
+            },
+            CaptureBatch {
+                dropped_samples_total: 19,
+                rx_stall_count_total: 4,
+                first_sample_us: Some(9_000),
+                sample_offsets_us: vec![0, 7],
+                seq: Some(9),
+                seq_event: SeqEvent::Gap {
+                    expected: 6,
+                    lost: 3,
+                },
+                samples: vec![0x0003_F107, 0x0003_F107],
+            },
+            CaptureBatch {
+                first_sample_us: Some(9_500),
+                seq: Some(1),
+                seq_event: SeqEvent::Reset { expected: 10 },
+                samples: vec![0x0003_F107],
+                ..CaptureBatch::default()
+            },
+        ];
+        let read = roundtrip(&batches);
+        assert_eq!(read, batches);
 
-        let batch2 = reader.read_batch().expect("read 2").expect("batch 2");
-        assert_eq!(batch2.dropped_samples_total, 19);
-        assert_eq!(batch2.rx_stall_count_total, 4);
-        assert_eq!(batch2.samples, vec![0x0003_F107]);
+        assert_eq!(read[0].sample_time_us(0), Some(1_000));
+        assert_eq!(read[0].sample_time_us(1), None);
+        assert_eq!(read[1].sample_time_us(1), Some(9_007));
+    }
+
+    #[test]
+    fn compressed_trace_is_stored_expanded() {
+        let samples: Vec<u32> = std::iter::repeat_n(0x0003_F0F0, 500)
+            .chain([0x0003_8003])
+            .collect();
+        let mut encoder = TraceBatchEncoder::new();
+        for &sample in &samples {
+            assert!(encoder.push(sample, 42));
+        }
+        let pkt = encoder.finish(1, 5, 0);
+        assert_eq!(pkt.msg_type, MsgType::TraceSampleCompressed);
 
-        assert!(reader.read_batch().expect("read eof").is_none());
+        let batch = CaptureBatch::from_trace(pkt.decode_trace_samples().expect("trace"));
+        let read = roundtrip(std::slice::from_ref(&batch));
+        assert_eq!(read[0].dropped_samples_total, 5);
+        assert_eq!(read[0].first_sample_us, Some(42));
+        assert!(read[0].sample_offsets_us.is_empty());
+        assert_eq!(read[0].samples, samples);
+    }
+
+    #[test]
+    fn timed_trace_keeps_sample_offsets() {
+        let mut encoder = TraceBatchEncoder::new();
+        encoder.set_sample_times(true);
+        for (sample, at_us) in [(0x0003_8003, 100), (0x0003_F132, 103), (0x0003_F0F0, 160)] {
+            assert!(encoder.push(sample, at_us));
+        }
+        let pkt = encoder.finish(2, 0, 0);
+
+        let batch = CaptureBatch::from_trace(pkt.decode_trace_samples().expect("trace"));
+        assert_eq!(batch.sample_offsets_us, vec![0, 3, 60]);
+        let read = roundtrip(std::slice::from_ref(&batch));
+        assert_eq!(read[0], batch);
+        assert_eq!(read[0].sample_time_us(2), Some(160));
     }
 
     #[test]
@@ -211,7 +406,26 @@ mod tests {
         let batch = reader.read_batch().expect("read").expect("batch");
         assert_eq!(batch.dropped_samples_total, 12);
         assert_eq!(batch.rx_stall_count_total, 3);
+        assert_eq!(batch.first_sample_us, None);
         assert_eq!(batch.samples, vec![0x0003_8003, 0x0003_F132]);
         assert!(reader.read_batch().expect("eof").is_none());
     }
+
+    #[test]
+    fn reads_v2_packed_capture_batches() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&CAPTURE_MAGIC);
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&[0x32, 0xF1, 0x01]);
+
+        let mut reader = CaptureReader::new(Cursor::new(bytes)).expect("reader");
+        let batch = reader.read_batch().expect("read").expect("batch");
+        assert_eq!(batch.first_sample_us, None);
+        assert_eq!(batch.samples, vec![0x0003_F132]);
+        assert!(reader.read_batch().expect("eof").is_none());
+    }
 }
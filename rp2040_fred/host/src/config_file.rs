@@ -0,0 +1,152 @@
+//! Device settings kept in a text file, one `name = value` per line in the
+//! form `fredctl config set` takes. `#` starts a comment. A file describing
+//! another ROM revision looks like:
+//!
+//! ```text
+//! # TCL 240, ROM v1.x
+//! x_commands = 13,12,11,10
+//! z_commands = 17,16,15,14
+//! rpm_commands = 1D,1C
+//! ```
+//!
+//! The calibration keys (`x_counts_per_mm`, `x_inverted`, `x_zero_offset`
+//! and their Z twins, with `units` and `x_mode`) also make a
+//! [`CalibrationProfile`] for the monitor.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rp2040_fred_protocol::config::{ConfigKey, ConfigValue, DeviceConfig};
+use rp2040_fred_protocol::dro_decode::CalibrationProfile;
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<ConfigValue>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+pub fn parse(text: &str) -> io::Result<Vec<ConfigValue>> {
+    let mut values = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |what: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {what}", i + 1),
+            )
+        };
+        let (name, text) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("expected `name = value`, got `{line}`")))?;
+        let (name, text) = (name.trim(), text.trim());
+        let key =
+            ConfigKey::from_name(name).ok_or_else(|| invalid(format!("unknown key `{name}`")))?;
+        let value = ConfigValue::parse(key, text)
+            .map_err(|_| invalid(format!("invalid value for {name}: {text}")))?;
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// The defaults with `values` applied, checked as the device checks a
+/// `CONFIG_SET`; a command map that reuses a command is an error.
+pub fn device_config(values: &[ConfigValue]) -> io::Result<DeviceConfig> {
+    let entries: Vec<_> = values.iter().map(|&value| value.into()).collect();
+    let mut config = DeviceConfig::default();
+    config.apply(&entries).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "settings use one command for two DRO fields",
+        )
+    })?;
+    Ok(config)
+}
+
+/// The calibration in `values`. Units and X mode are only overridden when
+/// the file sets them; otherwise the device's choice is followed.
+pub fn calibration_profile(values: &[ConfigValue]) -> io::Result<CalibrationProfile> {
+    let config = device_config(values)?;
+    let mut profile = CalibrationProfile {
+        calibration: config.calibration,
+        ..CalibrationProfile::default()
+    };
+    for value in values {
+        match *value {
+            ConfigValue::Units(units) => profile.units = Some(units),
+            ConfigValue::XMode(mode) => profile.x_mode = Some(mode),
+            _ => {}
+        }
+    }
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use rp2040_fred_protocol::bridge_proto::XAxisMode;
+    use rp2040_fred_protocol::command_map::CommandMap;
+    use rp2040_fred_protocol::config::ConfigValue;
+    use rp2040_fred_protocol::dro_decode::AxisCalibration;
+
+    use super::{calibration_profile, device_config, parse};
+
+    #[test]
+    fn parses_a_command_map_for_another_rom() {
+        let values = parse(
+            "# TCL 240\n\
+             x_commands = 13,12,11,10\n\
+             z_commands = 17,16,15,14  # Z\n\
+             \n\
+             rpm_commands=1D,1C\n\
+             max_counts_per_s = 0\n",
+        )
+        .expect("valid file");
+        assert_eq!(values[3], ConfigValue::MaxCountsPerS(0));
+
+        let config = device_config(&values).expect("distinct commands");
+        assert_eq!(
+            config.command_map,
+            CommandMap {
+                x: [0x13, 0x12, 0x11, 0x10],
+                z: [0x17, 0x16, 0x15, 0x14],
+                rpm: [0x1D, 0x1C],
+            }
+        );
+    }
+
+    #[test]
+    fn builds_a_calibration_profile() {
+        let values = parse(
+            "x_counts_per_mm = 200
+             x_inverted = true
+             z_zero_offset = -1500
+             x_mode = radius
+",
+        )
+        .expect("valid file");
+        let profile = calibration_profile(&values).expect("valid profile");
+        assert_eq!(
+            profile.calibration.x,
+            AxisCalibration {
+                counts_per_mm: 200.0,
+                inverted: true,
+                zero_offset: 0,
+            }
+        );
+        assert_eq!(profile.calibration.z.zero_offset, -1500);
+        assert_eq!(profile.x_mode, Some(XAxisMode::Radius));
+        assert_eq!(profile.units, None);
+    }
+
+    #[test]
+    fn reports_the_offending_line() {
+        let err = parse("x_commands = 03,02,01,00\nrpm_commands = 0D\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"), "{err}");
+        let err = parse("speed = 3\n").unwrap_err();
+        assert!(err.to_string().contains("unknown key `speed`"), "{err}");
+
+        let clash = parse("rpm_commands = 0D,03\n").expect("parses");
+        assert!(device_config(&clash).is_err());
+    }
+}
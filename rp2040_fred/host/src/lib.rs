@@ -1,3 +1,5 @@
 pub mod capture_file;
+pub mod config_file;
 pub mod monitor;
+pub mod sequence;
 pub mod transport;
@@ -2,33 +2,155 @@ use std::env;
 use std::fs::File;
 use std::io;
 use std::io::BufReader;
+use std::process::ExitCode;
+use std::time::Duration;
 
-use fredctl::capture_file::{CaptureReader, CaptureWriter};
+use fredctl::capture_file::{CaptureBatch, CaptureReader, CaptureWriter};
+use fredctl::config_file;
 use fredctl::monitor::FredMonitorClient;
-use fredctl::transport::{HostTransport, UsbTransport};
-use rp2040_fred_protocol::bridge_proto::Packet;
-use rp2040_fred_protocol::trace_decode::{AxisSnapshot, FeedbackDecoder, FeedbackSnapshot};
+use fredctl::sequence::{SeqEvent, SeqTracker};
+use fredctl::transport::{
+    check_trace_layout, query_device_info, read_config, write_config, HostTransport,
+    SerialTransport, UsbTransport,
+};
+use rp2040_fred_protocol::bridge_proto::compat::Downgrade;
+use rp2040_fred_protocol::bridge_proto::{
+    CaptureSetPayload, HealthPayload, LinearUnits, LogPayload, MsgType, Packet, UnitConfig,
+    XAxisMode, HEALTH_IDLE_NEVER,
+};
+use rp2040_fred_protocol::config::{ConfigEntry, ConfigKey, ConfigValue};
+use rp2040_fred_protocol::device_log::LogLevel;
+use rp2040_fred_protocol::dro_decode::{
+    AxisCheck, CalibrationProfile, DroEncoding, ASSUMED_CADENCE_US,
+};
+use rp2040_fred_protocol::fred_transaction::{
+    FredEvent, FredTransaction, FredTransactionAssembler,
+};
+use rp2040_fred_protocol::trace_decode::{
+    AxisSnapshot, DecoderStats, FeedbackDecoder, FeedbackFilter, FeedbackSnapshot, TraceCycle,
+};
+
+const TRACE_READ_TIMEOUT: Duration = Duration::from_millis(600_000);
+
+fn main() -> ExitCode {
+    // `Display` rather than the default `Debug`, so a NACK reads as the
+    // device's reason for refusing the request.
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("fredctl: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-fn main() -> io::Result<()> {
+fn run() -> io::Result<()> {
     let mut args = env::args().skip(1);
     let cmd = args.next().unwrap_or_else(|| "help".to_string());
-    let mode = args.next().unwrap_or_else(|| "".to_string());
+    let mode = args.next().unwrap_or_default();
 
     match (cmd.as_str(), mode.as_str()) {
+        ("info", "usb") => {
+            let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+            print_device_info(&mut t)?;
+            warn_downgrade(t.downgrade());
+            Ok(())
+        }
+        ("info", "serial") => {
+            let usage = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "usage: fredctl info serial <port> [baud]",
+                )
+            };
+            let path = args.next().ok_or_else(usage)?;
+            let baud = match args.next() {
+                Some(arg) => arg.parse().map_err(|_| usage())?,
+                None => 115_200,
+            };
+            let mut t = SerialTransport::open(&path, baud)?;
+            t.set_timeout(Duration::from_millis(250))?;
+            print_device_info(&mut t)
+        }
         ("monitor-on", "usb") => set_usb_telemetry(true),
         ("monitor-off", "usb") => set_usb_telemetry(false),
-        ("monitor", "usb") => monitor_usb(),
+        ("monitor", "usb") => {
+            let profile = match args.next() {
+                Some(path) => Some(config_file::calibration_profile(&config_file::load(path)?)?),
+                None => None,
+            };
+            monitor_usb(profile)
+        }
+        ("snapshot", "usb") => snapshot_usb(),
+        ("health", "usb") => {
+            let period_ms = match args.next() {
+                Some(arg) => arg.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "usage: fredctl health usb [period_ms]",
+                    )
+                })?,
+                None => 1000,
+            };
+            health_usb(period_ms)
+        }
+        ("log", "usb") => {
+            let max_level = match args.next() {
+                Some(name) => LogLevel::from_name(&name).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "usage: fredctl log usb [error|warn|info|debug]",
+                    )
+                })?,
+                None => LogLevel::Info,
+            };
+            log_usb(max_level)
+        }
+        ("units", "usb") => {
+            let units = parse_units(args.next().as_deref(), args.next().as_deref())?;
+            set_usb_units(units)
+        }
+        ("config", "list") => config_list_usb(),
+        ("config", "get") => {
+            let key = parse_config_key(args.next().as_deref())?;
+            config_get_usb(key)
+        }
+        ("config", "set") => {
+            let key = parse_config_key(args.next().as_deref())?;
+            let text = args.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "usage: fredctl config set <key> <value>",
+                )
+            })?;
+            let value = ConfigValue::parse(key, &text).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid value for {}: {text}", key.name()),
+                )
+            })?;
+            config_set_usb(value)
+        }
+        ("config", "load") => {
+            let path = args.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "usage: fredctl config load <settings.cfg>",
+                )
+            })?;
+            config_load_usb(&path)
+        }
         ("capture-on", "usb") => set_usb_capture(true),
         ("capture-off", "usb") => set_usb_capture(false),
-        ("capture", "usb") => capture_usb(),
+        ("capture", "usb") => capture_usb(parse_timed(args.next().as_deref())?),
         ("capture", "file") => {
             let path = args.next().ok_or_else(|| {
                 io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    "usage: fredctl capture file <capture.bin>",
+                    "usage: fredctl capture file <capture.bin> [timed]",
                 )
             })?;
-            capture_usb_to_file(&path)
+            capture_usb_to_file(&path, parse_timed(args.next().as_deref())?)
         }
         ("raw", "file") => {
             let path = args.next().ok_or_else(|| {
@@ -39,15 +161,47 @@ fn main() -> io::Result<()> {
             })?;
             raw_capture_file(&path)
         }
-        ("decode", "usb") => decode_usb_capture(),
+        ("decode", "usb") => decode_usb_capture(parse_timed(args.next().as_deref())?),
         ("decode", "file") => {
             let path = args.next().ok_or_else(|| {
                 io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    "usage: fredctl decode file <capture.bin>",
+                    "usage: fredctl decode file <capture.bin> [max_counts_per_s|settings.cfg]",
                 )
             })?;
-            decode_capture_file(&path)
+            let printer = match args.next() {
+                Some(limit) if limit.bytes().all(|b| b.is_ascii_digit()) => DecodePrinter {
+                    filter: FeedbackFilter::new(limit.parse().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid max_counts_per_s: {limit}"),
+                        )
+                    })?),
+                    ..DecodePrinter::default()
+                },
+                Some(settings) => {
+                    let config = config_file::device_config(&config_file::load(settings)?)?;
+                    DecodePrinter {
+                        decoder: FeedbackDecoder::with_command_map(
+                            DroEncoding::Bcd,
+                            config.command_map,
+                        ),
+                        filter: FeedbackFilter::new(config.max_counts_per_s),
+                        ..DecodePrinter::default()
+                    }
+                }
+                None => DecodePrinter::default(),
+            };
+            decode_capture_file(&path, printer)
+        }
+        ("transactions", "file") => {
+            let path = args.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "usage: fredctl transactions file <capture.bin>",
+                )
+            })?;
+            transactions_capture_file(&path)
         }
         _ => {
             print_help();
@@ -58,189 +212,615 @@ fn main() -> io::Result<()> {
 
 fn print_help() {
     eprintln!("usage:");
+    eprintln!("  fredctl info usb");
+    eprintln!("  fredctl info serial <port> [baud]");
     eprintln!("  fredctl monitor-on usb");
     eprintln!("  fredctl monitor-off usb");
-    eprintln!("  fredctl monitor usb");
+    eprintln!("  fredctl monitor usb [settings.cfg]");
+    eprintln!("  fredctl snapshot usb");
+    eprintln!("  fredctl health usb [period_ms]");
+    eprintln!("  fredctl log usb [error|warn|info|debug]");
+    eprintln!("  fredctl units usb <metric|imperial> [diameter|radius]");
+    eprintln!("  fredctl config list");
+    eprintln!("  fredctl config get <key>");
+    eprintln!("  fredctl config set <key> <value>");
+    eprintln!("  fredctl config load <settings.cfg>");
     eprintln!("  fredctl capture-on usb");
     eprintln!("  fredctl capture-off usb");
-    eprintln!("  fredctl capture usb");
-    eprintln!("  fredctl capture file <capture.bin>");
+    eprintln!("  fredctl capture usb [timed]");
+    eprintln!("  fredctl capture file <capture.bin> [timed]");
     eprintln!("  fredctl raw file <capture.bin>");
-    eprintln!("  fredctl decode usb");
-    eprintln!("  fredctl decode file <capture.bin>");
+    eprintln!("  fredctl decode usb [timed]");
+    eprintln!("  fredctl decode file <capture.bin> [max_counts_per_s|settings.cfg]");
+    eprintln!("  fredctl transactions file <capture.bin>");
 }
 
 fn set_usb_telemetry(enable: bool) -> io::Result<()> {
     let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
     let _ = t.transact(Packet::capture_set(1, false))?;
-    let req = Packet::telemetry_set(2, enable, 100);
-    let replies = t.transact(req)?;
+    t.transact(Packet::telemetry_set(2, enable, 100))?;
+    println!("usb telemetry {} -> ACK", if enable { "ON" } else { "OFF" });
+    Ok(())
+}
+
+fn parse_units(units: Option<&str>, x_mode: Option<&str>) -> io::Result<UnitConfig> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: fredctl units usb <metric|imperial> [diameter|radius]",
+        )
+    };
+    let units = match units {
+        Some("metric") => LinearUnits::Metric,
+        Some("imperial") => LinearUnits::Imperial,
+        _ => return Err(usage()),
+    };
+    let x_mode = match x_mode {
+        None | Some("diameter") => XAxisMode::Diameter,
+        Some("radius") => XAxisMode::Radius,
+        Some(_) => return Err(usage()),
+    };
+    Ok(UnitConfig { units, x_mode })
+}
+
+fn set_usb_units(units: UnitConfig) -> io::Result<()> {
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    t.transact(Packet::unit_cfg(1, units))?;
+    println!("usb units {:?}/{:?} -> ACK", units.units, units.x_mode);
+    Ok(())
+}
+
+fn parse_config_key(name: Option<&str>) -> io::Result<ConfigKey> {
+    let name = name.unwrap_or_default();
+    ConfigKey::from_name(name).ok_or_else(|| {
+        let keys: Vec<_> = ConfigKey::ALL.iter().map(|key| key.name()).collect();
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown config key `{name}` (one of: {})", keys.join(", ")),
+        )
+    })
+}
+
+fn print_config_entry(entry: &ConfigEntry) {
+    match entry.decode_value() {
+        Ok(value) => println!("{:<20} {value}", value.key().name()),
+        Err(_) => println!(
+            "{:<20} 0x{:08X}",
+            format!("key_0x{:02X}", entry.key),
+            entry.value
+        ),
+    }
+}
+
+fn config_list_usb() -> io::Result<()> {
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    for entry in read_config(&mut t, 1, None)? {
+        print_config_entry(&entry);
+    }
+    Ok(())
+}
+
+fn config_get_usb(key: ConfigKey) -> io::Result<()> {
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    for entry in read_config(&mut t, 1, Some(key))? {
+        print_config_entry(&entry);
+    }
+    Ok(())
+}
+
+fn config_set_usb(value: ConfigValue) -> io::Result<()> {
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    write_config(&mut t, 1, &[value])?;
     println!(
-        "usb telemetry {} -> {} reply packet(s)",
-        if enable { "ON" } else { "OFF" },
-        replies.len()
+        "usb config {} = {value} -> ACK (applies at next power-up)",
+        value.key().name()
     );
     Ok(())
 }
 
-fn monitor_usb() -> io::Result<()> {
-    let mut client = FredMonitorClient::open(0x2E8A, 0x000A)?;
+/// Checks the whole file before sending it, so a bad line stores nothing.
+fn config_load_usb(path: &str) -> io::Result<()> {
+    let values = config_file::load(path)?;
+    config_file::device_config(&values)?;
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    write_config(&mut t, 1, &values)?;
+    for value in &values {
+        println!("usb config {} = {value}", value.key().name());
+    }
+    println!("usb config load {path} -> ACK (applies at next power-up)");
+    Ok(())
+}
+
+/// `profile` of `None` uses the calibration stored on the device.
+fn monitor_usb(profile: Option<CalibrationProfile>) -> io::Result<()> {
+    let mut client =
+        FredMonitorClient::open_with_options(0x2E8A, 0x000A, Duration::from_millis(250), profile)?;
     client.enable_polling(25)?;
-    println!("step  X_mm        Z_mm        RPM");
+    warn_downgrade(client.downgrade());
+    // Older firmware has no LOG stream; monitor without it.
+    let logs = client
+        .device_info()
+        .is_some_and(|info| info.supports(MsgType::LogSet));
+    if logs {
+        client.enable_logs(LogLevel::Info)?;
+    }
 
+    let mut units = None;
     let mut i = 0usize;
     loop {
         let snapshot = client.next_snapshot()?;
+        for log in client.take_logs() {
+            print_log(&log);
+        }
+        if units != Some(snapshot.units) {
+            units = Some(snapshot.units);
+            let label = snapshot.unit_label();
+            println!(
+                "step  X_{label:<8}  Z_{label:<8}  RPM    ({:?})",
+                snapshot.units.x_mode
+            );
+        }
         println!(
-            "{:04}  {:+9.3}   {:+9.3}   {:5}",
-            i, snapshot.x_mm, snapshot.z_mm, snapshot.spindle_rpm
+            "{:04}  {:+9.4}   {:+9.4}   {:5}",
+            i,
+            snapshot.x_display(),
+            snapshot.z_display(),
+            snapshot.spindle_rpm
         );
         i = i.wrapping_add(1);
     }
 }
 
-fn set_usb_capture(enable: bool) -> io::Result<()> {
+fn log_usb(max_level: LogLevel) -> io::Result<()> {
     let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
-    let req = Packet::capture_set(1, enable);
-    let replies = t.transact(req)?;
+    t.transact(Packet::log_set(1, true, Some(max_level)))?;
+    eprintln!("device log up to {}; Ctrl-C to stop", max_level.name());
+
+    let mut seq = SeqTracker::new();
+    loop {
+        let pkt = match t.read_packet_timeout(TRACE_READ_TIMEOUT) {
+            Ok(pkt) => pkt,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => return Err(err),
+        };
+        let Ok(log) = pkt.decode_payload::<LogPayload>() else {
+            continue;
+        };
+        match seq.observe(pkt.seq) {
+            SeqEvent::Duplicate => continue,
+            SeqEvent::Gap { lost, .. } => {
+                println!("{:>14}  {lost} LOG packet(s) lost on USB", "")
+            }
+            SeqEvent::InOrder | SeqEvent::Reset { .. } => {}
+        }
+        print_log(&log);
+    }
+}
+
+fn print_log(log: &LogPayload) {
+    if log.dropped > 0 {
+        println!(
+            "{:>14}  {} earlier record(s) overwritten on device",
+            "", log.dropped
+        );
+    }
+    let record = &log.record;
     println!(
-        "usb passive capture {} -> {} reply packet(s)",
-        if enable { "ON" } else { "OFF" },
-        replies.len()
+        "[{:>12.6}] {:<5} {record}",
+        record.at_us as f64 / 1e6,
+        record.level.name().to_uppercase()
+    );
+}
+
+fn snapshot_usb() -> io::Result<()> {
+    let mut client = FredMonitorClient::open(0x2E8A, 0x000A)?;
+    let polled = client.snapshot()?;
+    warn_downgrade(client.downgrade());
+    let snapshot = polled.snapshot;
+    let label = snapshot.unit_label();
+    println!(
+        "sample={} valid={} age_ms={} X_{label}={:+.4} Z_{label}={:+.4} X_counts={} Z_counts={} RPMraw={} RPMdisp={}",
+        snapshot.tick,
+        polled.valid,
+        polled.age_ms,
+        snapshot.x_display(),
+        snapshot.z_display(),
+        snapshot.x_counts,
+        snapshot.z_counts,
+        polled.rpm_raw,
+        snapshot.spindle_rpm
     );
     Ok(())
 }
 
-fn capture_usb() -> io::Result<()> {
-    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
-    let _ = t.transact(Packet::capture_set(1, true))?;
-    let _ = t.transact(Packet::telemetry_set(2, false, 25))?;
+fn print_device_info(t: &mut impl HostTransport) -> io::Result<()> {
+    let Some(info) = query_device_info(t, 1)? else {
+        println!("device rejected HELLO; firmware predates DEVICE_INFO");
+        return Ok(());
+    };
 
-    print_raw_header();
-    let mut i = 0u64;
-    let mut counters = TraceCaptureCounters::default();
+    let [major, minor, patch] = info.firmware_version;
+    println!(
+        "firmware:       {major}.{minor}.{patch} ({})",
+        info.git_hash_str()
+    );
+    println!("transport:      {}", info.transport.name());
+    println!("protocol:       v{}", info.protocol_version);
+    println!("max payload:    {} bytes", info.max_payload);
+    println!("trace layout:   v{}", info.trace_layout_version);
+    println!("pin map:        {}", info.pin_map_id);
+    let supported: Vec<String> = info
+        .supported
+        .iter()
+        .map(|t| format!("{t:?}(0x{:02X})", t as u8))
+        .collect();
+    println!("supported:      {}", supported.join(" "));
+    Ok(())
+}
+
+fn health_usb(period_ms: u16) -> io::Result<()> {
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    let _ = t.transact(Packet::health_set(1, true, period_ms))?;
 
+    println!(
+        "seq    drops      rxstall    ring        high/cap       txns        dec_err  idle_ms"
+    );
+    let mut decoder_stats = DecoderStats::ZERO;
     loop {
         let pkt = t.read_packet()?;
-        let Some(trace) = pkt.decode_trace_samples() else {
+        let Ok(health) = pkt.decode_payload::<HealthPayload>() else {
             continue;
         };
-
-        if let Some(comment) =
-            counters.update(trace.dropped_samples_total, trace.rx_stall_count_total)
-        {
-            println!("{comment}");
+        if health.decoder_stats != decoder_stats {
+            decoder_stats = health.decoder_stats;
+            println!("# {}", format_decoder_stats(&decoder_stats));
         }
+        let idle = if health.idle_ms == HEALTH_IDLE_NEVER {
+            "never".to_string()
+        } else {
+            health.idle_ms.to_string()
+        };
+        println!(
+            "{:05}  {:9}  {:9}  {:5} ({:3}%)  {:5}/{:<5}  {:10}  {:7}  {}",
+            pkt.seq,
+            health.queue_drop_count,
+            health.rx_stall_count,
+            health.ring_fill,
+            (health.ring_fill as u32 * 100) / (health.ring_capacity.max(1) as u32),
+            health.ring_high_water,
+            health.ring_capacity,
+            health.decoded_transactions,
+            health.decoder_errors,
+            idle
+        );
+    }
+}
 
-        for sample in trace.iter_samples() {
-            print_raw_sample(i, sample);
-            i = i.wrapping_add(1);
-        }
+/// `timed` asks the device for a timestamp on every sample rather than just
+/// the first of each batch.
+fn parse_timed(arg: Option<&str>) -> io::Result<bool> {
+    match arg {
+        None => Ok(false),
+        Some("timed") => Ok(true),
+        Some(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown capture option: {other} (expected `timed`)"),
+        )),
     }
 }
 
-fn decode_usb_capture() -> io::Result<()> {
+fn set_usb_capture(enable: bool) -> io::Result<()> {
     let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
-    let _ = t.transact(Packet::telemetry_set(1, false, 100))?;
-    let _ = t.transact(Packet::capture_set(2, true))?;
+    t.transact(Packet::capture_set(1, enable))?;
+    println!(
+        "usb passive capture {} -> ACK",
+        if enable { "ON" } else { "OFF" }
+    );
+    Ok(())
+}
 
-    let mut decoder = FeedbackDecoder::new();
-    let mut sample_index = 0u64;
-    let mut counters = TraceCaptureCounters::default();
+fn start_usb_capture(first_seq: u16, sample_times: bool) -> io::Result<UsbTransport> {
+    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
+    let info = query_device_info(&mut t, first_seq)?;
+    check_trace_layout(info.as_ref(), t.downgrade())?;
+    let _ = t.transact(Packet::telemetry_set(first_seq + 1, false, 100))?;
+    let capture = CaptureSetPayload {
+        enable: true,
+        sample_times,
+    };
+    let _ = t.transact(Packet::from_payload(first_seq + 2, &capture))?;
+    warn_downgrade(t.downgrade());
+    Ok(t)
+}
 
-    print_decode_header();
-    loop {
-        let pkt = t.read_packet()?;
-        let Some(trace) = pkt.decode_trace_samples() else {
-            continue;
-        };
+fn warn_downgrade(downgrade: Option<Downgrade>) {
+    if let Some(downgrade) = downgrade {
+        eprintln!("warning: {downgrade}");
+    }
+}
 
-        if let Some(comment) =
-            counters.update(trace.dropped_samples_total, trace.rx_stall_count_total)
-        {
-            println!("{comment}");
+fn read_usb_batch(t: &mut UsbTransport, seqs: &mut SeqTracker) -> io::Result<CaptureBatch> {
+    loop {
+        let pkt = t.read_packet_ref(TRACE_READ_TIMEOUT)?;
+        if let Some(trace) = pkt.decode_trace_samples() {
+            let mut batch = CaptureBatch::from_trace(trace);
+            batch.seq = Some(pkt.seq);
+            batch.seq_event = seqs.observe(pkt.seq);
+            return Ok(batch);
         }
+    }
+}
 
-        for sample in trace.iter_samples() {
-            if let Some(snapshot) = decoder.ingest_sample(sample_index, sample) {
-                print_decoded_snapshot(snapshot);
-            }
-            sample_index = sample_index.wrapping_add(1);
-        }
+fn capture_usb(sample_times: bool) -> io::Result<()> {
+    let mut t = start_usb_capture(1, sample_times)?;
+    let mut seqs = SeqTracker::new();
+    let mut printer = RawPrinter::default();
+
+    print_raw_header();
+    loop {
+        printer.print_batch(&read_usb_batch(&mut t, &mut seqs)?);
     }
 }
 
-fn capture_usb_to_file(path: &str) -> io::Result<()> {
-    let mut t = UsbTransport::open(0x2E8A, 0x000A)?;
-    let _ = t.transact(Packet::telemetry_set(1, false, 100))?;
-    let _ = t.transact(Packet::capture_set(2, true))?;
+fn decode_usb_capture(sample_times: bool) -> io::Result<()> {
+    let mut t = start_usb_capture(1, sample_times)?;
+    let mut seqs = SeqTracker::new();
+    let mut printer = DecodePrinter::default();
+
+    print_decode_header();
+    loop {
+        printer.print_batch(&read_usb_batch(&mut t, &mut seqs)?);
+    }
+}
+
+fn capture_usb_to_file(path: &str, sample_times: bool) -> io::Result<()> {
+    let mut t = start_usb_capture(1, sample_times)?;
 
     let file = File::create(path)?;
     let mut writer = CaptureWriter::new(file)?;
+    let mut seqs = SeqTracker::new();
 
     loop {
-        let pkt = t.read_packet()?;
-        let Some(trace) = pkt.decode_trace_samples() else {
-            continue;
-        };
-        writer.write_trace(trace)?;
+        let batch = read_usb_batch(&mut t, &mut seqs)?;
+        if let SeqEvent::Gap { lost, .. } = batch.seq_event {
+            eprintln!("usb sequence gap: {lost} trace packet(s) lost");
+        }
+        // The marker is kept so the gap shows up when the file is decoded.
+        writer.write_batch(&batch)?;
     }
 }
 
 fn raw_capture_file(path: &str) -> io::Result<()> {
     let file = File::open(path)?;
     let mut reader = CaptureReader::new(BufReader::new(file))?;
-    let mut counters = TraceCaptureCounters::default();
-    let mut sample_index = 0u64;
+    let mut printer = RawPrinter::default();
 
     print_raw_header();
     while let Some(batch) = reader.read_batch()? {
-        if let Some(comment) =
-            counters.update(batch.dropped_samples_total, batch.rx_stall_count_total)
-        {
-            println!("{comment}");
-        }
-
-        for sample in batch.samples {
-            print_raw_sample(sample_index, sample);
-            sample_index = sample_index.wrapping_add(1);
-        }
+        printer.print_batch(&batch);
     }
 
     Ok(())
 }
 
-fn decode_capture_file(path: &str) -> io::Result<()> {
+fn decode_capture_file(path: &str, mut printer: DecodePrinter) -> io::Result<()> {
     let file = File::open(path)?;
     let mut reader = CaptureReader::new(BufReader::new(file))?;
-    let mut decoder = FeedbackDecoder::new();
-    let mut counters = TraceCaptureCounters::default();
-    let mut sample_index = 0u64;
 
     print_decode_header();
     while let Some(batch) = reader.read_batch()? {
-        if let Some(comment) =
-            counters.update(batch.dropped_samples_total, batch.rx_stall_count_total)
-        {
-            println!("{comment}");
+        printer.print_batch(&batch);
+    }
+    printer.print_summary();
+
+    Ok(())
+}
+
+fn transactions_capture_file(path: &str) -> io::Result<()> {
+    let file = File::open(path)?;
+    let mut reader = CaptureReader::new(BufReader::new(file))?;
+    let mut printer = TransactionPrinter::default();
+
+    print_transaction_header();
+    while let Some(batch) = reader.read_batch()? {
+        printer.print_batch(&batch);
+    }
+    printer.print_summary();
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct RawPrinter {
+    counters: TraceCaptureCounters,
+    sample_index: u64,
+}
+
+impl RawPrinter {
+    fn print_batch(&mut self, batch: &CaptureBatch) {
+        if !self.counters.report(batch) {
+            return;
         }
 
-        for sample in batch.samples {
-            if let Some(snapshot) = decoder.ingest_sample(sample_index, sample) {
-                print_decoded_snapshot(snapshot);
+        for (i, &sample) in batch.samples.iter().enumerate() {
+            print_raw_sample(self.sample_index, batch.sample_time_us(i), sample);
+            self.sample_index = self.sample_index.wrapping_add(1);
+        }
+    }
+}
+
+#[derive(Default)]
+struct DecodePrinter {
+    counters: TraceCaptureCounters,
+    decoder: FeedbackDecoder,
+    filter: FeedbackFilter,
+    sample_index: u64,
+    /// Device time of the last snapshot, advanced by `ASSUMED_CADENCE_US`
+    /// for snapshots whose sample has no timestamp.
+    clock_us: u64,
+    reported_stats: DecoderStats,
+    reported_event_gate: Option<bool>,
+}
+
+impl DecodePrinter {
+    fn print_batch(&mut self, batch: &CaptureBatch) {
+        if !self.counters.report(batch) {
+            return;
+        }
+
+        for (i, &sample) in batch.samples.iter().enumerate() {
+            if let Some(mut snapshot) = self.decoder.ingest_sample(self.sample_index, sample) {
+                let at_us = batch.sample_time_us(i);
+                self.clock_us = at_us.unwrap_or(self.clock_us + ASSUMED_CADENCE_US);
+                let checks = self.filter.apply(&mut snapshot, self.clock_us);
+                print_decoded_snapshot(at_us, snapshot, checks);
+            }
+            self.sample_index = self.sample_index.wrapping_add(1);
+        }
+
+        // After the batch rather than per sample, so a burst of corrupt
+        // frames is one line.
+        let stats = self.decoder.stats();
+        if stats != self.reported_stats {
+            self.reported_stats = stats;
+            println!("# {}", format_decoder_stats(&stats));
+        }
+        if let Some(status) = self.decoder.status() {
+            if self.reported_event_gate != Some(status.event_gate()) {
+                self.reported_event_gate = Some(status.event_gate());
+                println!(
+                    "# status fcf0=0x{:02X} event_gate={}",
+                    status.0,
+                    status.event_gate() as u8
+                );
             }
-            sample_index = sample_index.wrapping_add(1);
         }
     }
 
-    Ok(())
+    fn print_summary(&self) {
+        let cadence = self.decoder.cadence();
+        println!(
+            "# decoder transactions={} errors={} skipped_commands={} repeated_commands={} torn_cycles={} corrected_axes={}",
+            self.decoder.transactions(),
+            self.decoder.stats().total(),
+            cadence.skipped(),
+            cadence.repeated(),
+            cadence.torn_cycles(),
+            self.filter.corrections()
+        );
+        println!("# latency cmd  txns      busy_polls min/mean/max");
+        for (cmd, latency) in self.decoder.latency().iter() {
+            let Some(mean) = latency.mean_busy_polls() else {
+                continue;
+            };
+            println!(
+                "# latency {cmd:02X}   {:8}  {}/{mean:.1}/{}",
+                latency.transactions, latency.busy_polls_min, latency.busy_polls_max
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct TransactionPrinter {
+    counters: TraceCaptureCounters,
+    assembler: FredTransactionAssembler,
+    sample_index: u64,
+    complete: u64,
+    abandoned: u64,
+    orphans: u64,
+}
+
+impl TransactionPrinter {
+    fn print_batch(&mut self, batch: &CaptureBatch) {
+        if !self.counters.report(batch) {
+            return;
+        }
+
+        for &sample in &batch.samples {
+            let event = TraceCycle::from_sample(sample)
+                .and_then(|cycle| self.assembler.push(self.sample_index, cycle));
+            match event {
+                Some(FredEvent::Transaction(transaction)) => {
+                    if transaction.is_complete() {
+                        self.complete += 1;
+                    } else {
+                        self.abandoned += 1;
+                    }
+                    print_transaction(&transaction);
+                }
+                Some(FredEvent::OrphanResponse { response, sample }) => {
+                    self.orphans += 1;
+                    println!("{sample:08}  {sample:08}  --   {response:02X}    orphan response");
+                }
+                None => {}
+            }
+            self.sample_index = self.sample_index.wrapping_add(1);
+        }
+    }
+
+    fn print_summary(&self) {
+        println!(
+            "# transactions complete={} abandoned={} orphan_responses={} status_polls={}",
+            self.complete,
+            self.abandoned,
+            self.orphans,
+            self.assembler.status_polls()
+        );
+    }
+}
+
+fn print_transaction_header() {
+    println!("first     last      cmd  resp  pre  post  busy  fcf0");
+}
+
+/// Abandoned transactions show `--` for the response they never got.
+fn print_transaction(transaction: &FredTransaction) {
+    let response = match transaction.response {
+        Some(response) => format!("{response:02X}"),
+        None => "--".to_string(),
+    };
+    let status_values: Vec<String> = transaction
+        .status_values
+        .as_slice()
+        .iter()
+        .map(|value| format!("{value:02X}"))
+        .collect();
+    println!(
+        "{:08}  {:08}  {:02X}   {response}    {:3}  {:4}  {:4}  {}",
+        transaction.first_sample,
+        transaction.last_sample,
+        transaction.cmd,
+        transaction.pre_polls,
+        transaction.post_polls,
+        transaction.busy_polls,
+        status_values.join(",")
+    );
+}
+
+fn format_decoder_stats(stats: &DecoderStats) -> String {
+    format!(
+        "decoder invalid_bcd={} orphan_responses={} unknown_commands={} overwritten_pending={} incomplete_cycles={}",
+        stats.invalid_bcd,
+        stats.orphan_responses,
+        stats.unknown_commands,
+        stats.overwritten_pending,
+        stats.incomplete_cycles
+    )
+}
+
+fn format_time_us(at_us: Option<u64>) -> String {
+    match at_us {
+        Some(at_us) => format!("{at_us:>12}"),
+        None => format!("{:>12}", "-"),
+    }
 }
 
 fn print_raw_header() {
-    println!("step  sample      D    A   RnW CLK FREDn");
+    println!("step          t_us  sample      D    A   RnW CLK FREDn");
 }
 
-fn print_raw_sample(step: u64, sample: u32) {
+fn print_raw_sample(step: u64, at_us: Option<u64>, sample: u32) {
     let d = (sample & 0xFF) as u8;
     let a = ((sample >> 8) & 0xFF) as u8;
     let rnw = if ((sample >> 16) & 1) as u8 == 0 {
@@ -252,23 +832,46 @@ fn print_raw_sample(step: u64, sample: u32) {
     let fred_n = ((sample >> 20) & 1) as u8;
 
     println!(
-        "{:04}  0x{sample:08X}  {d:02X}  {a:02X}   {rnw}   {clk}    {fred_n}",
-        step
+        "{:04}  {}  0x{sample:08X}  {d:02X}  {a:02X}   {rnw}   {clk}    {fred_n}",
+        step,
+        format_time_us(at_us)
     );
 }
 
 fn print_decode_header() {
-    println!("sample    X_raw    Z_raw    RPMraw RPMdisp");
+    println!("sample            t_us    X_raw    Z_raw    RPMraw RPMdisp");
 }
 
-fn print_decoded_snapshot(snapshot: FeedbackSnapshot) {
+/// Torn snapshots are printed with a trailing `torn`, and axis values the
+/// filter replaced with what was read, so a capture shows where they fell.
+fn print_decoded_snapshot(
+    at_us: Option<u64>,
+    snapshot: FeedbackSnapshot,
+    (x_check, z_check): (AxisCheck, AxisCheck),
+) {
+    let mut notes = String::new();
+    if !snapshot.clean {
+        notes.push_str("  torn");
+    }
+    for (axis, check) in [("x", x_check), ("z", z_check)] {
+        match check {
+            AxisCheck::Corrected { raw } => {
+                notes.push_str(&format!("  {axis}_corrected_from={}", format_count(raw)))
+            }
+            AxisCheck::Rejected { raw } => {
+                notes.push_str(&format!("  {axis}_held_over={}", format_count(raw)))
+            }
+            AxisCheck::Accepted | AxisCheck::Resynced => {}
+        }
+    }
     println!(
-        "{:08}  {}  {}  {:6} {:7}",
+        "{:08}  {}  {}  {}  {:6} {:7}{notes}",
         snapshot.sample_index,
+        format_time_us(at_us),
         format_axis(snapshot.x),
         format_axis(snapshot.z),
         snapshot.rpm_raw,
-        snapshot.rpm_display
+        snapshot.rpm_display,
     );
 }
 
@@ -276,13 +879,52 @@ fn format_axis(axis: AxisSnapshot) -> String {
     format!("{}{:06}", if axis.negative { "-" } else { "+" }, axis.value)
 }
 
+fn format_count(count: i32) -> String {
+    format_axis(AxisSnapshot::from_count(count))
+}
+
 #[derive(Default)]
 struct TraceCaptureCounters {
     dropped_samples_total: u32,
     rx_stall_count_total: u32,
+    usb_lost_packets_total: u64,
 }
 
 impl TraceCaptureCounters {
+    /// Prints device drop counters and USB sequence events for `batch`.
+    /// Returns `false` for a duplicate packet whose samples must be skipped.
+    fn report(&mut self, batch: &CaptureBatch) -> bool {
+        if let Some(comment) = self.update_sequence(batch.seq, batch.seq_event) {
+            println!("{comment}");
+        }
+        if batch.seq_event == SeqEvent::Duplicate {
+            return false;
+        }
+        if let Some(comment) = self.update(batch.dropped_samples_total, batch.rx_stall_count_total)
+        {
+            println!("{comment}");
+        }
+        true
+    }
+
+    fn update_sequence(&mut self, seq: Option<u16>, event: SeqEvent) -> Option<String> {
+        let seq = seq?;
+        match event {
+            SeqEvent::InOrder => None,
+            SeqEvent::Gap { expected, lost } => {
+                self.usb_lost_packets_total += lost as u64;
+                Some(format!(
+                    "# usb seq_gap expected={expected} got={seq} lost_packets={lost} lost_packets_total={}",
+                    self.usb_lost_packets_total
+                ))
+            }
+            SeqEvent::Duplicate => Some(format!("# usb seq_duplicate seq={seq} (skipped)")),
+            SeqEvent::Reset { expected } => {
+                Some(format!("# usb seq_reset expected={expected} got={seq}"))
+            }
+        }
+    }
+
     fn update(&mut self, dropped_samples_total: u32, rx_stall_count_total: u32) -> Option<String> {
         if dropped_samples_total == self.dropped_samples_total
             && rx_stall_count_total == self.rx_stall_count_total
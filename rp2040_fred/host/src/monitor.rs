@@ -1,10 +1,19 @@
 use std::io;
+use std::mem;
 use std::time::Duration;
 
-use rp2040_fred_protocol::bridge_proto::{MsgType, Packet};
-use rp2040_fred_protocol::dro_decode::{counts_to_mm, Calibration, DroSnapshot};
+use rp2040_fred_protocol::bridge_proto::compat::Downgrade;
+use rp2040_fred_protocol::bridge_proto::{
+    DeviceInfoPayload, LinearUnits, LogPayload, MsgType, Packet, SnapshotPayload, TelemetryPayload,
+    UnitConfig, PROTOCOL_VERSION,
+};
+use rp2040_fred_protocol::device_log::LogLevel;
+use rp2040_fred_protocol::dro_decode::{
+    counts_to_units, CalibrationProfile, DroSnapshot, MM_PER_INCH,
+};
 
-use crate::transport::{HostTransport, UsbTransport};
+use crate::sequence::{SeqEvent, SeqStats, SeqStream, StreamSequences};
+use crate::transport::{query_device_info, read_calibration, HostTransport, UsbTransport};
 
 const DEFAULT_VID: u16 = 0x2E8A;
 const DEFAULT_PID: u16 = 0x000A;
@@ -19,6 +28,7 @@ pub struct MonitorSnapshot {
     pub z_counts: i32,
     pub tick: u32,
     pub flags: u8,
+    pub units: UnitConfig,
 }
 
 impl Default for MonitorSnapshot {
@@ -31,23 +41,28 @@ impl Default for MonitorSnapshot {
             z_counts: 0,
             tick: 0,
             flags: 0,
+            units: UnitConfig::default(),
         }
     }
 }
 
 impl MonitorSnapshot {
-    pub fn from_telemetry_packet(pkt: &Packet, calibration: Calibration) -> Option<Self> {
-        if pkt.msg_type != MsgType::Telemetry || pkt.payload_len < 16 {
-            return None;
-        }
-
-        let payload = pkt.payload_used();
+    /// Units and X mode come from the telemetry flags unless `profile` sets
+    /// them.
+    pub fn from_telemetry_packet(pkt: &Packet, profile: CalibrationProfile) -> Option<Self> {
+        let telemetry = pkt.decode_payload::<TelemetryPayload>().ok()?;
         let snapshot = DroSnapshot {
-            x_counts: i32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]),
-            z_counts: i32::from_le_bytes([payload[8], payload[9], payload[10], payload[11]]),
-            rpm: u16::from_le_bytes([payload[12], payload[13]]),
+            x_counts: telemetry.x_counts,
+            z_counts: telemetry.z_counts,
+            rpm: telemetry.rpm,
+        };
+        let flags = telemetry.flags;
+        let units = profile.unit_config(UnitConfig::from_telemetry_flags(flags));
+        let metric = UnitConfig {
+            units: LinearUnits::Metric,
+            ..units
         };
-        let (x_mm, z_mm, spindle_rpm) = counts_to_mm(snapshot, calibration);
+        let (x_mm, z_mm, spindle_rpm) = counts_to_units(snapshot, profile.calibration, metric);
 
         Some(Self {
             x_mm,
@@ -55,16 +70,89 @@ impl MonitorSnapshot {
             spindle_rpm,
             x_counts: snapshot.x_counts,
             z_counts: snapshot.z_counts,
-            tick: u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]),
-            flags: payload[14],
+            tick: telemetry.tick,
+            flags,
+            units,
+        })
+    }
+
+    /// X in the active units: mm or inches.
+    pub fn x_display(&self) -> f32 {
+        self.in_display_units(self.x_mm)
+    }
+
+    /// Z in the active units: mm or inches.
+    pub fn z_display(&self) -> f32 {
+        self.in_display_units(self.z_mm)
+    }
+
+    pub fn unit_label(&self) -> &'static str {
+        match self.units.units {
+            LinearUnits::Metric => "mm",
+            LinearUnits::Imperial => "in",
+        }
+    }
+
+    fn in_display_units(&self, mm: f32) -> f32 {
+        match self.units.units {
+            LinearUnits::Metric => mm,
+            LinearUnits::Imperial => mm / MM_PER_INCH,
+        }
+    }
+}
+
+/// One-shot reading returned for `SNAPSHOT_REQ`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PolledSnapshot {
+    /// Converted values; `tick` holds the low 32 bits of the sample index
+    /// and `spindle_rpm` the display-rounded RPM.
+    pub snapshot: MonitorSnapshot,
+    pub rpm_raw: u16,
+    pub age_ms: u32,
+    pub valid: bool,
+}
+
+impl PolledSnapshot {
+    pub fn from_snapshot_packet(pkt: &Packet, profile: CalibrationProfile) -> Option<Self> {
+        let payload = pkt.decode_payload::<SnapshotPayload>().ok()?;
+        let flags = payload.flags;
+        let units = profile.unit_config(UnitConfig::from_telemetry_flags(flags));
+        let metric = UnitConfig {
+            units: LinearUnits::Metric,
+            ..units
+        };
+        let dro = DroSnapshot {
+            x_counts: payload.x_counts,
+            z_counts: payload.z_counts,
+            rpm: payload.rpm_display,
+        };
+        let (x_mm, z_mm, spindle_rpm) = counts_to_units(dro, profile.calibration, metric);
+
+        Some(Self {
+            snapshot: MonitorSnapshot {
+                x_mm,
+                z_mm,
+                spindle_rpm,
+                x_counts: dro.x_counts,
+                z_counts: dro.z_counts,
+                tick: payload.sample_index,
+                flags,
+                units,
+            },
+            rpm_raw: payload.rpm_raw,
+            age_ms: payload.age_ms,
+            valid: payload.valid,
         })
     }
 }
 
 pub struct FredMonitorClient {
     transport: UsbTransport,
-    calibration: Calibration,
+    profile: CalibrationProfile,
     latest: MonitorSnapshot,
+    device_info: Option<DeviceInfoPayload>,
+    sequences: StreamSequences,
+    logs: Vec<LogPayload>,
 }
 
 impl FredMonitorClient {
@@ -73,32 +161,115 @@ impl FredMonitorClient {
     }
 
     pub fn open(vid: u16, pid: u16) -> io::Result<Self> {
-        Self::open_with_options(vid, pid, Duration::from_millis(250), Calibration::default())
+        Self::open_with_options(vid, pid, Duration::from_millis(250), None)
     }
 
+    /// With `profile` of `None`, positions use the calibration stored on the
+    /// device (`fredctl config set x_counts_per_mm ...`), or the defaults if
+    /// the firmware has no `CONFIG_GET`.
     pub fn open_with_options(
         vid: u16,
         pid: u16,
         timeout: Duration,
-        calibration: Calibration,
+        profile: Option<CalibrationProfile>,
     ) -> io::Result<Self> {
         let mut transport = UsbTransport::open(vid, pid)?;
         transport.set_timeout(timeout);
+        let device_info = query_device_info(&mut transport, 1)?;
+        if let Some(info) = &device_info {
+            check_monitor_capabilities(info)?;
+        }
+        let profile = match profile {
+            Some(profile) => profile,
+            None if device_info.is_some_and(|info| info.supports(MsgType::ConfigGet)) => {
+                CalibrationProfile {
+                    calibration: read_calibration(&mut transport, 5)?,
+                    ..CalibrationProfile::default()
+                }
+            }
+            None => CalibrationProfile::default(),
+        };
         Ok(Self {
             transport,
-            calibration,
+            profile,
             latest: MonitorSnapshot::default(),
+            device_info,
+            sequences: StreamSequences::new(),
+            logs: Vec::new(),
         })
     }
 
+    pub fn profile(&self) -> CalibrationProfile {
+        self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: CalibrationProfile) {
+        self.profile = profile;
+    }
+
+    /// `None` when the firmware predates `HELLO`; every request is then
+    /// attempted and the device's NACK is reported instead.
+    pub fn device_info(&self) -> Option<DeviceInfoPayload> {
+        self.device_info
+    }
+
+    /// Set once the device has answered in protocol v1 or v2.
+    pub fn downgrade(&self) -> Option<Downgrade> {
+        self.transport.downgrade()
+    }
+
     pub fn enable_polling(&mut self, period_ms: u16) -> io::Result<()> {
         let _ = self.transport.transact(Packet::capture_set(1, false))?;
         let _ = self
             .transport
             .transact(Packet::telemetry_set(2, true, period_ms))?;
+        self.sequences.restart(SeqStream::Telemetry);
         Ok(())
     }
 
+    /// Asks the device to interleave its log records, up to `max_level`,
+    /// with telemetry. Collect them with [`take_logs`](Self::take_logs).
+    pub fn enable_logs(&mut self, max_level: LogLevel) -> io::Result<()> {
+        self.require(MsgType::LogSet)?;
+        let _ = self
+            .transport
+            .transact(Packet::log_set(4, true, Some(max_level)))?;
+        self.sequences.restart(SeqStream::Log);
+        Ok(())
+    }
+
+    /// Device log records received since the last call, oldest first.
+    pub fn take_logs(&mut self) -> Vec<LogPayload> {
+        mem::take(&mut self.logs)
+    }
+
+    pub fn set_units(&mut self, units: UnitConfig) -> io::Result<()> {
+        self.require(MsgType::UnitCfg)?;
+        let _ = self.transport.transact(Packet::unit_cfg(1, units))?;
+        Ok(())
+    }
+
+    /// Requests the device's latest decoded position without touching the
+    /// telemetry stream.
+    pub fn snapshot(&mut self) -> io::Result<PolledSnapshot> {
+        self.require(MsgType::SnapshotReq)?;
+        let req = Packet::snapshot_req(3);
+        let replies = self.transport.transact(req)?;
+        for pkt in &replies {
+            if pkt.seq != req.seq {
+                self.consume_packet(pkt);
+                continue;
+            }
+            if let Some(polled) = PolledSnapshot::from_snapshot_packet(pkt, self.profile) {
+                return Ok(polled);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no SNAPSHOT reply received",
+        ))
+    }
+
     pub fn disable_polling(&mut self) -> io::Result<()> {
         let _ = self
             .transport
@@ -131,10 +302,30 @@ impl FredMonitorClient {
         self.latest
     }
 
+    /// Packets received, lost, repeated and restarted on a device stream
+    /// since the client was opened.
+    pub fn stream_stats(&self, stream: SeqStream) -> SeqStats {
+        self.sequences.stats(stream)
+    }
+
     pub fn close(self) {}
 
+    fn require(&self, msg_type: MsgType) -> io::Result<()> {
+        match &self.device_info {
+            Some(info) if !info.supports(msg_type) => Err(unsupported(info, msg_type)),
+            _ => Ok(()),
+        }
+    }
+
     fn consume_packet(&mut self, pkt: &Packet) -> bool {
-        let Some(snapshot) = MonitorSnapshot::from_telemetry_packet(pkt, self.calibration) else {
+        if self.sequences.observe(pkt.msg_type, pkt.seq) == Some(SeqEvent::Duplicate) {
+            return false;
+        }
+        if let Ok(log) = pkt.decode_payload::<LogPayload>() {
+            self.logs.push(log);
+            return false;
+        }
+        let Some(snapshot) = MonitorSnapshot::from_telemetry_packet(pkt, self.profile) else {
             return false;
         };
         self.latest = snapshot;
@@ -142,17 +333,55 @@ impl FredMonitorClient {
     }
 }
 
+/// Rejects devices the monitor cannot drive at all. Optional requests are
+/// checked when they are made.
+fn check_monitor_capabilities(info: &DeviceInfoPayload) -> io::Result<()> {
+    if info.protocol_version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "device speaks bridge protocol v{}, host expects v{PROTOCOL_VERSION}",
+                info.protocol_version
+            ),
+        ));
+    }
+    for msg_type in [MsgType::TelemetrySet, MsgType::Telemetry] {
+        if !info.supports(msg_type) {
+            return Err(unsupported(info, msg_type));
+        }
+    }
+    Ok(())
+}
+
+fn unsupported(info: &DeviceInfoPayload, msg_type: MsgType) -> io::Error {
+    let [major, minor, patch] = info.firmware_version;
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "firmware {major}.{minor}.{patch} ({}, {}) does not support {msg_type:?}",
+            info.git_hash_str(),
+            info.transport.name()
+        ),
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use super::MonitorSnapshot;
-    use rp2040_fred_protocol::bridge_proto::{MsgType, Packet};
-    use rp2040_fred_protocol::dro_decode::Calibration;
+    use super::{check_monitor_capabilities, MonitorSnapshot, PolledSnapshot};
+    use rp2040_fred_protocol::bridge_proto::{
+        DeviceInfoPayload, DeviceTransport, LinearUnits, MsgType, MsgTypeSet, Packet,
+        SnapshotPayload, XAxisMode, PAYLOAD_SIZE, PIN_MAP_PASSIVE_SNIFFER, PROTOCOL_VERSION,
+        TELEMETRY_FLAG_ENABLED, TELEMETRY_FLAG_IMPERIAL, TELEMETRY_FLAG_RADIUS,
+        TRACE_LAYOUT_VERSION,
+    };
+    use rp2040_fred_protocol::dro_decode::{AxisCalibration, Calibration, CalibrationProfile};
 
     #[test]
     fn telemetry_packet_decodes_to_monitor_snapshot() {
         let packet = Packet::telemetry(9, 123, -100, 250, 780, 0x5A);
         let snapshot =
-            MonitorSnapshot::from_telemetry_packet(&packet, Calibration::default()).expect("valid");
+            MonitorSnapshot::from_telemetry_packet(&packet, CalibrationProfile::default())
+                .expect("valid");
 
         assert_eq!(snapshot.tick, 123);
         assert_eq!(snapshot.x_counts, -100);
@@ -163,9 +392,112 @@ mod tests {
         assert!((snapshot.z_mm - 2.5).abs() < 0.0001);
     }
 
+    #[test]
+    fn telemetry_flags_select_display_units() {
+        let flags = TELEMETRY_FLAG_ENABLED | TELEMETRY_FLAG_IMPERIAL | TELEMETRY_FLAG_RADIUS;
+        let packet = Packet::telemetry(9, 0, 2540, -5080, 0, flags);
+        let snapshot =
+            MonitorSnapshot::from_telemetry_packet(&packet, CalibrationProfile::default())
+                .expect("valid");
+
+        assert_eq!(snapshot.units.units, LinearUnits::Imperial);
+        assert_eq!(snapshot.units.x_mode, XAxisMode::Radius);
+        assert_eq!(snapshot.unit_label(), "in");
+        assert!((snapshot.x_mm - 25.4).abs() < 0.0001);
+        assert!((snapshot.x_display() - 1.0).abs() < 0.0001);
+        assert!((snapshot.z_display() + 2.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn profile_calibrates_and_overrides_units() {
+        let packet = Packet::telemetry(9, 0, 1000, 1000, 0, TELEMETRY_FLAG_ENABLED);
+        let profile = CalibrationProfile {
+            calibration: Calibration {
+                x: AxisCalibration {
+                    inverted: true,
+                    ..AxisCalibration::DEFAULT
+                },
+                z: AxisCalibration {
+                    counts_per_mm: 200.0,
+                    zero_offset: 270,
+                    ..AxisCalibration::DEFAULT
+                },
+            },
+            units: Some(LinearUnits::Imperial),
+            x_mode: Some(XAxisMode::Radius),
+        };
+        let snapshot = MonitorSnapshot::from_telemetry_packet(&packet, profile).expect("valid");
+
+        assert_eq!(snapshot.units.units, LinearUnits::Imperial);
+        assert_eq!(snapshot.units.x_mode, XAxisMode::Radius);
+        assert_eq!(snapshot.x_counts, 1000);
+        assert!((snapshot.x_mm + 10.0).abs() < 0.0001);
+        assert!((snapshot.z_mm - 6.35).abs() < 0.0001);
+        assert!((snapshot.z_display() - 0.25).abs() < 0.0001);
+    }
+
+    #[test]
+    fn snapshot_packet_decodes_to_polled_snapshot() {
+        let packet = Packet::snapshot(
+            3,
+            &SnapshotPayload {
+                age_ms: 40,
+                sample_index: 1234,
+                x_counts: -652,
+                z_counts: 1234,
+                rpm_raw: 783,
+                rpm_display: 780,
+                valid: true,
+                flags: 0,
+            },
+        );
+        let polled = PolledSnapshot::from_snapshot_packet(&packet, CalibrationProfile::default())
+            .expect("valid");
+
+        assert!(polled.valid);
+        assert_eq!(polled.age_ms, 40);
+        assert_eq!(polled.rpm_raw, 783);
+        assert_eq!(polled.snapshot.spindle_rpm, 780);
+        assert_eq!(polled.snapshot.tick, 1234);
+        assert_eq!(polled.snapshot.x_counts, -652);
+        assert!((polled.snapshot.x_mm + 13.04).abs() < 0.0001);
+        assert!((polled.snapshot.z_mm - 12.34).abs() < 0.0001);
+        assert!(
+            MonitorSnapshot::from_telemetry_packet(&packet, CalibrationProfile::default())
+                .is_none()
+        );
+    }
+
     #[test]
     fn non_telemetry_packets_are_ignored() {
         let packet = Packet::ack(7, MsgType::TelemetrySet, 0);
-        assert!(MonitorSnapshot::from_telemetry_packet(&packet, Calibration::default()).is_none());
+        assert!(
+            MonitorSnapshot::from_telemetry_packet(&packet, CalibrationProfile::default())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn capability_check_names_missing_message() {
+        let mut info = DeviceInfoPayload {
+            firmware_version: [0, 1, 0],
+            git_hash: *b"1a2b3c4d",
+            transport: DeviceTransport::PioReal,
+            protocol_version: PROTOCOL_VERSION,
+            max_payload: PAYLOAD_SIZE as u16,
+            trace_layout_version: TRACE_LAYOUT_VERSION,
+            pin_map_id: PIN_MAP_PASSIVE_SNIFFER,
+            supported: MsgTypeSet::from_types(&[MsgType::TelemetrySet, MsgType::Telemetry]),
+        };
+        assert!(check_monitor_capabilities(&info).is_ok());
+
+        info.supported = MsgTypeSet::from_types(&[MsgType::TelemetrySet]);
+        let err = check_monitor_capabilities(&info).expect_err("missing telemetry");
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("Telemetry"));
+        assert!(err.to_string().contains("pio-real"));
+
+        info.protocol_version = PROTOCOL_VERSION + 1;
+        assert!(check_monitor_capabilities(&info).is_err());
     }
 }
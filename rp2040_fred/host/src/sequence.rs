@@ -0,0 +1,235 @@
+//! Per-stream sequence tracking for unsolicited device packets.
+//!
+//! The device numbers each stream (`TELEMETRY`, `HEALTH`, `LOG`, the trace
+//! messages) independently and restarts it at 1 whenever the stream is
+//! reconfigured. Replies to requests echo the request's `seq` and are not
+//! tracked here.
+
+use rp2040_fred_protocol::bridge_proto::MsgType;
+
+/// How far behind the expected sequence a packet may be and still be
+/// treated as a repeat rather than a device-side restart.
+const DUPLICATE_WINDOW: u16 = 32;
+const FIRST_SEQ: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqStream {
+    Telemetry,
+    Health,
+    Log,
+    Trace,
+}
+
+impl SeqStream {
+    pub fn of(msg_type: MsgType) -> Option<Self> {
+        match msg_type {
+            MsgType::Telemetry => Some(Self::Telemetry),
+            MsgType::Health => Some(Self::Health),
+            MsgType::Log => Some(Self::Log),
+            MsgType::TraceSample | MsgType::TraceSampleCompressed | MsgType::TraceSampleTimed => {
+                Some(Self::Trace)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SeqEvent {
+    /// First packet of the stream, or the one expected.
+    #[default]
+    InOrder,
+    /// `lost` packets between `expected` and this one never arrived.
+    Gap { expected: u16, lost: u16 },
+    /// A packet already seen; callers should discard it.
+    Duplicate,
+    /// The device restarted the stream.
+    Reset { expected: u16 },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeqStats {
+    pub packets: u64,
+    pub lost_packets: u64,
+    pub gaps: u64,
+    pub duplicates: u64,
+    pub resets: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SeqTracker {
+    expected: Option<u16>,
+    stats: SeqStats,
+}
+
+impl SeqTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, seq: u16) -> SeqEvent {
+        self.stats.packets += 1;
+        let Some(expected) = self.expected else {
+            self.expected = Some(seq.wrapping_add(1));
+            return SeqEvent::InOrder;
+        };
+
+        let ahead = seq.wrapping_sub(expected);
+        let behind = expected.wrapping_sub(seq);
+        // A jump back to the first sequence number is a restart even when it
+        // lands inside the duplicate window.
+        let restarted = seq == FIRST_SEQ && expected > FIRST_SEQ + 1;
+        let event = if ahead == 0 {
+            SeqEvent::InOrder
+        } else if !restarted && (1..=DUPLICATE_WINDOW).contains(&behind) {
+            self.stats.duplicates += 1;
+            return SeqEvent::Duplicate;
+        } else if restarted || ahead >= 0x8000 {
+            self.stats.resets += 1;
+            SeqEvent::Reset { expected }
+        } else {
+            self.stats.gaps += 1;
+            self.stats.lost_packets += ahead as u64;
+            SeqEvent::Gap {
+                expected,
+                lost: ahead,
+            }
+        };
+        self.expected = Some(seq.wrapping_add(1));
+        event
+    }
+
+    /// Forget the expected sequence, e.g. after the host itself restarted
+    /// the stream. Counters are kept.
+    pub fn restart(&mut self) {
+        self.expected = None;
+    }
+
+    pub fn stats(&self) -> SeqStats {
+        self.stats
+    }
+}
+
+/// One tracker per device stream.
+#[derive(Clone, Debug, Default)]
+pub struct StreamSequences {
+    telemetry: SeqTracker,
+    health: SeqTracker,
+    log: SeqTracker,
+    trace: SeqTracker,
+}
+
+impl StreamSequences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` for packets that are not part of a device stream.
+    pub fn observe(&mut self, msg_type: MsgType, seq: u16) -> Option<SeqEvent> {
+        let stream = SeqStream::of(msg_type)?;
+        Some(self.tracker_mut(stream).observe(seq))
+    }
+
+    pub fn restart(&mut self, stream: SeqStream) {
+        self.tracker_mut(stream).restart();
+    }
+
+    pub fn stats(&self, stream: SeqStream) -> SeqStats {
+        match stream {
+            SeqStream::Telemetry => self.telemetry.stats(),
+            SeqStream::Health => self.health.stats(),
+            SeqStream::Log => self.log.stats(),
+            SeqStream::Trace => self.trace.stats(),
+        }
+    }
+
+    fn tracker_mut(&mut self, stream: SeqStream) -> &mut SeqTracker {
+        match stream {
+            SeqStream::Telemetry => &mut self.telemetry,
+            SeqStream::Health => &mut self.health,
+            SeqStream::Log => &mut self.log,
+            SeqStream::Trace => &mut self.trace,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rp2040_fred_protocol::bridge_proto::MsgType;
+
+    use super::{SeqEvent, SeqStats, SeqStream, SeqTracker, StreamSequences};
+
+    #[test]
+    fn classifies_gaps_duplicates_and_resets() {
+        let mut tracker = SeqTracker::new();
+        assert_eq!(tracker.observe(10), SeqEvent::InOrder);
+        assert_eq!(tracker.observe(11), SeqEvent::InOrder);
+        assert_eq!(
+            tracker.observe(15),
+            SeqEvent::Gap {
+                expected: 12,
+                lost: 3
+            }
+        );
+        assert_eq!(tracker.observe(14), SeqEvent::Duplicate);
+        assert_eq!(tracker.observe(16), SeqEvent::InOrder);
+        assert_eq!(tracker.observe(1), SeqEvent::Reset { expected: 17 });
+        assert_eq!(tracker.observe(2), SeqEvent::InOrder);
+
+        assert_eq!(
+            tracker.stats(),
+            SeqStats {
+                packets: 7,
+                lost_packets: 3,
+                gaps: 1,
+                duplicates: 1,
+                resets: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn sequence_wraps_without_loss() {
+        let mut tracker = SeqTracker::new();
+        for seq in [0xFFFE, 0xFFFF, 0, 1, 2] {
+            assert_eq!(tracker.observe(seq), SeqEvent::InOrder, "seq {seq}");
+        }
+        assert_eq!(
+            tracker.observe(0x9000),
+            SeqEvent::Reset { expected: 3 },
+            "far backwards jump"
+        );
+    }
+
+    #[test]
+    fn streams_are_tracked_independently() {
+        let mut streams = StreamSequences::new();
+        assert_eq!(streams.observe(MsgType::Ack, 5), None);
+        assert_eq!(
+            streams.observe(MsgType::TraceSample, 1),
+            Some(SeqEvent::InOrder)
+        );
+        assert_eq!(streams.observe(MsgType::Health, 1), Some(SeqEvent::InOrder));
+        assert_eq!(
+            streams.observe(MsgType::TraceSampleCompressed, 2),
+            Some(SeqEvent::InOrder)
+        );
+        assert_eq!(
+            streams.observe(MsgType::TraceSampleTimed, 4),
+            Some(SeqEvent::Gap {
+                expected: 3,
+                lost: 1
+            })
+        );
+        assert_eq!(streams.stats(SeqStream::Trace).lost_packets, 1);
+        assert_eq!(streams.stats(SeqStream::Health).packets, 1);
+        assert_eq!(streams.observe(MsgType::Log, 1), Some(SeqEvent::InOrder));
+        assert_eq!(streams.stats(SeqStream::Log).packets, 1);
+
+        streams.restart(SeqStream::Trace);
+        assert_eq!(
+            streams.observe(MsgType::TraceSample, 1),
+            Some(SeqEvent::InOrder)
+        );
+    }
+}
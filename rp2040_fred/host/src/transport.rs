@@ -1,30 +1,253 @@
+use std::fmt;
 use std::io;
 use std::time::{Duration, Instant};
 
+use rp2040_fred_protocol::bridge_proto::compat::{
+    decode_legacy, Downgrade, LegacyVersion, V1_PACKET_SIZE, V1_PROTOCOL_VERSION, V2_PACKET_SIZE,
+    V2_PROTOCOL_VERSION,
+};
 use rp2040_fred_protocol::bridge_proto::{
-    crc32_ieee, MsgType, Packet, CRC_SIZE, HEADER_SIZE, MIN_PACKET_SIZE, PACKET_SIZE,
-    PROTOCOL_VERSION,
+    ConfigGetPayload, ConfigPayload, ConfigSetPayload, DeviceInfoPayload, MsgType, NackPayload,
+    NackReason, Packet, PacketRef, MIN_PACKET_SIZE, PACKET_SIZE, PROTOCOL_VERSION,
+    TRACE_LAYOUT_VERSION,
+};
+use rp2040_fred_protocol::config::{
+    ConfigEntries, ConfigEntry, ConfigKey, ConfigValue, DeviceConfig,
 };
+use rp2040_fred_protocol::dro_decode::Calibration;
 use rusb::{Context, DeviceHandle, Direction, Error as UsbError, TransferType, UsbContext};
 
-const LEGACY_PROTOCOL_VERSION: u8 = 1;
-const LEGACY_PACKET_SIZE: usize = 32;
-const LEGACY_PAYLOAD_SIZE: usize = 20;
-const V2_PROTOCOL_VERSION: u8 = 2;
-const V2_PACKET_SIZE: usize = 64;
-const V2_PAYLOAD_SIZE: usize = V2_PACKET_SIZE - HEADER_SIZE - CRC_SIZE;
+mod serial;
+
+pub use serial::SerialTransport;
 
 pub trait HostTransport {
+    /// Sends `req` and returns every packet read up to and including its
+    /// `ACK`. A `NACK` is returned as an error wrapping a [`NackError`].
     fn transact(&mut self, req: Packet) -> io::Result<Vec<Packet>>;
 }
 
+/// The device answered a request with `NACK`, one variant per
+/// [`NackReason`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NackError {
+    InvalidPayload {
+        rejected_type: u8,
+    },
+    Undecodable,
+    CaptureActive {
+        rejected_type: u8,
+    },
+    CaptureInactive {
+        rejected_type: u8,
+    },
+    Unsupported {
+        rejected_type: u8,
+    },
+    Storage {
+        rejected_type: u8,
+    },
+    /// A reason code this build does not know.
+    Other {
+        rejected_type: u8,
+        reason: u8,
+    },
+}
+
+impl NackError {
+    pub fn from_payload(nack: NackPayload) -> Self {
+        let rejected_type = nack.rejected_type;
+        match nack.nack_reason() {
+            Some(NackReason::InvalidPayload) => Self::InvalidPayload { rejected_type },
+            Some(NackReason::Undecodable) => Self::Undecodable,
+            Some(NackReason::CaptureActive) => Self::CaptureActive { rejected_type },
+            Some(NackReason::CaptureInactive) => Self::CaptureInactive { rejected_type },
+            Some(NackReason::Unsupported) => Self::Unsupported { rejected_type },
+            Some(NackReason::Storage) => Self::Storage { rejected_type },
+            None => Self::Other {
+                rejected_type,
+                reason: nack.reason,
+            },
+        }
+    }
+
+    pub fn rejected_type(&self) -> u8 {
+        match *self {
+            Self::Undecodable => 0xFF,
+            Self::InvalidPayload { rejected_type }
+            | Self::CaptureActive { rejected_type }
+            | Self::CaptureInactive { rejected_type }
+            | Self::Unsupported { rejected_type }
+            | Self::Storage { rejected_type }
+            | Self::Other { rejected_type, .. } => rejected_type,
+        }
+    }
+
+    pub fn reason(&self) -> Option<NackReason> {
+        match self {
+            Self::InvalidPayload { .. } => Some(NackReason::InvalidPayload),
+            Self::Undecodable => Some(NackReason::Undecodable),
+            Self::CaptureActive { .. } => Some(NackReason::CaptureActive),
+            Self::CaptureInactive { .. } => Some(NackReason::CaptureInactive),
+            Self::Unsupported { .. } => Some(NackReason::Unsupported),
+            Self::Storage { .. } => Some(NackReason::Storage),
+            Self::Other { .. } => None,
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::InvalidPayload { .. } => io::ErrorKind::InvalidInput,
+            Self::Undecodable | Self::Other { .. } => io::ErrorKind::InvalidData,
+            Self::Storage { .. } => io::ErrorKind::Other,
+            Self::CaptureActive { .. }
+            | Self::CaptureInactive { .. }
+            | Self::Unsupported { .. } => io::ErrorKind::Unsupported,
+        }
+    }
+}
+
+impl fmt::Display for NackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rejected = self.rejected_type();
+        match MsgType::from_u8(rejected) {
+            Some(t) => write!(f, "device rejected {t:?}: ")?,
+            None => write!(f, "device rejected type 0x{rejected:02X}: ")?,
+        }
+        match *self {
+            Self::Other { reason, .. } => write!(f, "unknown reason 0x{reason:02X}"),
+            _ => f.write_str(self.reason().map_or("", NackReason::description)),
+        }
+    }
+}
+
+impl std::error::Error for NackError {}
+
+impl From<NackError> for io::Error {
+    fn from(err: NackError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// The [`NackError`] behind `err`, if `err` came from a rejected request.
+pub fn nack_error(err: &io::Error) -> Option<&NackError> {
+    err.get_ref()?.downcast_ref()
+}
+
+/// Runs the `HELLO` handshake. `Ok(None)` means the device rejected `HELLO`
+/// as `Undecodable`, i.e. its firmware predates `DEVICE_INFO` and cannot
+/// decode the message type. Any other NACK is returned as an error.
+pub fn query_device_info<T: HostTransport>(
+    transport: &mut T,
+    seq: u16,
+) -> io::Result<Option<DeviceInfoPayload>> {
+    let replies = match transport.transact(Packet::hello(seq)) {
+        Ok(replies) => replies,
+        Err(err) if nack_error(&err) == Some(&NackError::Undecodable) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if let Some(pkt) = replies.iter().find(|p| p.msg_type == MsgType::DeviceInfo) {
+        return pkt.decode_payload().map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad DEVICE_INFO payload: {e:?}"),
+            )
+        });
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no DEVICE_INFO reply to HELLO",
+    ))
+}
+
+/// Fails unless trace batches from the device use [`TRACE_LAYOUT_VERSION`].
+/// Firmware without `HELLO` (`info` of `None`) predates layout 2 and sends
+/// 8-byte metadata, except legacy protocols, whose batches are converted.
+pub fn check_trace_layout(
+    info: Option<&DeviceInfoPayload>,
+    downgrade: Option<Downgrade>,
+) -> io::Result<()> {
+    let layout = match (info, downgrade) {
+        (Some(info), _) => info.trace_layout_version,
+        (None, Some(_)) => return Ok(()),
+        (None, None) => 1,
+    };
+    if layout == TRACE_LAYOUT_VERSION {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "device sends trace layout v{layout}, this fredctl reads v{TRACE_LAYOUT_VERSION}; \
+             update the firmware"
+        ),
+    ))
+}
+
+/// `CONFIG_GET`: the stored value of `key`, or of every key. Entries with
+/// keys this build does not know are returned as-is.
+pub fn read_config<T: HostTransport>(
+    transport: &mut T,
+    seq: u16,
+    key: Option<ConfigKey>,
+) -> io::Result<Vec<ConfigEntry>> {
+    let replies = transport.transact(Packet::from_payload(seq, &ConfigGetPayload { key }))?;
+    let pkt = replies
+        .iter()
+        .find(|p| p.msg_type == MsgType::Config && p.seq == seq)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no CONFIG reply"))?;
+    let config = pkt.decode_payload::<ConfigPayload>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad CONFIG payload: {e:?}"),
+        )
+    })?;
+    Ok(config.entries.as_slice().to_vec())
+}
+
+/// The calibration stored on the device (`x_counts_per_mm`, `x_inverted`,
+/// `x_zero_offset` and their Z twins), the defaults for keys it lacks.
+/// Entries this build cannot read are skipped.
+pub fn read_calibration<T: HostTransport>(transport: &mut T, seq: u16) -> io::Result<Calibration> {
+    let mut config = DeviceConfig::default();
+    for entry in read_config(transport, seq, None)? {
+        if let Ok(value) = entry.decode_value() {
+            config.set(value);
+        }
+    }
+    Ok(config.calibration)
+}
+
+/// `CONFIG_SET`: stores `values` on the device. They take effect at its
+/// next power-up.
+pub fn write_config<T: HostTransport>(
+    transport: &mut T,
+    seq: u16,
+    values: &[ConfigValue],
+) -> io::Result<()> {
+    let mut entries = ConfigEntries::new();
+    for &value in values {
+        if !entries.push(value.into()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many config values for one CONFIG_SET",
+            ));
+        }
+    }
+    transport.transact(Packet::from_payload(seq, &ConfigSetPayload { entries }))?;
+    Ok(())
+}
+
 pub struct UsbTransport {
     _ctx: Context,
     handle: DeviceHandle<Context>,
     in_ep: u8,
     out_ep: u8,
     timeout: Duration,
-    warned_legacy_packets: bool,
+    downgrade: Option<Downgrade>,
+    rx: Box<[u8; PACKET_SIZE]>,
+    /// Legacy packets are converted, so they cannot borrow from `rx`.
+    converted: Packet,
 }
 
 impl UsbTransport {
@@ -86,7 +309,9 @@ impl UsbTransport {
                 in_ep,
                 out_ep,
                 timeout: Duration::from_millis(600_000),
-                warned_legacy_packets: false,
+                downgrade: None,
+                rx: Box::new([0u8; PACKET_SIZE]),
+                converted: Packet::ping(0),
             });
         }
 
@@ -101,11 +326,16 @@ impl UsbTransport {
     }
 
     pub fn read_packet_timeout(&mut self, timeout: Duration) -> io::Result<Packet> {
+        self.read_packet_ref(timeout).map(|pkt| pkt.to_packet())
+    }
+
+    /// Reads one packet without copying its payload out of the receive
+    /// buffer. The view is valid until the next read.
+    pub fn read_packet_ref(&mut self, timeout: Duration) -> io::Result<PacketRef<'_>> {
         loop {
-            let mut buf = [0u8; PACKET_SIZE];
             let n = self
                 .handle
-                .read_bulk(self.in_ep, &mut buf, timeout)
+                .read_bulk(self.in_ep, &mut self.rx[..], timeout)
                 .map_err(io_other)?;
 
             // Embassy's CMSIS-DAP v2 class appends a zero-length packet after
@@ -114,40 +344,43 @@ impl UsbTransport {
                 continue;
             }
 
-            let raw = &buf[..n];
-            if raw.len() >= MIN_PACKET_SIZE && raw[1] == PROTOCOL_VERSION {
-                return Packet::decode(raw).map_err(|e| {
+            if n >= MIN_PACKET_SIZE && self.rx[1] == PROTOCOL_VERSION {
+                return PacketRef::decode(&self.rx[..n]).map_err(|e| {
                     io::Error::new(io::ErrorKind::InvalidData, format!("decode error: {:?}", e))
                 });
             }
 
-            if n == V2_PACKET_SIZE && raw[1] == V2_PROTOCOL_VERSION {
-                if !self.warned_legacy_packets {
-                    eprintln!(
-                        "warning: device returned legacy fixed-size 64-byte packets; likely old firmware/protocol v2"
-                    );
-                    self.warned_legacy_packets = true;
-                }
-                return decode_v2_packet(raw);
-            }
-
-            if n == LEGACY_PACKET_SIZE && raw[1] == LEGACY_PROTOCOL_VERSION {
-                if !self.warned_legacy_packets {
-                    eprintln!("warning: device returned legacy 32-byte packets; likely old firmware/protocol v1");
-                    self.warned_legacy_packets = true;
-                }
-                return decode_legacy_packet(raw);
+            let raw = &self.rx[..n];
+            if let Some(version) = LegacyVersion::detect(raw) {
+                let (downgrade, pkt) = decode_legacy(raw).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "protocol v{} decode error: {e:?}",
+                            version.protocol_version()
+                        ),
+                    )
+                })?;
+                self.downgrade.get_or_insert(downgrade);
+                self.converted = pkt;
+                return Ok(self.converted.as_packet_ref());
             }
 
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!(
-                    "unexpected USB packet size: got {n} bytes, expected a protocol v{PROTOCOL_VERSION} packet between {MIN_PACKET_SIZE} and {PACKET_SIZE} bytes, a protocol v{V2_PROTOCOL_VERSION} packet of {V2_PACKET_SIZE} bytes, or a protocol v{LEGACY_PROTOCOL_VERSION} packet of {LEGACY_PACKET_SIZE} bytes"
+                    "unexpected USB packet size: got {n} bytes, expected a protocol v{PROTOCOL_VERSION} packet between {MIN_PACKET_SIZE} and {PACKET_SIZE} bytes, a protocol v{V2_PROTOCOL_VERSION} packet of {V2_PACKET_SIZE} bytes, or a protocol v{V1_PROTOCOL_VERSION} packet of {V1_PACKET_SIZE} bytes"
                 ),
             ));
         }
     }
 
+    /// Set once the device has sent a protocol v1 or v2 packet, which this
+    /// transport converts. Callers decide whether and how to warn.
+    pub fn downgrade(&self) -> Option<Downgrade> {
+        self.downgrade
+    }
+
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.timeout = timeout;
     }
@@ -172,40 +405,71 @@ impl UsbTransport {
 impl HostTransport for UsbTransport {
     fn transact(&mut self, req: Packet) -> io::Result<Vec<Packet>> {
         self.write_packet(&req)?;
+        collect_replies(req.seq, || self.read_packet())
+    }
+}
 
-        let deadline = Instant::now() + Duration::from_millis(5000);
-        let mut replies = Vec::new();
-        let want_seq = req.seq;
-
-        while Instant::now() < deadline {
-            match self.read_packet() {
-                Ok(pkt) => {
-                    let done = matches!(
-                        pkt.msg_type,
-                        rp2040_fred_protocol::bridge_proto::MsgType::Ack
-                            | rp2040_fred_protocol::bridge_proto::MsgType::Nack
-                    ) && pkt.seq == want_seq;
-                    replies.push(pkt);
-                    if done {
-                        break;
-                    }
+/// Reads until the `ACK`/`NACK` for `want_seq`, keeping everything seen on
+/// the way. Read timeouts are retried until the transaction deadline. A
+/// `NACK` becomes a [`NackError`].
+///
+/// A device that could not decode the request cannot tell its seq, so an
+/// `Undecodable` NACK with seq 0 also answers the pending request.
+fn collect_replies(
+    want_seq: u16,
+    mut read_packet: impl FnMut() -> io::Result<Packet>,
+) -> io::Result<Vec<Packet>> {
+    let deadline = Instant::now() + Duration::from_millis(5000);
+    let mut replies = Vec::new();
+
+    while Instant::now() < deadline {
+        match read_packet() {
+            Ok(pkt) => {
+                let done = answers(&pkt, want_seq);
+                replies.push(pkt);
+                if done {
+                    break;
                 }
-                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
-                Err(e) => return Err(e),
             }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
         }
+    }
 
-        if replies.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                "no USB response packet received",
-            ));
+    if replies.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no response packet received",
+        ));
+    }
+
+    match replies.last() {
+        Some(pkt) if pkt.msg_type == MsgType::Nack && answers(pkt, want_seq) => {
+            let nack = pkt.decode_payload::<NackPayload>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad NACK payload: {e:?}"),
+                )
+            })?;
+            Err(NackError::from_payload(nack).into())
         }
+        _ => Ok(replies),
+    }
+}
 
-        Ok(replies)
+fn answers(pkt: &Packet, want_seq: u16) -> bool {
+    match pkt.msg_type {
+        MsgType::Ack => pkt.seq == want_seq,
+        MsgType::Nack => pkt.seq == want_seq || (pkt.seq == 0 && is_undecodable_nack(pkt)),
+        _ => false,
     }
 }
 
+fn is_undecodable_nack(pkt: &Packet) -> bool {
+    pkt.decode_payload::<NackPayload>()
+        .is_ok_and(|nack| nack.rejected_type == 0xFF)
+}
+
 fn io_other(e: UsbError) -> io::Error {
     let kind = match e {
         UsbError::Timeout => io::ErrorKind::TimedOut,
@@ -215,128 +479,247 @@ fn io_other(e: UsbError) -> io::Error {
     io::Error::new(kind, e.to_string())
 }
 
-fn decode_legacy_packet(raw: &[u8]) -> io::Result<Packet> {
-    if raw.len() != LEGACY_PACKET_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "legacy packet decode with wrong length",
-        ));
-    }
-
-    if raw[0] != 0xA5 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("legacy bad magic: 0x{:02X}", raw[0]),
-        ));
-    }
-    if raw[1] != LEGACY_PROTOCOL_VERSION {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("legacy bad protocol version: {}", raw[1]),
-        ));
-    }
-
-    let payload_len = raw[3] as usize;
-    if payload_len > LEGACY_PAYLOAD_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("legacy invalid payload length: {payload_len}"),
-        ));
-    }
-
-    let expected_crc = u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]);
-    let actual_crc = crc32_ieee(&raw[..28]);
-    if expected_crc != actual_crc {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "legacy CRC mismatch",
-        ));
-    }
-
-    let msg_type = MsgType::from_u8(raw[2]).ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("legacy unknown msg type: 0x{:02X}", raw[2]),
-        )
-    })?;
-    let seq = u16::from_le_bytes([raw[4], raw[5]]);
-    let payload = &raw[8..8 + payload_len];
+#[cfg(test)]
+mod tests {
+    use std::io;
 
-    if msg_type == MsgType::TraceSample {
-        let mut samples = Vec::new();
-        for chunk in payload.chunks_exact(4) {
-            samples.push(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+    use super::{
+        check_trace_layout, collect_replies, nack_error, query_device_info, read_calibration,
+        read_config, write_config, HostTransport, NackError,
+    };
+    use rp2040_fred_protocol::bridge_proto::compat::{Downgrade, LegacyVersion};
+    use rp2040_fred_protocol::bridge_proto::{
+        crc32_ieee, ConfigPayload, DeviceInfoPayload, DeviceTransport, MsgType, MsgTypeSet,
+        NackPayload, NackReason, Packet, CRC_SIZE, PAYLOAD_SIZE, PIN_MAP_NONE, PROTOCOL_VERSION,
+        TRACE_LAYOUT_VERSION,
+    };
+    use rp2040_fred_protocol::config::{ConfigEntry, ConfigKey, ConfigValue, DeviceConfig};
+
+    struct CannedTransport(Vec<Packet>);
+
+    impl HostTransport for CannedTransport {
+        fn transact(&mut self, req: Packet) -> io::Result<Vec<Packet>> {
+            let mut replies = self.0.clone().into_iter();
+            collect_replies(req.seq, || {
+                replies
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no more replies"))
+            })
         }
-        return Ok(Packet::trace_samples(seq, 0, 0, &samples));
     }
 
-    Packet::new(msg_type, seq, payload).ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            "legacy packet could not be converted to current packet format",
-        )
-    })
-}
-
-fn decode_v2_packet(raw: &[u8]) -> io::Result<Packet> {
-    if raw.len() != V2_PACKET_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "v2 packet decode with wrong length",
-        ));
+    #[test]
+    fn hello_returns_device_info_or_none_for_old_firmware() {
+        let info = DeviceInfoPayload {
+            firmware_version: [0, 1, 0],
+            git_hash: *b"deadbeef",
+            transport: DeviceTransport::MockBus,
+            protocol_version: PROTOCOL_VERSION,
+            max_payload: PAYLOAD_SIZE as u16,
+            trace_layout_version: TRACE_LAYOUT_VERSION,
+            pin_map_id: PIN_MAP_NONE,
+            supported: MsgTypeSet::from_types(&[MsgType::Ping, MsgType::Hello]),
+        };
+        let mut t = CannedTransport(vec![
+            Packet::from_payload(4, &info),
+            Packet::ack(4, MsgType::Hello, 0),
+        ]);
+        assert_eq!(query_device_info(&mut t, 4).expect("hello"), Some(info));
+
+        // Pre-HELLO firmware cannot decode the type, so it cannot echo seq.
+        let mut old = CannedTransport(vec![Packet::nack(0, 0xFF, NackReason::Undecodable)]);
+        assert_eq!(query_device_info(&mut old, 4).expect("hello"), None);
+
+        let mut busy = CannedTransport(vec![Packet::nack(
+            4,
+            MsgType::Hello as u8,
+            NackReason::CaptureActive,
+        )]);
+        let err = query_device_info(&mut busy, 4).expect_err("capture active");
+        assert_eq!(
+            nack_error(&err),
+            Some(&NackError::CaptureActive {
+                rejected_type: MsgType::Hello as u8
+            })
+        );
+
+        let mut silent = CannedTransport(vec![Packet::ack(4, MsgType::Hello, 0)]);
+        assert!(query_device_info(&mut silent, 4).is_err());
     }
 
-    if raw[0] != 0xA5 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("v2 bad magic: 0x{:02X}", raw[0]),
-        ));
-    }
-    if raw[1] != V2_PROTOCOL_VERSION {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("v2 bad protocol version: {}", raw[1]),
-        ));
+    #[test]
+    fn trace_layout_must_match() {
+        let mut info = DeviceInfoPayload {
+            firmware_version: [0, 1, 0],
+            git_hash: *b"deadbeef",
+            transport: DeviceTransport::PioReal,
+            protocol_version: PROTOCOL_VERSION,
+            max_payload: PAYLOAD_SIZE as u16,
+            trace_layout_version: TRACE_LAYOUT_VERSION,
+            pin_map_id: PIN_MAP_NONE,
+            supported: MsgTypeSet::from_types(&[MsgType::Hello]),
+        };
+        assert!(check_trace_layout(Some(&info), None).is_ok());
+
+        info.trace_layout_version = 1;
+        let err = check_trace_layout(Some(&info), None).expect_err("layout 1");
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("trace layout v1"), "{err}");
+        // No HELLO: 8-byte metadata, unless a legacy protocol is converted.
+        assert!(check_trace_layout(None, None).is_err());
+        let downgrade = Downgrade {
+            version: LegacyVersion::V2,
+            first_msg_type: MsgType::Nack,
+        };
+        assert!(check_trace_layout(None, Some(downgrade)).is_ok());
     }
 
-    let payload_len = raw[3] as usize;
-    if payload_len > V2_PAYLOAD_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("v2 invalid payload length: {payload_len}"),
-        ));
-    }
+    #[test]
+    fn each_nack_reason_maps_to_its_own_error() {
+        let cases = [
+            (
+                NackReason::InvalidPayload,
+                NackError::InvalidPayload {
+                    rejected_type: 0x11,
+                },
+                io::ErrorKind::InvalidInput,
+            ),
+            (
+                NackReason::Undecodable,
+                NackError::Undecodable,
+                io::ErrorKind::InvalidData,
+            ),
+            (
+                NackReason::CaptureActive,
+                NackError::CaptureActive {
+                    rejected_type: 0x11,
+                },
+                io::ErrorKind::Unsupported,
+            ),
+            (
+                NackReason::CaptureInactive,
+                NackError::CaptureInactive {
+                    rejected_type: 0x11,
+                },
+                io::ErrorKind::Unsupported,
+            ),
+            (
+                NackReason::Unsupported,
+                NackError::Unsupported {
+                    rejected_type: 0x11,
+                },
+                io::ErrorKind::Unsupported,
+            ),
+            (
+                NackReason::Storage,
+                NackError::Storage {
+                    rejected_type: 0x11,
+                },
+                io::ErrorKind::Other,
+            ),
+        ];
+        for (reason, expected, kind) in cases {
+            let rejected_type = if reason == NackReason::Undecodable {
+                0xFF
+            } else {
+                MsgType::UnitCfg as u8
+            };
+            let mut t = CannedTransport(vec![
+                Packet::telemetry(1, 0, 0, 0, 0, 0),
+                Packet::nack(8, rejected_type, reason),
+            ]);
+            let err = t.transact(Packet::ping(8)).expect_err("nack");
+            assert_eq!(err.kind(), kind);
+            assert_eq!(nack_error(&err), Some(&expected));
+            assert_eq!(expected.reason(), Some(reason));
+        }
 
-    let expected_crc = u32::from_le_bytes([raw[60], raw[61], raw[62], raw[63]]);
-    let actual_crc = crc32_ieee(&raw[..60]);
-    if expected_crc != actual_crc {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "v2 CRC mismatch",
-        ));
+        let unknown = NackPayload {
+            rejected_type: MsgType::Ping as u8,
+            reason: 0x42,
+        };
+        let mut t = CannedTransport(vec![Packet::from_payload(8, &unknown)]);
+        let err = t.transact(Packet::ping(8)).expect_err("nack");
+        assert_eq!(err.to_string(), "device rejected Ping: unknown reason 0x42");
+
+        // A NACK for some other request does not end this transaction.
+        let mut t = CannedTransport(vec![
+            Packet::nack(3, 0xFF, NackReason::Undecodable),
+            Packet::ack(8, MsgType::Ping, 0),
+        ]);
+        assert_eq!(t.transact(Packet::ping(8)).expect("ping").len(), 2);
     }
 
-    let msg_type = MsgType::from_u8(raw[2]).ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("v2 unknown msg type: 0x{:02X}", raw[2]),
-        )
-    })?;
-    let seq = u16::from_le_bytes([raw[4], raw[5]]);
-    let payload = &raw[8..8 + payload_len];
-
-    if msg_type == MsgType::TraceSample {
-        let mut samples = Vec::new();
-        for chunk in payload.chunks_exact(4) {
-            samples.push(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+    #[test]
+    fn undecodable_requests_fail_with_undecodable() {
+        // What the firmware answers to a type it does not know: the seq is
+        // echoed from the intact header, or 0 when the header is damaged.
+        let req = Packet::ping(9);
+        let mut raw = req.encode();
+        raw[2] = 0x7E;
+        let n = req.encoded_len();
+        let crc = crc32_ieee(&raw[..n - CRC_SIZE]);
+        raw[n - CRC_SIZE..n].copy_from_slice(&crc.to_le_bytes());
+        let err = Packet::decode(&raw[..n]).expect_err("unknown type");
+        let echoed = Packet::undecodable_nack(&raw[..n], err);
+        assert_eq!(echoed.seq, 9);
+
+        for nack in [echoed, Packet::nack(0, 0xFF, NackReason::Undecodable)] {
+            let mut t = CannedTransport(vec![Packet::telemetry(1, 0, 0, 0, 0, 0), nack]);
+            let err = t.transact(req).expect_err("nack");
+            assert_eq!(nack_error(&err), Some(&NackError::Undecodable));
         }
-        return Ok(Packet::trace_samples(seq, 0, 0, &samples));
     }
 
-    Packet::new(msg_type, seq, payload).ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            "v2 packet could not be converted to current packet format",
-        )
-    })
+    #[test]
+    fn config_get_and_set() {
+        let config = DeviceConfig::default();
+        let mut t = CannedTransport(vec![
+            Packet::from_payload(
+                5,
+                &ConfigPayload {
+                    entries: config.entries(),
+                },
+            ),
+            Packet::ack(5, MsgType::ConfigGet, 0),
+        ]);
+        let entries = read_config(&mut t, 5, None).expect("config");
+        assert_eq!(entries.len(), ConfigKey::ALL.len());
+        assert_eq!(
+            entries[0].decode_value(),
+            Ok(ConfigValue::PowerUpMode(config.power_up_mode))
+        );
+
+        let mut stored = DeviceConfig::default();
+        stored.calibration.x.counts_per_mm = 200.0;
+        stored.calibration.z.zero_offset = -1500;
+        let mut entries = stored.entries();
+        // A key from newer firmware.
+        assert!(entries.push(ConfigEntry {
+            key: 0x7F,
+            value: 1
+        }));
+        let mut t = CannedTransport(vec![
+            Packet::from_payload(5, &ConfigPayload { entries }),
+            Packet::ack(5, MsgType::ConfigGet, 0),
+        ]);
+        assert_eq!(
+            read_calibration(&mut t, 5).expect("calibration"),
+            stored.calibration
+        );
+
+        let mut t = CannedTransport(vec![Packet::nack(
+            6,
+            MsgType::ConfigSet as u8,
+            NackReason::Storage,
+        )]);
+        let err = write_config(&mut t, 6, &[ConfigValue::TelemetryPeriodMs(50)])
+            .expect_err("storage nack");
+        assert_eq!(
+            nack_error(&err),
+            Some(&NackError::Storage {
+                rejected_type: MsgType::ConfigSet as u8
+            })
+        );
+    }
 }
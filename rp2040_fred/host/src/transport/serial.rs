@@ -0,0 +1,156 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use rp2040_fred_protocol::bridge_proto::Packet;
+use rp2040_fred_protocol::framing::{encode_packet_frame, FrameDecoder, MAX_FRAME_SIZE};
+use serialport::SerialPort;
+
+use super::{collect_replies, HostTransport};
+
+/// Bridge protocol over a byte stream (UART, CDC-ACM, pty), COBS framed.
+pub struct SerialTransport<S = Box<dyn SerialPort>> {
+    port: S,
+    decoder: FrameDecoder,
+    rx: [u8; 256],
+    rx_pos: usize,
+    rx_len: usize,
+    frame_errors: u64,
+}
+
+impl SerialTransport {
+    pub fn open(path: &str, baud_rate: u32) -> io::Result<Self> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(Duration::from_millis(600_000))
+            .open()?;
+        Ok(Self::new(port))
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> SerialTransport<S> {
+    /// Wraps an already configured stream. Read timeouts come from the stream.
+    pub fn new(port: S) -> Self {
+        Self {
+            port,
+            decoder: FrameDecoder::new(),
+            rx: [0u8; 256],
+            rx_pos: 0,
+            rx_len: 0,
+            frame_errors: 0,
+        }
+    }
+
+    /// Frames dropped for bad COBS, CRC or length since open.
+    pub fn frame_errors(&self) -> u64 {
+        self.frame_errors
+    }
+
+    pub fn read_packet(&mut self) -> io::Result<Packet> {
+        loop {
+            if self.rx_pos == self.rx_len {
+                let n = self.port.read(&mut self.rx)?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "serial stream closed",
+                    ));
+                }
+                self.rx_pos = 0;
+                self.rx_len = n;
+            }
+
+            while self.rx_pos < self.rx_len {
+                let byte = self.rx[self.rx_pos];
+                self.rx_pos += 1;
+                match self.decoder.push(byte) {
+                    Some(Ok(pkt)) => return Ok(pkt.to_packet()),
+                    // Corrupt frames are dropped; the decoder has already
+                    // resynchronised on the delimiter.
+                    Some(Err(_)) => self.frame_errors += 1,
+                    None => {}
+                }
+            }
+        }
+    }
+
+    pub fn write_packet(&mut self, pkt: &Packet) -> io::Result<()> {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let n = encode_packet_frame(pkt, &mut frame);
+        self.port.write_all(&frame[..n])?;
+        self.port.flush()
+    }
+}
+
+impl<S: Read + Write> HostTransport for SerialTransport<S> {
+    fn transact(&mut self, req: Packet) -> io::Result<Vec<Packet>> {
+        self.write_packet(&req)?;
+        collect_replies(req.seq, || self.read_packet())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    use rp2040_fred_protocol::bridge_proto::{MsgType, Packet};
+    use rp2040_fred_protocol::framing::{encode_packet_frame, FrameDecoder, MAX_FRAME_SIZE};
+    use serialport::{SerialPort, TTYPort};
+
+    use super::SerialTransport;
+    use crate::transport::HostTransport;
+
+    /// Minimal device on the far end of the pty: answers every request with
+    /// line noise, a telemetry packet and the ACK. Runs until the host side
+    /// closes.
+    fn fake_device(mut port: TTYPort) {
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let Ok(n) = port.read(&mut buf) else {
+                return;
+            };
+            for &byte in &buf[..n] {
+                let Some(Ok(req)) = decoder.push(byte) else {
+                    continue;
+                };
+                let mut frame = [0u8; MAX_FRAME_SIZE];
+                port.write_all(&[0x17, 0x42, 0x00]).unwrap();
+                let n = encode_packet_frame(&Packet::telemetry(9, 1, 2, 3, 4, 0), &mut frame);
+                port.write_all(&frame[..n]).unwrap();
+                let n = encode_packet_frame(&Packet::ack(req.seq, req.msg_type, 0), &mut frame);
+                port.write_all(&frame[..n]).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn transact_over_pty_pair() {
+        let (mut host, mut device) = TTYPort::pair().expect("pty pair");
+        host.set_timeout(Duration::from_secs(2)).unwrap();
+        device.set_timeout(Duration::from_secs(2)).unwrap();
+        let device = thread::spawn(move || fake_device(device));
+
+        let mut t = SerialTransport::new(host);
+        let replies = t.transact(Packet::ping(0x1234)).expect("ping");
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].msg_type, MsgType::Telemetry);
+        assert_eq!(replies[1], Packet::ack(0x1234, MsgType::Ping, 0));
+        assert_eq!(t.frame_errors(), 1);
+
+        let replies = t.transact(Packet::capture_set(7, true)).expect("capture");
+        assert_eq!(
+            replies.last(),
+            Some(&Packet::ack(7, MsgType::CaptureSet, 0))
+        );
+        assert_eq!(t.frame_errors(), 2);
+
+        drop(t);
+        device.join().unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Packed BCD as the lathe sends DRO digits over FCF1: two decimal digits
+//! per byte, high nibble first. An axis is three pairs (six digits) after a
+//! separate sign byte, the spindle speed two pairs.
+
+/// Largest magnitude six digits hold.
+pub const AXIS_MAX: u32 = 999_999;
+/// Largest speed four digits hold.
+pub const RPM_MAX: u16 = 9_999;
+
+pub fn is_packed(byte: u8) -> bool {
+    (byte >> 4) <= 9 && (byte & 0x0F) <= 9
+}
+
+/// `None` if either nibble is not a decimal digit.
+pub fn decode_pair(byte: u8) -> Option<u8> {
+    is_packed(byte).then(|| (byte >> 4) * 10 + (byte & 0x0F))
+}
+
+/// `None` above 99.
+pub fn encode_pair(value: u8) -> Option<u8> {
+    if value > 99 {
+        return None;
+    }
+    Some(((value / 10) << 4) | (value % 10))
+}
+
+/// Pairs most significant first, in the order `03/02/01/00` (or
+/// `07/06/05/04`) answer after the sign.
+pub fn decode_axis(pairs: [u8; 3]) -> Option<u32> {
+    let mut value = 0u32;
+    for pair in pairs {
+        value = value * 100 + decode_pair(pair)? as u32;
+    }
+    Some(value)
+}
+
+/// `None` above [`AXIS_MAX`].
+pub fn encode_axis(magnitude: u32) -> Option<[u8; 3]> {
+    if magnitude > AXIS_MAX {
+        return None;
+    }
+    Some([
+        encode_pair((magnitude / 10_000) as u8)?,
+        encode_pair((magnitude / 100 % 100) as u8)?,
+        encode_pair((magnitude % 100) as u8)?,
+    ])
+}
+
+/// Pairs in `0D/0C` order.
+pub fn decode_rpm(pairs: [u8; 2]) -> Option<u16> {
+    Some(decode_pair(pairs[0])? as u16 * 100 + decode_pair(pairs[1])? as u16)
+}
+
+/// `None` above [`RPM_MAX`].
+pub fn encode_rpm(rpm: u16) -> Option<[u8; 2]> {
+    if rpm > RPM_MAX {
+        return None;
+    }
+    Some([
+        encode_pair((rpm / 100) as u8)?,
+        encode_pair((rpm % 100) as u8)?,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_axis, decode_pair, decode_rpm, encode_axis, encode_pair, encode_rpm, AXIS_MAX,
+        RPM_MAX,
+    };
+
+    #[test]
+    fn pairs_roundtrip_and_reject_hex_digits() {
+        for value in 0..=99 {
+            let byte = encode_pair(value).expect("encodable");
+            assert_eq!(decode_pair(byte), Some(value));
+        }
+        assert_eq!(encode_pair(42), Some(0x42));
+        assert_eq!(encode_pair(100), None);
+        assert_eq!(decode_pair(0x1A), None);
+        assert_eq!(decode_pair(0xA1), None);
+    }
+
+    #[test]
+    fn axis_and_rpm_roundtrip() {
+        for magnitude in [0, 7, 652, 1_234, 98_765, AXIS_MAX] {
+            let pairs = encode_axis(magnitude).expect("encodable");
+            assert_eq!(decode_axis(pairs), Some(magnitude));
+        }
+        assert_eq!(encode_axis(652), Some([0x00, 0x06, 0x52]));
+        assert_eq!(encode_axis(AXIS_MAX + 1), None);
+        assert_eq!(decode_axis([0x00, 0x0F, 0x00]), None);
+
+        for rpm in [0, 783, 1_200, RPM_MAX] {
+            assert_eq!(decode_rpm(encode_rpm(rpm).expect("encodable")), Some(rpm));
+        }
+        assert_eq!(encode_rpm(783), Some([0x07, 0x83]));
+        assert_eq!(encode_rpm(RPM_MAX + 1), None);
+    }
+}
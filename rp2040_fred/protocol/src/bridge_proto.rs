@@ -1,5 +1,31 @@
 #![allow(dead_code)]
 
+pub mod compat;
+mod config;
+mod log;
+mod payload;
+mod trace;
+
+pub use crate::crc::crc32_ieee;
+use crate::device_log::LogLevel;
+
+pub use config::{ConfigGetPayload, ConfigPayload, ConfigSetPayload};
+pub use log::{LogPayload, LogSetPayload};
+pub use payload::{
+    AckPayload, BridgePayload, CaptureSetPayload, DecoderStats, DeviceInfoPayload, DeviceTransport,
+    HealthPayload, HealthSetPayload, LinearUnits, MockSetPayload, MsgTypeSet, NackPayload,
+    NackReason, PayloadError, SnapshotPayload, TelemetryPayload, TelemetrySetPayload,
+    TraceMetadata, UnitConfig, XAxisMode, CAPTURE_FLAG_SAMPLE_TIMES, HEALTH_IDLE_NEVER,
+    PIN_MAP_NONE, PIN_MAP_PASSIVE_SNIFFER, TELEMETRY_FLAG_BUS_FAULT, TELEMETRY_FLAG_CORRECTED,
+    TELEMETRY_FLAG_ENABLED, TELEMETRY_FLAG_IMPERIAL, TELEMETRY_FLAG_RADIUS, TRACE_LAYOUT_VERSION,
+};
+use trace::TraceEncoding;
+pub use trace::{
+    TimedTraceSample, TimedTraceSampleIter, TraceBatchEncoder, TraceSampleIter,
+    TRACE_COMPRESSED_MAX_SAMPLES, TRACE_RUNS_PER_PACKET, TRACE_RUN_MAX, TRACE_RUN_SIZE,
+    TRACE_TIMED_SAMPLES_PER_PACKET, TRACE_TIMED_SAMPLE_SIZE,
+};
+
 pub const PACKET_MAGIC: u8 = 0xA5;
 pub const PROTOCOL_VERSION: u8 = 3;
 pub const HEADER_SIZE: usize = 8;
@@ -7,7 +33,7 @@ pub const CRC_SIZE: usize = 4;
 pub const PAYLOAD_SIZE: usize = 305;
 pub const PACKET_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE + CRC_SIZE;
 pub const MIN_PACKET_SIZE: usize = HEADER_SIZE + CRC_SIZE;
-pub const TRACE_METADATA_SIZE: usize = 8;
+pub const TRACE_METADATA_SIZE: usize = TraceMetadata::LEN;
 pub const TRACE_PACKED_SAMPLE_SIZE: usize = 3;
 pub const TRACE_SAMPLES_PER_PACKET: usize =
     (PAYLOAD_SIZE - TRACE_METADATA_SIZE) / TRACE_PACKED_SAMPLE_SIZE;
@@ -16,32 +42,54 @@ pub const TRACE_SAMPLES_PER_PACKET: usize =
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MsgType {
     Ping = 0x01,
+    Hello = 0x02,
     TelemetrySet = 0x10,
     UnitCfg = 0x11,
     SnapshotReq = 0x12,
     CaptureSet = 0x13,
     MockSet = 0x14,
+    HealthSet = 0x15,
+    ConfigGet = 0x16,
+    ConfigSet = 0x17,
+    LogSet = 0x18,
     Ack = 0x80,
     Nack = 0x81,
     Telemetry = 0x90,
     Health = 0x91,
     TraceSample = 0x92,
+    Snapshot = 0x93,
+    DeviceInfo = 0x94,
+    TraceSampleCompressed = 0x95,
+    TraceSampleTimed = 0x96,
+    Config = 0x97,
+    Log = 0x98,
 }
 
 impl MsgType {
     pub fn from_u8(v: u8) -> Option<Self> {
         match v {
             0x01 => Some(Self::Ping),
+            0x02 => Some(Self::Hello),
             0x10 => Some(Self::TelemetrySet),
             0x11 => Some(Self::UnitCfg),
             0x12 => Some(Self::SnapshotReq),
             0x13 => Some(Self::CaptureSet),
             0x14 => Some(Self::MockSet),
+            0x15 => Some(Self::HealthSet),
+            0x16 => Some(Self::ConfigGet),
+            0x17 => Some(Self::ConfigSet),
+            0x18 => Some(Self::LogSet),
             0x80 => Some(Self::Ack),
             0x81 => Some(Self::Nack),
             0x90 => Some(Self::Telemetry),
             0x91 => Some(Self::Health),
             0x92 => Some(Self::TraceSample),
+            0x93 => Some(Self::Snapshot),
+            0x94 => Some(Self::DeviceInfo),
+            0x95 => Some(Self::TraceSampleCompressed),
+            0x96 => Some(Self::TraceSampleTimed),
+            0x97 => Some(Self::Config),
+            0x98 => Some(Self::Log),
             _ => None,
         }
     }
@@ -65,26 +113,38 @@ pub struct Packet {
     pub payload: [u8; PAYLOAD_SIZE],
 }
 
+/// Samples of any trace packet; runs are expanded on iteration so callers
+/// never see the wire encoding.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct TraceSamples<'a> {
     pub dropped_samples_total: u32,
     pub rx_stall_count_total: u32,
+    pub first_sample_us: u64,
     sample_bytes: &'a [u8],
+    encoding: TraceEncoding,
+    sample_count: usize,
 }
 
 impl<'a> TraceSamples<'a> {
-    pub fn iter_samples(&self) -> impl Iterator<Item = u32> + 'a {
-        self.sample_bytes
-            .chunks_exact(TRACE_PACKED_SAMPLE_SIZE)
-            .map(|chunk| unpack_trace_sample([chunk[0], chunk[1], chunk[2]]))
+    pub fn iter_samples(&self) -> TraceSampleIter<'a> {
+        TraceSampleIter(self.iter_timed_samples())
+    }
+
+    pub fn iter_timed_samples(&self) -> TimedTraceSampleIter<'a> {
+        TimedTraceSampleIter::new(self.sample_bytes, self.encoding, self.first_sample_us)
     }
 
     pub fn sample_count(&self) -> usize {
-        self.sample_bytes.len() / TRACE_PACKED_SAMPLE_SIZE
+        self.sample_count
     }
 
-    pub fn packed_sample_bytes(&self) -> &'a [u8] {
-        self.sample_bytes
+    pub fn is_compressed(&self) -> bool {
+        self.encoding == TraceEncoding::Runs
+    }
+
+    /// Every sample carries its own timestamp (`TRACE_SAMPLE_TIMED`).
+    pub fn has_sample_times(&self) -> bool {
+        self.encoding == TraceEncoding::Timed
     }
 }
 
@@ -106,98 +166,126 @@ impl Packet {
 
     pub fn encode(&self) -> [u8; PACKET_SIZE] {
         let mut out = [0u8; PACKET_SIZE];
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE + self.payload_len as usize + CRC_SIZE
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self, DecodeError> {
+        PacketRef::decode(raw).map(|pkt| pkt.to_packet())
+    }
+
+    pub fn as_packet_ref(&self) -> PacketRef<'_> {
+        PacketRef {
+            msg_type: self.msg_type,
+            seq: self.seq,
+            payload: self.payload_used(),
+        }
+    }
+
+    /// Encodes into `out` and returns the encoded length, avoiding the
+    /// full-size temporary that `encode` returns.
+    pub fn encode_into(&self, out: &mut [u8]) -> usize {
+        let payload_len = self.payload_len as usize;
         out[0] = PACKET_MAGIC;
         out[1] = PROTOCOL_VERSION;
         out[2] = self.msg_type as u8;
         out[3] = 0;
         out[4..6].copy_from_slice(&self.seq.to_le_bytes());
         out[6..8].copy_from_slice(&self.payload_len.to_le_bytes());
-        let encoded_len = self.encoded_len();
-        let payload_len = self.payload_len as usize;
         out[HEADER_SIZE..HEADER_SIZE + payload_len].copy_from_slice(self.payload_used());
         let crc_offset = HEADER_SIZE + payload_len;
         let crc = crc32_ieee(&out[..crc_offset]);
-        out[crc_offset..encoded_len].copy_from_slice(&crc.to_le_bytes());
-        out
+        out[crc_offset..crc_offset + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+        crc_offset + CRC_SIZE
     }
 
-    pub fn encoded_len(&self) -> usize {
-        HEADER_SIZE + self.payload_len as usize + CRC_SIZE
+    pub fn payload_used(&self) -> &[u8] {
+        &self.payload[..self.payload_len as usize]
     }
 
-    pub fn decode(raw: &[u8]) -> Result<Self, DecodeError> {
-        if raw.len() < MIN_PACKET_SIZE {
-            return Err(DecodeError::PacketLen);
-        }
-        if raw[0] != PACKET_MAGIC {
-            return Err(DecodeError::BadMagic);
-        }
-        if raw[1] != PROTOCOL_VERSION {
-            return Err(DecodeError::BadVersion);
-        }
-        let payload_len = u16::from_le_bytes([raw[6], raw[7]]);
-        if payload_len as usize > PAYLOAD_SIZE {
-            return Err(DecodeError::PayloadLen);
-        }
-        let encoded_len = HEADER_SIZE + payload_len as usize + CRC_SIZE;
-        if raw.len() != encoded_len {
-            return Err(DecodeError::PacketLen);
-        }
-        let msg_type = MsgType::from_u8(raw[2]).ok_or(DecodeError::UnknownMsgType)?;
-        let crc_offset = HEADER_SIZE + payload_len as usize;
-        let expected_crc = u32::from_le_bytes([
-            raw[crc_offset],
-            raw[crc_offset + 1],
-            raw[crc_offset + 2],
-            raw[crc_offset + 3],
-        ]);
-        let actual_crc = crc32_ieee(&raw[..crc_offset]);
-        if expected_crc != actual_crc {
-            return Err(DecodeError::BadCrc);
-        }
+    pub fn ping(seq: u16) -> Self {
+        Self::new(MsgType::Ping, seq, &[]).expect("valid ping")
+    }
 
-        let seq = u16::from_le_bytes([raw[4], raw[5]]);
-        let mut payload = [0u8; PAYLOAD_SIZE];
-        payload[..payload_len as usize].copy_from_slice(&raw[HEADER_SIZE..crc_offset]);
-        Ok(Self {
-            msg_type,
+    pub fn from_payload<P: BridgePayload>(seq: u16, payload: &P) -> Self {
+        let mut fixed = [0u8; PAYLOAD_SIZE];
+        let used = payload.encode(&mut fixed);
+        Self {
+            msg_type: P::MSG_TYPE,
             seq,
-            payload_len,
-            payload,
-        })
+            payload_len: used as u16,
+            payload: fixed,
+        }
     }
 
-    pub fn payload_used(&self) -> &[u8] {
-        &self.payload[..self.payload_len as usize]
+    pub fn decode_payload<P: BridgePayload>(&self) -> Result<P, PayloadError> {
+        self.as_packet_ref().decode_payload()
     }
 
-    pub fn ping(seq: u16) -> Self {
-        Self::new(MsgType::Ping, seq, &[]).expect("valid ping")
+    /// Asks the device for `DEVICE_INFO`; the reply is followed by an `ACK`.
+    pub fn hello(seq: u16) -> Self {
+        Self::new(MsgType::Hello, seq, &[]).expect("valid hello")
     }
 
     pub fn telemetry_set(seq: u16, enable: bool, period_ms: u16) -> Self {
-        let payload = [enable as u8, period_ms as u8, (period_ms >> 8) as u8];
-        Self::new(MsgType::TelemetrySet, seq, &payload).expect("valid telemetry_set")
+        Self::from_payload(
+            seq,
+            &TelemetrySetPayload {
+                enable,
+                period_ms: Some(period_ms),
+            },
+        )
+    }
+
+    pub fn unit_cfg(seq: u16, config: UnitConfig) -> Self {
+        Self::from_payload(seq, &config)
+    }
+
+    pub fn snapshot_req(seq: u16) -> Self {
+        Self::new(MsgType::SnapshotReq, seq, &[]).expect("valid snapshot_req")
     }
 
     pub fn capture_set(seq: u16, enable: bool) -> Self {
-        let payload = [enable as u8];
-        Self::new(MsgType::CaptureSet, seq, &payload).expect("valid capture_set")
+        Self::from_payload(
+            seq,
+            &CaptureSetPayload {
+                enable,
+                sample_times: false,
+            },
+        )
     }
 
     pub fn mock_set(seq: u16, enable: bool) -> Self {
-        let payload = [enable as u8];
-        Self::new(MsgType::MockSet, seq, &payload).expect("valid mock_set")
+        Self::from_payload(seq, &MockSetPayload { enable })
     }
 
     pub fn ack(seq: u16, acked_type: MsgType, status: u8) -> Self {
-        let payload = [acked_type as u8, status];
-        Self::new(MsgType::Ack, seq, &payload).expect("valid ack")
+        Self::from_payload(seq, &AckPayload { acked_type, status })
     }
 
-    pub fn nack(seq: u16, rejected_type: u8, reason: u8) -> Self {
-        let payload = [rejected_type, reason];
-        Self::new(MsgType::Nack, seq, &payload).expect("valid nack")
+    pub fn nack(seq: u16, rejected_type: u8, reason: NackReason) -> Self {
+        Self::from_payload(seq, &NackPayload::new(rejected_type, reason))
+    }
+
+    /// The reply to a request [`PacketRef::decode`] rejected with `err`.
+    /// An unknown message type leaves the header readable, so its seq is
+    /// echoed when the CRC holds; otherwise the seq is 0.
+    pub fn undecodable_nack(raw: &[u8], err: DecodeError) -> Self {
+        let crc_offset = raw.len().saturating_sub(CRC_SIZE);
+        let seq = match raw {
+            [_, _, _, _, lo, hi, ..]
+                if err == DecodeError::UnknownMsgType
+                    && raw[crc_offset..] == crc32_ieee(&raw[..crc_offset]).to_le_bytes() =>
+            {
+                u16::from_le_bytes([*lo, *hi])
+            }
+            _ => 0,
+        };
+        Self::nack(seq, 0xFF, NackReason::Undecodable)
     }
 
     pub fn telemetry(
@@ -208,36 +296,45 @@ impl Packet {
         rpm: u16,
         flags: u8,
     ) -> Self {
-        let mut payload = [0u8; 16];
-        payload[0..4].copy_from_slice(&tick.to_le_bytes());
-        payload[4..8].copy_from_slice(&x_counts.to_le_bytes());
-        payload[8..12].copy_from_slice(&z_counts.to_le_bytes());
-        payload[12..14].copy_from_slice(&rpm.to_le_bytes());
-        payload[14] = flags;
-        payload[15] = 0;
-        Self::new(MsgType::Telemetry, seq, &payload).expect("valid telemetry")
+        Self::from_payload(
+            seq,
+            &TelemetryPayload {
+                tick,
+                x_counts,
+                z_counts,
+                rpm,
+                flags,
+            },
+        )
     }
 
-    pub fn health(seq: u16, tx_timeout_count: u32, rx_timeout_count: u32, bus_cycles: u32) -> Self {
-        let mut payload = [0u8; 12];
-        payload[0..4].copy_from_slice(&tx_timeout_count.to_le_bytes());
-        payload[4..8].copy_from_slice(&rx_timeout_count.to_le_bytes());
-        payload[8..12].copy_from_slice(&bus_cycles.to_le_bytes());
-        Self::new(MsgType::Health, seq, &payload).expect("valid health")
+    pub fn snapshot(seq: u16, snapshot: &SnapshotPayload) -> Self {
+        Self::from_payload(seq, snapshot)
     }
 
-    pub fn trace_samples(
-        seq: u16,
-        dropped_samples_total: u32,
-        rx_stall_count_total: u32,
-        samples: &[u32],
-    ) -> Self {
+    pub fn health_set(seq: u16, enable: bool, period_ms: u16) -> Self {
+        Self::from_payload(
+            seq,
+            &HealthSetPayload {
+                enable,
+                period_ms: Some(period_ms),
+            },
+        )
+    }
+
+    pub fn log_set(seq: u16, enable: bool, max_level: Option<LogLevel>) -> Self {
+        Self::from_payload(seq, &LogSetPayload { enable, max_level })
+    }
+
+    pub fn health(seq: u16, counters: &HealthPayload) -> Self {
+        Self::from_payload(seq, counters)
+    }
+
+    pub fn trace_samples(seq: u16, metadata: &TraceMetadata, samples: &[u32]) -> Self {
         assert!(samples.len() <= TRACE_SAMPLES_PER_PACKET);
 
         let mut payload = [0u8; PAYLOAD_SIZE];
-        payload[0..4].copy_from_slice(&dropped_samples_total.to_le_bytes());
-        payload[4..8].copy_from_slice(&rx_stall_count_total.to_le_bytes());
-        let mut used = TRACE_METADATA_SIZE;
+        let mut used = metadata.encode(&mut payload);
 
         for sample in samples {
             let packed = pack_trace_sample(*sample);
@@ -249,64 +346,125 @@ impl Packet {
     }
 
     pub fn trace_sample(seq: u16, sample_bits: u32) -> Self {
-        Self::trace_samples(seq, 0, 0, core::slice::from_ref(&sample_bits))
+        Self::trace_samples(
+            seq,
+            &TraceMetadata::default(),
+            core::slice::from_ref(&sample_bits),
+        )
     }
 
     pub fn decode_trace_samples(&self) -> Option<TraceSamples<'_>> {
-        if self.msg_type != MsgType::TraceSample
-            || (self.payload_len as usize) < TRACE_METADATA_SIZE
-        {
-            return None;
+        self.as_packet_ref().decode_trace_samples()
+    }
+}
+
+/// Validated packet borrowing its payload from the receive buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketRef<'a> {
+    pub msg_type: MsgType,
+    pub seq: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    /// Checks magic, version, length and CRC without copying the payload.
+    pub fn decode(raw: &'a [u8]) -> Result<Self, DecodeError> {
+        if raw.len() < MIN_PACKET_SIZE {
+            return Err(DecodeError::PacketLen);
+        }
+        if raw[0] != PACKET_MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if raw[1] != PROTOCOL_VERSION {
+            return Err(DecodeError::BadVersion);
+        }
+        let payload_len = u16::from_le_bytes([raw[6], raw[7]]) as usize;
+        if payload_len > PAYLOAD_SIZE {
+            return Err(DecodeError::PayloadLen);
+        }
+        let crc_offset = HEADER_SIZE + payload_len;
+        if raw.len() != crc_offset + CRC_SIZE {
+            return Err(DecodeError::PacketLen);
+        }
+        let msg_type = MsgType::from_u8(raw[2]).ok_or(DecodeError::UnknownMsgType)?;
+        let expected_crc = u32::from_le_bytes([
+            raw[crc_offset],
+            raw[crc_offset + 1],
+            raw[crc_offset + 2],
+            raw[crc_offset + 3],
+        ]);
+        if expected_crc != crc32_ieee(&raw[..crc_offset]) {
+            return Err(DecodeError::BadCrc);
         }
 
-        let used = self.payload_used();
-        let dropped_samples_total = u32::from_le_bytes([used[0], used[1], used[2], used[3]]);
-        let rx_stall_count_total = u32::from_le_bytes([used[4], used[5], used[6], used[7]]);
-        let sample_bytes = &used[TRACE_METADATA_SIZE..];
-        if sample_bytes.len() % TRACE_PACKED_SAMPLE_SIZE != 0 {
-            return None;
+        Ok(Self {
+            msg_type,
+            seq: u16::from_le_bytes([raw[4], raw[5]]),
+            payload: &raw[HEADER_SIZE..crc_offset],
+        })
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        Packet::new(self.msg_type, self.seq, self.payload).expect("validated payload length")
+    }
+
+    pub fn decode_payload<P: BridgePayload>(&self) -> Result<P, PayloadError> {
+        if self.msg_type != P::MSG_TYPE {
+            return Err(PayloadError::WrongType);
         }
+        P::decode(self.payload)
+    }
+
+    pub fn decode_trace_samples(&self) -> Option<TraceSamples<'a>> {
+        let encoding = TraceEncoding::of(self.msg_type)?;
+        let metadata = TraceMetadata::decode(self.payload).ok()?;
+        let sample_bytes = &self.payload[TraceMetadata::LEN..];
+        let sample_count = encoding.sample_count(sample_bytes)?;
 
         Some(TraceSamples {
-            dropped_samples_total,
-            rx_stall_count_total,
+            dropped_samples_total: metadata.dropped_samples_total,
+            rx_stall_count_total: metadata.rx_stall_count_total,
+            first_sample_us: metadata.first_sample_us,
             sample_bytes,
+            encoding,
+            sample_count,
         })
     }
 }
 
+const TRACE_SAMPLE_RNW: u32 = 1 << 16;
+const TRACE_SAMPLE_CLOCK_HIGH: u32 = 1 << 17;
+
+/// Packs a captured bus word into data, address and RnW. Only completed
+/// FRED phases are captured, so 1MHZE and FRED_N are implied.
 pub fn pack_trace_sample(sample: u32) -> [u8; TRACE_PACKED_SAMPLE_SIZE] {
     [
         (sample & 0xFF) as u8,
         ((sample >> 8) & 0xFF) as u8,
-        ((sample >> 16) & 0xFF) as u8,
+        ((sample & TRACE_SAMPLE_RNW) >> 16) as u8,
     ]
 }
 
+/// Rebuilds a raw capture word with `1MHZE=1` and `FRED_N=0`.
 pub fn unpack_trace_sample(packed: [u8; TRACE_PACKED_SAMPLE_SIZE]) -> u32 {
-    (packed[0] as u32) | ((packed[1] as u32) << 8) | (((packed[2]) as u32) << 16)
-}
-
-pub fn crc32_ieee(data: &[u8]) -> u32 {
-    let mut crc = 0xFFFF_FFFFu32;
-    for &byte in data {
-        crc ^= byte as u32;
-        for _ in 0..8 {
-            if (crc & 1) != 0 {
-                crc = (crc >> 1) ^ 0xEDB8_8320;
-            } else {
-                crc >>= 1;
-            }
-        }
-    }
-    !crc
+    (packed[0] as u32)
+        | ((packed[1] as u32) << 8)
+        | (((packed[2] & 0x01) as u32) << 16)
+        | TRACE_SAMPLE_CLOCK_HIGH
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        crc32_ieee, pack_trace_sample, unpack_trace_sample, DecodeError, MsgType, Packet, CRC_SIZE,
-        HEADER_SIZE, MIN_PACKET_SIZE, PACKET_MAGIC, PROTOCOL_VERSION,
+        crc32_ieee, pack_trace_sample, unpack_trace_sample, BridgePayload, DecodeError,
+        HealthPayload, LinearUnits, MsgType, NackPayload, NackReason, Packet, PacketRef,
+        PayloadError, SnapshotPayload, TelemetryPayload, TraceMetadata, UnitConfig, XAxisMode,
+        CRC_SIZE, HEADER_SIZE, HEALTH_IDLE_NEVER, MIN_PACKET_SIZE, PACKET_MAGIC, PROTOCOL_VERSION,
+        TELEMETRY_FLAG_ENABLED,
     };
 
     fn sample(data: u8, addr: u8, read: bool) -> u32 {
@@ -343,6 +501,98 @@ mod tests {
         assert_eq!(i32::from_le_bytes([p[8], p[9], p[10], p[11]]), 54321);
         assert_eq!(u16::from_le_bytes([p[12], p[13]]), 1800);
         assert_eq!(p[14], 0x03);
+        assert_eq!(
+            got.decode_payload::<TelemetryPayload>(),
+            Ok(TelemetryPayload {
+                tick: 0x1122_3344,
+                x_counts: -12345,
+                z_counts: 54321,
+                rpm: 1800,
+                flags: 0x03,
+            })
+        );
+    }
+
+    #[test]
+    fn unit_cfg_roundtrip() {
+        let config = UnitConfig {
+            units: LinearUnits::Imperial,
+            x_mode: XAxisMode::Radius,
+        };
+        let pkt = Packet::unit_cfg(3, config);
+        let raw = pkt.encode();
+        let got = Packet::decode(&raw[..pkt.encoded_len()]).expect("decode");
+        assert_eq!(got.msg_type, MsgType::UnitCfg);
+        assert_eq!(got.payload_used(), &[1, 1]);
+        assert_eq!(got.decode_payload::<UnitConfig>(), Ok(config));
+        assert_eq!(
+            UnitConfig::from_telemetry_flags(config.telemetry_flags() | TELEMETRY_FLAG_ENABLED),
+            config
+        );
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let req = Packet::snapshot_req(11);
+        assert_eq!(req.msg_type, MsgType::SnapshotReq);
+        assert_eq!(req.payload_len, 0);
+
+        let snapshot = SnapshotPayload {
+            age_ms: 42,
+            sample_index: 0x0102_0304,
+            x_counts: -652,
+            z_counts: 1234,
+            rpm_raw: 783,
+            rpm_display: 780,
+            valid: true,
+            flags: 0x04,
+        };
+        let pkt = Packet::snapshot(11, &snapshot);
+        let raw = pkt.encode();
+        let got = Packet::decode(&raw[..pkt.encoded_len()]).expect("decode");
+        assert_eq!(got.msg_type, MsgType::Snapshot);
+        assert_eq!(got.seq, 11);
+        assert_eq!(got.payload_len as usize, SnapshotPayload::LEN);
+
+        let p = got.payload_used();
+        assert_eq!(u32::from_le_bytes([p[0], p[1], p[2], p[3]]), 42);
+        assert_eq!(u32::from_le_bytes([p[4], p[5], p[6], p[7]]), 0x0102_0304);
+        assert_eq!(i32::from_le_bytes([p[8], p[9], p[10], p[11]]), -652);
+        assert_eq!(i32::from_le_bytes([p[12], p[13], p[14], p[15]]), 1234);
+        assert_eq!(u16::from_le_bytes([p[16], p[17]]), 783);
+        assert_eq!(u16::from_le_bytes([p[18], p[19]]), 780);
+        assert_eq!(p[20], 1);
+        assert_eq!(p[21], 0x04);
+        assert_eq!(got.decode_payload::<SnapshotPayload>(), Ok(snapshot));
+    }
+
+    #[test]
+    fn health_roundtrip() {
+        let set = Packet::health_set(4, true, 1000);
+        assert_eq!(set.msg_type, MsgType::HealthSet);
+        assert_eq!(set.payload_used(), &[1, 0xE8, 0x03]);
+
+        let counters = HealthPayload {
+            queue_drop_count: 17,
+            rx_stall_count: 3,
+            ring_fill: 120,
+            ring_high_water: 16_000,
+            ring_capacity: 16_384,
+            decoded_transactions: 0x0102_0304,
+            decoder_errors: 2,
+            idle_ms: HEALTH_IDLE_NEVER,
+            ..HealthPayload::default()
+        };
+        let pkt = Packet::health(5, &counters);
+        let raw = pkt.encode();
+        let got = Packet::decode(&raw[..pkt.encoded_len()]).expect("decode");
+        assert_eq!(got.msg_type, MsgType::Health);
+        assert_eq!(got.payload_len as usize, HealthPayload::LEN);
+        assert_eq!(got.decode_payload::<HealthPayload>(), Ok(counters));
+        assert_eq!(
+            Packet::ping(1).decode_payload::<HealthPayload>(),
+            Err(PayloadError::WrongType)
+        );
     }
 
     #[test]
@@ -355,27 +605,62 @@ mod tests {
         assert_eq!(capture_got.seq, 0x22);
         assert_eq!(capture_got.payload_used(), &[1]);
 
+        let metadata = TraceMetadata {
+            dropped_samples_total: 7,
+            rx_stall_count_total: 2,
+            first_sample_us: 0x1_0000_0005,
+        };
         let trace = Packet::trace_samples(
             0x33,
-            7,
-            2,
+            &metadata,
             &[sample(0x04, 0x03, false), sample(0x5A, 0xA5, true)],
         );
         let trace_raw = trace.encode();
         let trace_got = Packet::decode(&trace_raw[..trace.encoded_len()]).expect("decode trace");
         assert_eq!(trace_got.msg_type, MsgType::TraceSample);
         assert_eq!(trace_got.seq, 0x33);
-        assert_eq!(trace_got.payload_len, 14);
+        assert_eq!(trace_got.payload_len, 22);
         let trace_decoded = trace_got.decode_trace_samples().expect("trace payload");
         assert_eq!(trace_decoded.dropped_samples_total, 7);
         assert_eq!(trace_decoded.rx_stall_count_total, 2);
+        assert_eq!(trace_decoded.first_sample_us, 0x1_0000_0005);
         assert_eq!(trace_decoded.sample_count(), 2);
+        assert!(!trace_decoded.has_sample_times());
+        assert!(trace_decoded
+            .iter_timed_samples()
+            .map(|s| s.at_us)
+            .eq([Some(0x1_0000_0005), None]));
         let mut samples = trace_decoded.iter_samples();
         assert_eq!(samples.next(), Some(sample(0x04, 0x03, false)));
         assert_eq!(samples.next(), Some(sample(0x5A, 0xA5, true)));
         assert_eq!(samples.next(), None);
     }
 
+    #[test]
+    fn packet_ref_borrows_trace_payload() {
+        let metadata = TraceMetadata {
+            dropped_samples_total: 1,
+            ..TraceMetadata::default()
+        };
+        let trace = Packet::trace_samples(9, &metadata, &[sample(0x12, 0xF1, true)]);
+        let mut raw = [0u8; super::PACKET_SIZE];
+        let n = trace.encode_into(&mut raw);
+        assert_eq!(&raw[..n], &trace.encode()[..trace.encoded_len()]);
+
+        let view = PacketRef::decode(&raw[..n]).expect("decode");
+        assert_eq!(view.msg_type, MsgType::TraceSample);
+        assert_eq!(view.seq, 9);
+        assert_eq!(view.payload().as_ptr(), raw[HEADER_SIZE..].as_ptr());
+        let samples = view.decode_trace_samples().expect("trace payload");
+        assert_eq!(samples.dropped_samples_total, 1);
+        assert!(samples.iter_samples().eq([sample(0x12, 0xF1, true)]));
+        assert_eq!(view.to_packet(), trace);
+        assert_eq!(trace.as_packet_ref(), view);
+
+        raw[HEADER_SIZE] ^= 0x01;
+        assert_eq!(PacketRef::decode(&raw[..n]), Err(DecodeError::BadCrc));
+    }
+
     #[test]
     fn decode_rejects_bad_crc() {
         let pkt = Packet::ack(7, MsgType::Ping, 0);
@@ -409,4 +694,30 @@ mod tests {
         raw[0] = 0x00;
         assert_eq!(Packet::decode(&raw), Err(DecodeError::BadMagic));
     }
+
+    #[test]
+    fn undecodable_nack_echoes_seq_of_an_unknown_type() {
+        let mut raw = Packet::ping(0x0102).encode();
+        let n = Packet::ping(0x0102).encoded_len();
+        raw[2] = 0x7E;
+        let crc = crc32_ieee(&raw[..HEADER_SIZE]);
+        raw[HEADER_SIZE..n].copy_from_slice(&crc.to_le_bytes());
+        let err = Packet::decode(&raw[..n]).expect_err("unknown type");
+        assert_eq!(err, DecodeError::UnknownMsgType);
+        let nack = Packet::undecodable_nack(&raw[..n], err);
+        assert_eq!(nack.seq, 0x0102);
+        assert_eq!(
+            nack.decode_payload(),
+            Ok(NackPayload::new(0xFF, NackReason::Undecodable))
+        );
+
+        // A damaged header cannot be trusted for its seq.
+        raw[HEADER_SIZE] ^= 0x01;
+        assert_eq!(Packet::undecodable_nack(&raw[..n], err).seq, 0);
+        assert_eq!(
+            Packet::undecodable_nack(&raw[..n], DecodeError::BadMagic).seq,
+            0
+        );
+        assert_eq!(Packet::undecodable_nack(&[], DecodeError::PacketLen).seq, 0);
+    }
 }
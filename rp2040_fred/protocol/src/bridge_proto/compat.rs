@@ -0,0 +1,234 @@
+//! Decoders for the fixed-size packets of bridge protocols v1 and v2.
+//!
+//! Both use the v3 header with a `u8` payload length, zero padding up to a
+//! fixed packet size and the CRC in the last four bytes. Their
+//! `TRACE_SAMPLE` payload is bare 4-byte capture words with no metadata, so
+//! a converted batch reports zero drop and stall counters and no timestamp;
+//! [`Downgrade`] lets the caller say so instead of trusting those zeros.
+
+use core::fmt;
+
+use super::{
+    crc32_ieee, pack_trace_sample, DecodeError, MsgType, Packet, TraceMetadata, CRC_SIZE,
+    HEADER_SIZE, PACKET_MAGIC, PAYLOAD_SIZE, PROTOCOL_VERSION, TRACE_PACKED_SAMPLE_SIZE,
+};
+
+pub const V1_PROTOCOL_VERSION: u8 = 1;
+pub const V1_PACKET_SIZE: usize = 32;
+pub const V1_PAYLOAD_SIZE: usize = 20;
+pub const V2_PROTOCOL_VERSION: u8 = 2;
+pub const V2_PACKET_SIZE: usize = 64;
+pub const V2_PAYLOAD_SIZE: usize = V2_PACKET_SIZE - HEADER_SIZE - CRC_SIZE;
+const LEGACY_TRACE_SAMPLE_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyVersion {
+    V1,
+    V2,
+}
+
+impl LegacyVersion {
+    /// The legacy protocol `raw` was sent with, judged by its size and
+    /// version byte alone.
+    pub fn detect(raw: &[u8]) -> Option<Self> {
+        match (raw.len(), raw.get(1).copied()) {
+            (V1_PACKET_SIZE, Some(V1_PROTOCOL_VERSION)) => Some(Self::V1),
+            (V2_PACKET_SIZE, Some(V2_PROTOCOL_VERSION)) => Some(Self::V2),
+            _ => None,
+        }
+    }
+
+    pub fn protocol_version(self) -> u8 {
+        match self {
+            Self::V1 => V1_PROTOCOL_VERSION,
+            Self::V2 => V2_PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn packet_size(self) -> usize {
+        match self {
+            Self::V1 => V1_PACKET_SIZE,
+            Self::V2 => V2_PACKET_SIZE,
+        }
+    }
+
+    pub fn payload_size(self) -> usize {
+        match self {
+            Self::V1 => V1_PAYLOAD_SIZE,
+            Self::V2 => V2_PAYLOAD_SIZE,
+        }
+    }
+}
+
+/// The device answered in a legacy protocol. Transports record the first
+/// one so the caller can warn once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Downgrade {
+    pub version: LegacyVersion,
+    pub first_msg_type: MsgType,
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "device speaks bridge protocol v{} ({}-byte packets), host expects v{PROTOCOL_VERSION}; \
+             trace batches have no drop/stall counters or timestamps",
+            self.version.protocol_version(),
+            self.version.packet_size()
+        )
+    }
+}
+
+/// Checks and converts one legacy packet to its v3 equivalent.
+/// `TRACE_SAMPLE` capture words keep only data, address and RnW, as v3
+/// packing does.
+pub fn decode_legacy(raw: &[u8]) -> Result<(Downgrade, Packet), DecodeError> {
+    if raw.first() != Some(&PACKET_MAGIC) {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = LegacyVersion::detect(raw).ok_or(match raw.get(1) {
+        Some(&V1_PROTOCOL_VERSION | &V2_PROTOCOL_VERSION) => DecodeError::PacketLen,
+        _ => DecodeError::BadVersion,
+    })?;
+
+    let payload_len = raw[3] as usize;
+    if payload_len > version.payload_size() {
+        return Err(DecodeError::PayloadLen);
+    }
+    let crc_offset = raw.len() - CRC_SIZE;
+    let expected_crc = u32::from_le_bytes([
+        raw[crc_offset],
+        raw[crc_offset + 1],
+        raw[crc_offset + 2],
+        raw[crc_offset + 3],
+    ]);
+    if crc32_ieee(&raw[..crc_offset]) != expected_crc {
+        return Err(DecodeError::BadCrc);
+    }
+
+    let msg_type = MsgType::from_u8(raw[2]).ok_or(DecodeError::UnknownMsgType)?;
+    let seq = u16::from_le_bytes([raw[4], raw[5]]);
+    let payload = &raw[HEADER_SIZE..HEADER_SIZE + payload_len];
+    let downgrade = Downgrade {
+        version,
+        first_msg_type: msg_type,
+    };
+
+    if msg_type != MsgType::TraceSample {
+        let pkt = Packet::new(msg_type, seq, payload).ok_or(DecodeError::PayloadLen)?;
+        return Ok((downgrade, pkt));
+    }
+
+    if !payload.len().is_multiple_of(LEGACY_TRACE_SAMPLE_SIZE) {
+        return Err(DecodeError::PayloadLen);
+    }
+    let mut converted = [0u8; PAYLOAD_SIZE];
+    let mut used = TraceMetadata::default().encode(&mut converted);
+    for word in payload.chunks_exact(LEGACY_TRACE_SAMPLE_SIZE) {
+        let sample = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        converted[used..used + TRACE_PACKED_SAMPLE_SIZE]
+            .copy_from_slice(&pack_trace_sample(sample));
+        used += TRACE_PACKED_SAMPLE_SIZE;
+    }
+    let pkt = Packet::new(msg_type, seq, &converted[..used]).ok_or(DecodeError::PayloadLen)?;
+    Ok((downgrade, pkt))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    use super::{decode_legacy, Downgrade, LegacyVersion, V1_PACKET_SIZE, V2_PACKET_SIZE};
+    use crate::bridge_proto::{AckPayload, DecodeError, MsgType};
+
+    /// v1 `ACK` of a `TELEMETRY_SET`, seq 7.
+    const V1_ACK: [u8; V1_PACKET_SIZE] = [
+        0xA5, 0x01, 0x80, 0x02, 0x07, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE9, 0x3A,
+        0xA2, 0x16,
+    ];
+
+    /// v2 `TRACE_SAMPLE`, seq 0x0102, with capture words `0x0003803C` (read
+    /// of 0x3C from FC80, clock high) and `0x0002F055` (write of 0x55 to
+    /// FCF0).
+    const V2_TRACE: [u8; V2_PACKET_SIZE] = [
+        0xA5, 0x02, 0x92, 0x08, 0x02, 0x01, 0x00, 0x00, 0x3C, 0x80, 0x03, 0x00, 0x55, 0xF0, 0x02,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xE0, 0x64, 0xAB, 0x5E,
+    ];
+
+    #[test]
+    fn decodes_v1_golden_ack() {
+        let (downgrade, pkt) = decode_legacy(&V1_ACK).expect("valid v1 packet");
+        assert_eq!(
+            downgrade,
+            Downgrade {
+                version: LegacyVersion::V1,
+                first_msg_type: MsgType::Ack,
+            }
+        );
+        assert_eq!(pkt.seq, 7);
+        assert_eq!(
+            pkt.decode_payload(),
+            Ok(AckPayload {
+                acked_type: MsgType::TelemetrySet,
+                status: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_v2_golden_trace() {
+        let (downgrade, pkt) = decode_legacy(&V2_TRACE).expect("valid v2 packet");
+        assert_eq!(downgrade.version, LegacyVersion::V2);
+        assert_eq!(pkt.seq, 0x0102);
+
+        let trace = pkt.decode_trace_samples().expect("trace payload");
+        assert_eq!(trace.dropped_samples_total, 0);
+        assert_eq!(trace.rx_stall_count_total, 0);
+        assert_eq!(trace.first_sample_us, 0);
+        let samples: Vec<u32> = trace.iter_samples().collect();
+        assert_eq!(samples, [0x0003_803C, 0x0002_F055]);
+
+        assert!(downgrade
+            .to_string()
+            .contains("protocol v2 (64-byte packets)"));
+    }
+
+    #[test]
+    fn rejects_damaged_legacy_packets() {
+        let mut bad_crc = V1_ACK;
+        bad_crc[8] ^= 0x01;
+        assert_eq!(decode_legacy(&bad_crc), Err(DecodeError::BadCrc));
+
+        let mut bad_magic = V1_ACK;
+        bad_magic[0] = 0x5A;
+        assert_eq!(decode_legacy(&bad_magic), Err(DecodeError::BadMagic));
+
+        assert_eq!(decode_legacy(&V2_TRACE[..40]), Err(DecodeError::PacketLen));
+
+        let mut v3 = V1_ACK;
+        v3[1] = 3;
+        assert_eq!(decode_legacy(&v3), Err(DecodeError::BadVersion));
+
+        // The length byte is checked before the CRC so a bad one cannot
+        // index past the payload.
+        let mut too_long = V1_ACK;
+        too_long[3] = 21;
+        assert_eq!(decode_legacy(&too_long), Err(DecodeError::PayloadLen));
+    }
+
+    #[test]
+    fn detects_version_by_size_and_version_byte() {
+        assert_eq!(LegacyVersion::detect(&V1_ACK), Some(LegacyVersion::V1));
+        assert_eq!(LegacyVersion::detect(&V2_TRACE), Some(LegacyVersion::V2));
+        assert_eq!(LegacyVersion::detect(&V2_TRACE[..V1_PACKET_SIZE]), None);
+        assert_eq!(LegacyVersion::detect(&[]), None);
+    }
+}
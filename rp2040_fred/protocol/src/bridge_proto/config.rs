@@ -0,0 +1,120 @@
+//! `CONFIG_GET`, `CONFIG_SET` and `CONFIG` payloads. Keys, value types and
+//! the flash record live in [`crate::config`].
+
+use super::payload::{BridgePayload, PayloadError};
+use super::MsgType;
+use crate::config::{ConfigEntries, ConfigKey};
+
+/// `CONFIG_GET`: one key, or every key when `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfigGetPayload {
+    pub key: Option<ConfigKey>,
+}
+
+impl BridgePayload for ConfigGetPayload {
+    const MSG_TYPE: MsgType = MsgType::ConfigGet;
+    const MIN_LEN: usize = 0;
+    const LEN: usize = 1;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        match self.key {
+            Some(key) => {
+                out[0] = key as u8;
+                1
+            }
+            None => 0,
+        }
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let key = match payload.first() {
+            Some(&v) => Some(ConfigKey::from_u8(v).ok_or(PayloadError::BadValue)?),
+            None => None,
+        };
+        Ok(Self { key })
+    }
+}
+
+/// `CONFIG_SET`: entries to store. The device applies all of them or, on an
+/// unknown key or bad value, none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigSetPayload {
+    pub entries: ConfigEntries,
+}
+
+/// `CONFIG`: the stored values, sent before the `ACK` of `CONFIG_GET`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigPayload {
+    pub entries: ConfigEntries,
+}
+
+macro_rules! config_entries_payload {
+    ($payload:ty, $msg_type:expr) => {
+        impl BridgePayload for $payload {
+            const MSG_TYPE: MsgType = $msg_type;
+            const MIN_LEN: usize = 1;
+            const LEN: usize = ConfigEntries::MAX_ENCODED_LEN;
+
+            fn encode(&self, out: &mut [u8]) -> usize {
+                self.entries.encode(out)
+            }
+
+            fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+                match ConfigEntries::decode(payload) {
+                    Some((entries, used)) if used == payload.len() => Ok(Self { entries }),
+                    _ => Err(PayloadError::Length),
+                }
+            }
+        }
+    };
+}
+
+config_entries_payload!(ConfigSetPayload, MsgType::ConfigSet);
+config_entries_payload!(ConfigPayload, MsgType::Config);
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigGetPayload, ConfigPayload, ConfigSetPayload};
+    use crate::bridge_proto::{BridgePayload, MsgType, Packet, PayloadError};
+    use crate::config::{ConfigEntries, ConfigKey, ConfigValue, DeviceConfig, CONFIG_MAX_ENTRIES};
+
+    #[test]
+    fn config_payloads_roundtrip() {
+        for get in [
+            ConfigGetPayload { key: None },
+            ConfigGetPayload {
+                key: Some(ConfigKey::Units),
+            },
+        ] {
+            let pkt = Packet::from_payload(3, &get);
+            assert_eq!(pkt.msg_type, MsgType::ConfigGet);
+            assert_eq!(pkt.decode_payload(), Ok(get));
+        }
+        assert_eq!(
+            ConfigGetPayload::decode(&[0x7E]),
+            Err(PayloadError::BadValue)
+        );
+
+        let all = ConfigPayload {
+            entries: DeviceConfig::default().entries(),
+        };
+        let pkt = Packet::from_payload(3, &all);
+        assert_eq!(pkt.payload_len as usize, 1 + 5 * ConfigKey::ALL.len());
+        assert_eq!(pkt.decode_payload(), Ok(all));
+
+        let mut entries = ConfigEntries::new();
+        entries.push(ConfigValue::TelemetryPeriodMs(50).into());
+        let set = ConfigSetPayload { entries };
+        let pkt = Packet::from_payload(4, &set);
+        assert_eq!(pkt.decode_payload(), Ok(set));
+        // The count must match the payload length exactly.
+        assert_eq!(
+            ConfigSetPayload::decode(&pkt.payload[..pkt.payload_len as usize - 1]),
+            Err(PayloadError::Length)
+        );
+        assert_eq!(
+            ConfigSetPayload::decode(&[CONFIG_MAX_ENTRIES as u8 + 1]),
+            Err(PayloadError::Length)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! `LOG_SET` and `LOG` payloads. Levels, interned messages and the
+//! on-device ring live in [`crate::device_log`].
+
+use super::payload::{BridgePayload, PayloadError};
+use super::MsgType;
+use crate::device_log::{LogLevel, LogRecord, LOG_TEXT_MAX};
+
+const LOG_HEADER_SIZE: usize = 17;
+
+/// `LOG_SET`: start or stop forwarding log records. Records buffered while
+/// forwarding was off are sent first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogSetPayload {
+    pub enable: bool,
+    /// Most verbose level forwarded; `None` keeps the device's current one
+    /// (`Info` after boot).
+    pub max_level: Option<LogLevel>,
+}
+
+impl BridgePayload for LogSetPayload {
+    const MSG_TYPE: MsgType = MsgType::LogSet;
+    const MIN_LEN: usize = 1;
+    const LEN: usize = 2;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.enable as u8;
+        match self.max_level {
+            Some(level) => {
+                out[1] = level as u8;
+                2
+            }
+            None => 1,
+        }
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let max_level = match payload.get(1) {
+            Some(&v) => Some(LogLevel::from_u8(v).ok_or(PayloadError::BadValue)?),
+            None => None,
+        };
+        Ok(Self {
+            enable: payload[0] != 0,
+            max_level,
+        })
+    }
+}
+
+/// `LOG`: one record.
+///
+/// `level u8 | id u16 | arg u32 | at_us u64 | dropped u16 | text`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogPayload {
+    pub record: LogRecord,
+    /// Records lost to ring overflow since the previous `LOG`, saturating at
+    /// `u16::MAX`. Records filtered out by level are not counted.
+    pub dropped: u16,
+}
+
+impl BridgePayload for LogPayload {
+    const MSG_TYPE: MsgType = MsgType::Log;
+    const MIN_LEN: usize = LOG_HEADER_SIZE;
+    const LEN: usize = LOG_HEADER_SIZE + LOG_TEXT_MAX;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        let r = &self.record;
+        out[0] = r.level as u8;
+        out[1..3].copy_from_slice(&r.id.to_le_bytes());
+        out[3..7].copy_from_slice(&r.arg.to_le_bytes());
+        out[7..15].copy_from_slice(&r.at_us.to_le_bytes());
+        out[15..17].copy_from_slice(&self.dropped.to_le_bytes());
+        let text = r.text_bytes();
+        out[LOG_HEADER_SIZE..LOG_HEADER_SIZE + text.len()].copy_from_slice(text);
+        LOG_HEADER_SIZE + text.len()
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let level = LogLevel::from_u8(payload[0]).ok_or(PayloadError::BadValue)?;
+        let mut at_us = [0u8; 8];
+        at_us.copy_from_slice(&payload[7..15]);
+        let record = LogRecord::from_parts(
+            level,
+            u16::from_le_bytes([payload[1], payload[2]]),
+            u32::from_le_bytes([payload[3], payload[4], payload[5], payload[6]]),
+            u64::from_le_bytes(at_us),
+            &payload[LOG_HEADER_SIZE..],
+        )
+        .ok_or(PayloadError::BadValue)?;
+        Ok(Self {
+            record,
+            dropped: u16::from_le_bytes([payload[15], payload[16]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogPayload, LogSetPayload, LOG_HEADER_SIZE};
+    use crate::bridge_proto::{BridgePayload, MsgType, Packet, PayloadError};
+    use crate::device_log::{LogId, LogLevel, LogRecord, LOG_TEXT_MAX};
+
+    #[test]
+    fn log_payloads_roundtrip() {
+        for set in [
+            LogSetPayload {
+                enable: true,
+                max_level: Some(LogLevel::Debug),
+            },
+            LogSetPayload {
+                enable: false,
+                max_level: None,
+            },
+        ] {
+            let pkt = Packet::from_payload(2, &set);
+            assert_eq!(pkt.msg_type, MsgType::LogSet);
+            assert_eq!(pkt.decode_payload(), Ok(set));
+        }
+        assert_eq!(LogSetPayload::decode(&[1, 9]), Err(PayloadError::BadValue));
+
+        let event = LogPayload {
+            record: LogRecord::event(LogLevel::Warn, LogId::UsbReplyWriteFailed, 0, 1_234_567),
+            dropped: 4,
+        };
+        let pkt = Packet::from_payload(1, &event);
+        assert_eq!(pkt.payload_len as usize, LOG_HEADER_SIZE);
+        assert_eq!(pkt.decode_payload(), Ok(event));
+
+        let text = LogPayload {
+            record: LogRecord::text(LogLevel::Error, &"x".repeat(LOG_TEXT_MAX), 9),
+            dropped: 0,
+        };
+        let pkt = Packet::from_payload(2, &text);
+        assert_eq!(pkt.payload_len as usize, LogPayload::LEN);
+        assert_eq!(pkt.decode_payload(), Ok(text));
+
+        let mut bad_level = [0u8; LOG_HEADER_SIZE];
+        assert_eq!(LogPayload::decode(&bad_level), Err(PayloadError::BadValue));
+        bad_level[0] = LogLevel::Info as u8;
+        assert!(LogPayload::decode(&bad_level).is_ok());
+    }
+}
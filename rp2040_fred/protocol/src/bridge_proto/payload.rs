@@ -0,0 +1,920 @@
+//! Typed payloads for every fixed-layout bridge message.
+//!
+//! Each payload knows its own message type and accepted length range, so the
+//! firmware, host and Python bindings all read and write the same offsets.
+//! Trace batches are variable length and are read through
+//! [`super::TraceSamples`] instead.
+
+use super::MsgType;
+
+pub const TELEMETRY_FLAG_ENABLED: u8 = 1 << 0;
+pub const TELEMETRY_FLAG_BUS_FAULT: u8 = 1 << 1;
+pub const TELEMETRY_FLAG_IMPERIAL: u8 = 1 << 2;
+pub const TELEMETRY_FLAG_RADIUS: u8 = 1 << 5;
+/// An axis value was replaced by the torn-read filter
+/// (`trace_decode::FeedbackFilter`).
+pub const TELEMETRY_FLAG_CORRECTED: u8 = 1 << 4;
+/// `HealthPayload::idle_ms` value when no FRED_N activity has been seen.
+pub const HEALTH_IDLE_NEVER: u32 = u32::MAX;
+/// Trace batch layout: 16-byte `TraceMetadata` (drop and stall counters,
+/// first sample time) then the samples, with byte 2 of each packed sample
+/// holding only RnW. Layout 1 had 8 bytes, without the time, and packed
+/// bits 16-23 of the capture word.
+pub const TRACE_LAYOUT_VERSION: u8 = 2;
+pub const CAPTURE_FLAG_SAMPLE_TIMES: u8 = 1 << 0;
+/// No bus pins are used (mock bus).
+pub const PIN_MAP_NONE: u8 = 0;
+/// Passive sniffer: data GPIO0..7, address GPIO8..15, RnW GPIO16,
+/// 1MHZE GPIO17, FRED_N GPIO20, transceiver control GPIO26..28.
+pub const PIN_MAP_PASSIVE_SNIFFER: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadError {
+    /// The packet carries a different message type.
+    WrongType,
+    /// Payload shorter than `MIN_LEN` or longer than `LEN`.
+    Length,
+    /// A field holds a value outside its defined range.
+    BadValue,
+}
+
+pub trait BridgePayload: Sized {
+    const MSG_TYPE: MsgType;
+    /// Shortest payload `decode` accepts; trailing fields may be optional.
+    const MIN_LEN: usize;
+    /// Full encoded length.
+    const LEN: usize;
+
+    /// Writes the payload into `out` and returns the number of bytes used.
+    /// `out` must be at least `LEN` bytes.
+    fn encode(&self, out: &mut [u8]) -> usize;
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError>;
+
+    fn decode(payload: &[u8]) -> Result<Self, PayloadError> {
+        if payload.len() < Self::MIN_LEN || payload.len() > Self::LEN {
+            return Err(PayloadError::Length);
+        }
+        Self::decode_fields(payload)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TelemetrySetPayload {
+    pub enable: bool,
+    /// `None` keeps the device's current period.
+    pub period_ms: Option<u16>,
+}
+
+impl BridgePayload for TelemetrySetPayload {
+    const MSG_TYPE: MsgType = MsgType::TelemetrySet;
+    const MIN_LEN: usize = 1;
+    const LEN: usize = 3;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        encode_enable_period(self.enable, self.period_ms, out)
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let (enable, period_ms) = decode_enable_period(payload)?;
+        Ok(Self { enable, period_ms })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HealthSetPayload {
+    pub enable: bool,
+    /// `None` keeps the device's current period.
+    pub period_ms: Option<u16>,
+}
+
+impl BridgePayload for HealthSetPayload {
+    const MSG_TYPE: MsgType = MsgType::HealthSet;
+    const MIN_LEN: usize = 1;
+    const LEN: usize = 3;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        encode_enable_period(self.enable, self.period_ms, out)
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let (enable, period_ms) = decode_enable_period(payload)?;
+        Ok(Self { enable, period_ms })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureSetPayload {
+    pub enable: bool,
+    /// Send `TRACE_SAMPLE_TIMED` batches with a delta for every sample.
+    pub sample_times: bool,
+}
+
+impl BridgePayload for CaptureSetPayload {
+    const MSG_TYPE: MsgType = MsgType::CaptureSet;
+    const MIN_LEN: usize = 1;
+    const LEN: usize = 2;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.enable as u8;
+        if !self.sample_times {
+            return 1;
+        }
+        out[1] = CAPTURE_FLAG_SAMPLE_TIMES;
+        2
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let flags = payload.get(1).copied().unwrap_or(0);
+        Ok(Self {
+            enable: payload[0] != 0,
+            sample_times: flags & CAPTURE_FLAG_SAMPLE_TIMES != 0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MockSetPayload {
+    pub enable: bool,
+}
+
+impl BridgePayload for MockSetPayload {
+    const MSG_TYPE: MsgType = MsgType::MockSet;
+    const MIN_LEN: usize = 1;
+    const LEN: usize = 1;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.enable as u8;
+        1
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        Ok(Self {
+            enable: payload[0] != 0,
+        })
+    }
+}
+
+/// CNCMAN `N%` selection: `100` for metric, `2540` for imperial.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinearUnits {
+    #[default]
+    Metric = 0,
+    Imperial = 1,
+}
+
+impl LinearUnits {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Metric),
+            1 => Some(Self::Imperial),
+            _ => None,
+        }
+    }
+
+    pub fn cncman_n(&self) -> u16 {
+        match self {
+            Self::Metric => 100,
+            Self::Imperial => 2540,
+        }
+    }
+}
+
+/// X presentation. CNCMAN doubles the lathe's radius reading by default.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum XAxisMode {
+    #[default]
+    Diameter = 0,
+    Radius = 1,
+}
+
+impl XAxisMode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Diameter),
+            1 => Some(Self::Radius),
+            _ => None,
+        }
+    }
+}
+
+/// `UNIT_CFG` payload. The X mode byte is optional so that the original
+/// one-byte `units` payload is still accepted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnitConfig {
+    pub units: LinearUnits,
+    pub x_mode: XAxisMode,
+}
+
+impl UnitConfig {
+    pub fn from_telemetry_flags(flags: u8) -> Self {
+        Self {
+            units: if flags & TELEMETRY_FLAG_IMPERIAL != 0 {
+                LinearUnits::Imperial
+            } else {
+                LinearUnits::Metric
+            },
+            x_mode: if flags & TELEMETRY_FLAG_RADIUS != 0 {
+                XAxisMode::Radius
+            } else {
+                XAxisMode::Diameter
+            },
+        }
+    }
+
+    pub fn telemetry_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.units == LinearUnits::Imperial {
+            flags |= TELEMETRY_FLAG_IMPERIAL;
+        }
+        if self.x_mode == XAxisMode::Radius {
+            flags |= TELEMETRY_FLAG_RADIUS;
+        }
+        flags
+    }
+}
+
+impl BridgePayload for UnitConfig {
+    const MSG_TYPE: MsgType = MsgType::UnitCfg;
+    const MIN_LEN: usize = 1;
+    const LEN: usize = 2;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.units as u8;
+        out[1] = self.x_mode as u8;
+        2
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let units = LinearUnits::from_u8(payload[0]).ok_or(PayloadError::BadValue)?;
+        let x_mode = match payload.get(1) {
+            Some(&v) => XAxisMode::from_u8(v).ok_or(PayloadError::BadValue)?,
+            None => XAxisMode::Diameter,
+        };
+        Ok(Self { units, x_mode })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AckPayload {
+    pub acked_type: MsgType,
+    pub status: u8,
+}
+
+impl BridgePayload for AckPayload {
+    const MSG_TYPE: MsgType = MsgType::Ack;
+    const MIN_LEN: usize = 2;
+    const LEN: usize = 2;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.acked_type as u8;
+        out[1] = self.status;
+        2
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        Ok(Self {
+            acked_type: MsgType::from_u8(payload[0]).ok_or(PayloadError::BadValue)?,
+            status: payload[1],
+        })
+    }
+}
+
+/// Why the device refused a request, carried in `NACK.reason`.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NackReason {
+    /// The payload was too short or held an out-of-range value.
+    InvalidPayload = 0x01,
+    /// The request failed magic, version, length or CRC checks, so its type
+    /// is unknown (`rejected_type = 0xFF`).
+    Undecodable = 0x02,
+    /// The PIO transport does not handle this type while passive capture is on.
+    CaptureActive = 0x10,
+    /// The PIO transport does not handle this type while passive capture is off.
+    CaptureInactive = 0x11,
+    /// The configuration could not be written to flash.
+    Storage = 0x20,
+    /// The active transport does not implement this type at all.
+    Unsupported = 0xFE,
+}
+
+impl NackReason {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0x01 => Some(Self::InvalidPayload),
+            0x02 => Some(Self::Undecodable),
+            0x10 => Some(Self::CaptureActive),
+            0x11 => Some(Self::CaptureInactive),
+            0x20 => Some(Self::Storage),
+            0xFE => Some(Self::Unsupported),
+            _ => None,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::InvalidPayload => "payload too short or out of range",
+            Self::Undecodable => "request packet could not be decoded",
+            Self::CaptureActive => "not available while passive capture is on",
+            Self::CaptureInactive => "not available while passive capture is off",
+            Self::Storage => "configuration could not be saved to flash",
+            Self::Unsupported => "not supported by this transport",
+        }
+    }
+}
+
+/// Both fields stay raw bytes: the device also NACKs requests it could not
+/// decode at all (`0xFF`), and newer firmware may add reasons.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NackPayload {
+    pub rejected_type: u8,
+    pub reason: u8,
+}
+
+impl NackPayload {
+    pub fn new(rejected_type: u8, reason: NackReason) -> Self {
+        Self {
+            rejected_type,
+            reason: reason as u8,
+        }
+    }
+
+    /// `None` for reason codes this build does not know.
+    pub fn nack_reason(&self) -> Option<NackReason> {
+        NackReason::from_u8(self.reason)
+    }
+}
+
+impl BridgePayload for NackPayload {
+    const MSG_TYPE: MsgType = MsgType::Nack;
+    const MIN_LEN: usize = 2;
+    const LEN: usize = 2;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.rejected_type;
+        out[1] = self.reason;
+        2
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        Ok(Self {
+            rejected_type: payload[0],
+            reason: payload[1],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TelemetryPayload {
+    pub tick: u32,
+    pub x_counts: i32,
+    pub z_counts: i32,
+    pub rpm: u16,
+    pub flags: u8,
+}
+
+impl BridgePayload for TelemetryPayload {
+    const MSG_TYPE: MsgType = MsgType::Telemetry;
+    const MIN_LEN: usize = 16;
+    const LEN: usize = 16;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0..4].copy_from_slice(&self.tick.to_le_bytes());
+        out[4..8].copy_from_slice(&self.x_counts.to_le_bytes());
+        out[8..12].copy_from_slice(&self.z_counts.to_le_bytes());
+        out[12..14].copy_from_slice(&self.rpm.to_le_bytes());
+        out[14] = self.flags;
+        out[15] = 0;
+        Self::LEN
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        Ok(Self {
+            tick: read_u32(payload, 0),
+            x_counts: read_i32(payload, 4),
+            z_counts: read_i32(payload, 8),
+            rpm: read_u16(payload, 12),
+            flags: payload[14],
+        })
+    }
+}
+
+/// Reply to `SNAPSHOT_REQ`. `flags` uses the `TELEMETRY.flags` bit layout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotPayload {
+    /// Time since the snapshot was last decoded.
+    pub age_ms: u32,
+    /// Low 32 bits of the decoder sample index.
+    pub sample_index: u32,
+    pub x_counts: i32,
+    pub z_counts: i32,
+    pub rpm_raw: u16,
+    pub rpm_display: u16,
+    pub valid: bool,
+    pub flags: u8,
+}
+
+impl BridgePayload for SnapshotPayload {
+    const MSG_TYPE: MsgType = MsgType::Snapshot;
+    const MIN_LEN: usize = 22;
+    const LEN: usize = 22;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0..4].copy_from_slice(&self.age_ms.to_le_bytes());
+        out[4..8].copy_from_slice(&self.sample_index.to_le_bytes());
+        out[8..12].copy_from_slice(&self.x_counts.to_le_bytes());
+        out[12..16].copy_from_slice(&self.z_counts.to_le_bytes());
+        out[16..18].copy_from_slice(&self.rpm_raw.to_le_bytes());
+        out[18..20].copy_from_slice(&self.rpm_display.to_le_bytes());
+        out[20] = self.valid as u8;
+        out[21] = self.flags;
+        Self::LEN
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        Ok(Self {
+            age_ms: read_u32(payload, 0),
+            sample_index: read_u32(payload, 4),
+            x_counts: read_i32(payload, 8),
+            z_counts: read_i32(payload, 12),
+            rpm_raw: read_u16(payload, 16),
+            rpm_display: read_u16(payload, 18),
+            valid: payload[20] != 0,
+            flags: payload[21],
+        })
+    }
+}
+
+/// Why FC80/FCF1 traffic was not turned into DRO values. Each counter
+/// wraps; a rising count in a capture usually means the sniffer dropped
+/// samples mid-transaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecoderStats {
+    /// Digit responses that were not packed BCD.
+    pub invalid_bcd: u32,
+    /// FCF1 reads with no FC80 command in front of them.
+    pub orphan_responses: u32,
+    /// Responses to commands outside the feedback cadence.
+    pub unknown_commands: u32,
+    /// FC80 commands replaced by another before their response was read.
+    pub overwritten_pending: u32,
+    /// Torn cadences: see `trace_decode::CadenceTracker::torn_cycles`.
+    pub incomplete_cycles: u32,
+}
+
+impl DecoderStats {
+    pub const ZERO: Self = Self {
+        invalid_bcd: 0,
+        orphan_responses: 0,
+        unknown_commands: 0,
+        overwritten_pending: 0,
+        incomplete_cycles: 0,
+    };
+
+    /// All counters together, as `HEALTH` reports `decoder_errors`.
+    pub fn total(&self) -> u32 {
+        self.invalid_bcd
+            .wrapping_add(self.orphan_responses)
+            .wrapping_add(self.unknown_commands)
+            .wrapping_add(self.overwritten_pending)
+            .wrapping_add(self.incomplete_cycles)
+    }
+}
+
+/// Sniffer and decoder counters reported in `HEALTH`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HealthPayload {
+    pub queue_drop_count: u32,
+    pub rx_stall_count: u32,
+    pub ring_fill: u16,
+    pub ring_high_water: u16,
+    pub ring_capacity: u16,
+    pub decoded_transactions: u32,
+    /// [`DecoderStats::total`] of `decoder_stats`.
+    pub decoder_errors: u32,
+    pub idle_ms: u32,
+    /// All zero from firmware that sends only the first 26 bytes.
+    pub decoder_stats: DecoderStats,
+}
+
+impl BridgePayload for HealthPayload {
+    const MSG_TYPE: MsgType = MsgType::Health;
+    const MIN_LEN: usize = 26;
+    const LEN: usize = 46;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0..4].copy_from_slice(&self.queue_drop_count.to_le_bytes());
+        out[4..8].copy_from_slice(&self.rx_stall_count.to_le_bytes());
+        out[8..10].copy_from_slice(&self.ring_fill.to_le_bytes());
+        out[10..12].copy_from_slice(&self.ring_high_water.to_le_bytes());
+        out[12..14].copy_from_slice(&self.ring_capacity.to_le_bytes());
+        out[14..18].copy_from_slice(&self.decoded_transactions.to_le_bytes());
+        out[18..22].copy_from_slice(&self.decoder_errors.to_le_bytes());
+        out[22..26].copy_from_slice(&self.idle_ms.to_le_bytes());
+        let stats = &self.decoder_stats;
+        out[26..30].copy_from_slice(&stats.invalid_bcd.to_le_bytes());
+        out[30..34].copy_from_slice(&stats.orphan_responses.to_le_bytes());
+        out[34..38].copy_from_slice(&stats.unknown_commands.to_le_bytes());
+        out[38..42].copy_from_slice(&stats.overwritten_pending.to_le_bytes());
+        out[42..46].copy_from_slice(&stats.incomplete_cycles.to_le_bytes());
+        Self::LEN
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        Ok(Self {
+            queue_drop_count: read_u32(payload, 0),
+            rx_stall_count: read_u32(payload, 4),
+            ring_fill: read_u16(payload, 8),
+            ring_high_water: read_u16(payload, 10),
+            ring_capacity: read_u16(payload, 12),
+            decoded_transactions: read_u32(payload, 14),
+            decoder_errors: read_u32(payload, 18),
+            idle_ms: read_u32(payload, 22),
+            decoder_stats: match payload.len() {
+                Self::LEN => DecoderStats {
+                    invalid_bcd: read_u32(payload, 26),
+                    orphan_responses: read_u32(payload, 30),
+                    unknown_commands: read_u32(payload, 34),
+                    overwritten_pending: read_u32(payload, 38),
+                    incomplete_cycles: read_u32(payload, 42),
+                },
+                Self::MIN_LEN => DecoderStats::ZERO,
+                _ => return Err(PayloadError::Length),
+            },
+        })
+    }
+}
+
+/// Bus source compiled into the firmware.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceTransport {
+    MockBus = 0,
+    PioReal = 1,
+}
+
+impl DeviceTransport {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::MockBus),
+            1 => Some(Self::PioReal),
+            _ => None,
+        }
+    }
+
+    /// Cargo feature name of the transport.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MockBus => "mock-bus",
+            Self::PioReal => "pio-real",
+        }
+    }
+}
+
+/// Bitmap over all 256 message type codes. Codes the host does not know are
+/// kept, so a newer device can still be described by an older host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MsgTypeSet([u8; 32]);
+
+impl MsgTypeSet {
+    pub const LEN: usize = 32;
+
+    pub const fn from_types(types: &[MsgType]) -> Self {
+        let mut bits = [0u8; Self::LEN];
+        let mut i = 0;
+        while i < types.len() {
+            let code = types[i] as u8;
+            bits[(code >> 3) as usize] |= 1 << (code & 7);
+            i += 1;
+        }
+        Self(bits)
+    }
+
+    pub fn contains(&self, msg_type: MsgType) -> bool {
+        self.contains_code(msg_type as u8)
+    }
+
+    pub fn contains_code(&self, code: u8) -> bool {
+        self.0[(code >> 3) as usize] & (1 << (code & 7)) != 0
+    }
+
+    /// Known message types in code order.
+    pub fn iter(&self) -> impl Iterator<Item = MsgType> + '_ {
+        (0..=u8::MAX)
+            .filter(|&code| self.contains_code(code))
+            .filter_map(MsgType::from_u8)
+    }
+}
+
+/// Reply to `HELLO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfoPayload {
+    /// Firmware crate version, major/minor/patch.
+    pub firmware_version: [u8; 3],
+    /// Short git hash, ASCII, NUL padded.
+    pub git_hash: [u8; 8],
+    pub transport: DeviceTransport,
+    pub protocol_version: u8,
+    pub max_payload: u16,
+    pub trace_layout_version: u8,
+    pub pin_map_id: u8,
+    pub supported: MsgTypeSet,
+}
+
+impl DeviceInfoPayload {
+    pub fn supports(&self, msg_type: MsgType) -> bool {
+        self.supported.contains(msg_type)
+    }
+
+    pub fn git_hash_str(&self) -> &str {
+        let end = self
+            .git_hash
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.git_hash.len());
+        core::str::from_utf8(&self.git_hash[..end]).unwrap_or("?")
+    }
+}
+
+impl BridgePayload for DeviceInfoPayload {
+    const MSG_TYPE: MsgType = MsgType::DeviceInfo;
+    const MIN_LEN: usize = 17 + MsgTypeSet::LEN;
+    const LEN: usize = 17 + MsgTypeSet::LEN;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0..3].copy_from_slice(&self.firmware_version);
+        out[3..11].copy_from_slice(&self.git_hash);
+        out[11] = self.transport as u8;
+        out[12] = self.protocol_version;
+        out[13..15].copy_from_slice(&self.max_payload.to_le_bytes());
+        out[15] = self.trace_layout_version;
+        out[16] = self.pin_map_id;
+        out[17..Self::LEN].copy_from_slice(&self.supported.0);
+        Self::LEN
+    }
+
+    fn decode_fields(payload: &[u8]) -> Result<Self, PayloadError> {
+        let mut firmware_version = [0u8; 3];
+        firmware_version.copy_from_slice(&payload[0..3]);
+        let mut git_hash = [0u8; 8];
+        git_hash.copy_from_slice(&payload[3..11]);
+        let mut supported = [0u8; MsgTypeSet::LEN];
+        supported.copy_from_slice(&payload[17..Self::LEN]);
+        Ok(Self {
+            firmware_version,
+            git_hash,
+            transport: DeviceTransport::from_u8(payload[11]).ok_or(PayloadError::BadValue)?,
+            protocol_version: payload[12],
+            max_payload: read_u16(payload, 13),
+            trace_layout_version: payload[15],
+            pin_map_id: payload[16],
+            supported: MsgTypeSet(supported),
+        })
+    }
+}
+
+/// Fixed prefix of every trace payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceMetadata {
+    pub dropped_samples_total: u32,
+    pub rx_stall_count_total: u32,
+    /// Device timer (µs since boot) when the first sample was captured.
+    pub first_sample_us: u64,
+}
+
+impl TraceMetadata {
+    pub const LEN: usize = 16;
+
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[0..4].copy_from_slice(&self.dropped_samples_total.to_le_bytes());
+        out[4..8].copy_from_slice(&self.rx_stall_count_total.to_le_bytes());
+        out[8..16].copy_from_slice(&self.first_sample_us.to_le_bytes());
+        Self::LEN
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PayloadError> {
+        if payload.len() < Self::LEN {
+            return Err(PayloadError::Length);
+        }
+        Ok(Self {
+            dropped_samples_total: read_u32(payload, 0),
+            rx_stall_count_total: read_u32(payload, 4),
+            first_sample_us: read_u32(payload, 8) as u64 | (read_u32(payload, 12) as u64) << 32,
+        })
+    }
+}
+
+fn encode_enable_period(enable: bool, period_ms: Option<u16>, out: &mut [u8]) -> usize {
+    out[0] = enable as u8;
+    match period_ms {
+        Some(period_ms) => {
+            out[1..3].copy_from_slice(&period_ms.to_le_bytes());
+            3
+        }
+        None => 1,
+    }
+}
+
+/// The period is either absent or a full `u16`; a lone trailing byte is
+/// rejected rather than guessed at.
+fn decode_enable_period(payload: &[u8]) -> Result<(bool, Option<u16>), PayloadError> {
+    let period_ms = match payload.len() {
+        1 => None,
+        3 => Some(read_u16(payload, 1)),
+        _ => return Err(PayloadError::Length),
+    };
+    Ok((payload[0] != 0, period_ms))
+}
+
+fn read_u16(p: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([p[at], p[at + 1]])
+}
+
+fn read_u32(p: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([p[at], p[at + 1], p[at + 2], p[at + 3]])
+}
+
+fn read_i32(p: &[u8], at: usize) -> i32 {
+    read_u32(p, at) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AckPayload, BridgePayload, CaptureSetPayload, DecoderStats, DeviceInfoPayload,
+        DeviceTransport, HealthPayload, HealthSetPayload, LinearUnits, MsgTypeSet, NackPayload,
+        NackReason, PayloadError, SnapshotPayload, TelemetryPayload, TelemetrySetPayload,
+        UnitConfig, XAxisMode, HEALTH_IDLE_NEVER, PIN_MAP_PASSIVE_SNIFFER, TELEMETRY_FLAG_ENABLED,
+        TRACE_LAYOUT_VERSION,
+    };
+    use crate::bridge_proto::MsgType;
+
+    fn roundtrip<P: BridgePayload + PartialEq + core::fmt::Debug>(payload: P) {
+        let mut buf = [0u8; 64];
+        let n = payload.encode(&mut buf);
+        assert!(n >= P::MIN_LEN && n <= P::LEN);
+        assert_eq!(P::decode(&buf[..n]), Ok(payload));
+        assert_eq!(P::decode(&buf[..P::MIN_LEN - 1]), Err(PayloadError::Length));
+        assert_eq!(P::decode(&buf[..P::LEN + 1]), Err(PayloadError::Length));
+    }
+
+    #[test]
+    fn every_payload_roundtrips_and_checks_length() {
+        roundtrip(TelemetrySetPayload {
+            enable: true,
+            period_ms: Some(250),
+        });
+        roundtrip(HealthSetPayload {
+            enable: false,
+            period_ms: None,
+        });
+        roundtrip(CaptureSetPayload {
+            enable: true,
+            sample_times: true,
+        });
+        roundtrip(UnitConfig {
+            units: LinearUnits::Imperial,
+            x_mode: XAxisMode::Radius,
+        });
+        roundtrip(AckPayload {
+            acked_type: MsgType::UnitCfg,
+            status: 0,
+        });
+        roundtrip(NackPayload::new(0xFF, NackReason::Undecodable));
+        roundtrip(TelemetryPayload {
+            tick: 0x1122_3344,
+            x_counts: -12345,
+            z_counts: 54321,
+            rpm: 1800,
+            flags: TELEMETRY_FLAG_ENABLED,
+        });
+        roundtrip(SnapshotPayload {
+            age_ms: 42,
+            sample_index: 0x0102_0304,
+            x_counts: -652,
+            z_counts: 1234,
+            rpm_raw: 783,
+            rpm_display: 780,
+            valid: true,
+            flags: 0x04,
+        });
+        roundtrip(HealthPayload {
+            queue_drop_count: 17,
+            rx_stall_count: 3,
+            ring_fill: 120,
+            ring_high_water: 16_000,
+            ring_capacity: 16_384,
+            decoded_transactions: 0x0102_0304,
+            decoder_errors: 6,
+            idle_ms: HEALTH_IDLE_NEVER,
+            decoder_stats: DecoderStats {
+                invalid_bcd: 1,
+                orphan_responses: 2,
+                unknown_commands: 0,
+                overwritten_pending: 2,
+                incomplete_cycles: 1,
+            },
+        });
+    }
+
+    #[test]
+    fn short_health_from_older_firmware_has_no_decoder_stats() {
+        let health = HealthPayload {
+            decoded_transactions: 40,
+            decoder_errors: 3,
+            decoder_stats: DecoderStats {
+                orphan_responses: 3,
+                ..DecoderStats::ZERO
+            },
+            ..HealthPayload::default()
+        };
+        let mut buf = [0u8; HealthPayload::LEN];
+        health.encode(&mut buf);
+
+        let old = HealthPayload::decode(&buf[..HealthPayload::MIN_LEN]).expect("26-byte health");
+        assert_eq!(old.decoder_errors, 3);
+        assert_eq!(old.decoder_stats, DecoderStats::ZERO);
+        assert_eq!(
+            HealthPayload::decode(&buf[..HealthPayload::MIN_LEN + 4]),
+            Err(PayloadError::Length)
+        );
+    }
+
+    #[test]
+    fn device_info_roundtrips_supported_types() {
+        let info = DeviceInfoPayload {
+            firmware_version: [0, 1, 0],
+            git_hash: *b"1a2b3c4d",
+            transport: DeviceTransport::PioReal,
+            protocol_version: 3,
+            max_payload: 305,
+            trace_layout_version: TRACE_LAYOUT_VERSION,
+            pin_map_id: PIN_MAP_PASSIVE_SNIFFER,
+            supported: MsgTypeSet::from_types(&[MsgType::Ping, MsgType::Hello, MsgType::Telemetry]),
+        };
+        roundtrip(info);
+        assert_eq!(info.git_hash_str(), "1a2b3c4d");
+        assert!(info.supports(MsgType::Telemetry));
+        assert!(!info.supports(MsgType::SnapshotReq));
+        assert!(info
+            .supported
+            .iter()
+            .eq([MsgType::Ping, MsgType::Hello, MsgType::Telemetry]));
+
+        let mut buf = [0u8; DeviceInfoPayload::LEN];
+        info.encode(&mut buf);
+        buf[11] = 9;
+        assert_eq!(DeviceInfoPayload::decode(&buf), Err(PayloadError::BadValue));
+    }
+
+    #[test]
+    fn enable_period_rejects_partial_period() {
+        assert_eq!(
+            TelemetrySetPayload::decode(&[1, 0x10]),
+            Err(PayloadError::Length)
+        );
+        assert_eq!(TelemetrySetPayload::MSG_TYPE, MsgType::TelemetrySet);
+        assert_eq!(HealthSetPayload::MSG_TYPE, MsgType::HealthSet);
+    }
+
+    #[test]
+    fn unit_cfg_accepts_units_only_payload() {
+        assert_eq!(
+            UnitConfig::decode(&[1]),
+            Ok(UnitConfig {
+                units: LinearUnits::Imperial,
+                x_mode: XAxisMode::Diameter,
+            })
+        );
+        assert_eq!(UnitConfig::decode(&[]), Err(PayloadError::Length));
+        assert_eq!(UnitConfig::decode(&[2]), Err(PayloadError::BadValue));
+        assert_eq!(UnitConfig::decode(&[0, 7]), Err(PayloadError::BadValue));
+        assert_eq!(UnitConfig::default().telemetry_flags(), 0);
+    }
+
+    #[test]
+    fn ack_rejects_unknown_type() {
+        assert_eq!(AckPayload::decode(&[0x7E, 0]), Err(PayloadError::BadValue));
+    }
+
+    #[test]
+    fn nack_reason_codes_are_stable() {
+        for (reason, code) in [
+            (NackReason::InvalidPayload, 0x01),
+            (NackReason::Undecodable, 0x02),
+            (NackReason::CaptureActive, 0x10),
+            (NackReason::CaptureInactive, 0x11),
+            (NackReason::Storage, 0x20),
+            (NackReason::Unsupported, 0xFE),
+        ] {
+            assert_eq!(reason as u8, code);
+            assert_eq!(NackReason::from_u8(code), Some(reason));
+        }
+        let future = NackPayload::decode(&[MsgType::Ping as u8, 0x42]).expect("nack");
+        assert_eq!(future.reason, 0x42);
+        assert_eq!(future.nack_reason(), None);
+    }
+}
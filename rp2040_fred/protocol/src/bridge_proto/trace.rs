@@ -0,0 +1,444 @@
+//! Run-length encoded trace batches (`TRACE_SAMPLE_COMPRESSED`).
+//!
+//! Most of a capture is the DRO busy-polling FCF0, which yields long runs of
+//! identical packed samples. A compressed payload is the usual
+//! `TraceMetadata` followed by `[packed sample; 3][u8 run]` entries.
+
+use super::{
+    pack_trace_sample, unpack_trace_sample, MsgType, Packet, TraceMetadata, PAYLOAD_SIZE,
+    TRACE_METADATA_SIZE, TRACE_PACKED_SAMPLE_SIZE, TRACE_SAMPLES_PER_PACKET,
+};
+
+pub const TRACE_RUN_SIZE: usize = TRACE_PACKED_SAMPLE_SIZE + 1;
+pub const TRACE_RUNS_PER_PACKET: usize = (PAYLOAD_SIZE - TRACE_METADATA_SIZE) / TRACE_RUN_SIZE;
+pub const TRACE_RUN_MAX: usize = u8::MAX as usize;
+pub const TRACE_COMPRESSED_MAX_SAMPLES: usize = TRACE_RUNS_PER_PACKET * TRACE_RUN_MAX;
+/// Packed sample plus `u16` µs since the previous sample.
+pub const TRACE_TIMED_SAMPLE_SIZE: usize = TRACE_PACKED_SAMPLE_SIZE + 2;
+pub const TRACE_TIMED_SAMPLES_PER_PACKET: usize =
+    (PAYLOAD_SIZE - TRACE_METADATA_SIZE) / TRACE_TIMED_SAMPLE_SIZE;
+
+const BODY_BYTES: usize = PAYLOAD_SIZE - TRACE_METADATA_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum TraceEncoding {
+    Packed,
+    Runs,
+    Timed,
+}
+
+impl TraceEncoding {
+    pub(super) fn of(msg_type: MsgType) -> Option<Self> {
+        match msg_type {
+            MsgType::TraceSample => Some(Self::Packed),
+            MsgType::TraceSampleCompressed => Some(Self::Runs),
+            MsgType::TraceSampleTimed => Some(Self::Timed),
+            _ => None,
+        }
+    }
+
+    fn entry_size(self) -> usize {
+        match self {
+            Self::Packed => TRACE_PACKED_SAMPLE_SIZE,
+            Self::Runs => TRACE_RUN_SIZE,
+            Self::Timed => TRACE_TIMED_SAMPLE_SIZE,
+        }
+    }
+
+    /// Total sample count of a trace body, or `None` if it is malformed.
+    pub(super) fn sample_count(self, body: &[u8]) -> Option<usize> {
+        if !body.len().is_multiple_of(self.entry_size()) {
+            return None;
+        }
+        if self != Self::Runs {
+            return Some(body.len() / self.entry_size());
+        }
+        let mut total = 0usize;
+        for run in body.chunks_exact(TRACE_RUN_SIZE) {
+            let count = run[TRACE_PACKED_SAMPLE_SIZE];
+            if count == 0 {
+                return None;
+            }
+            total += count as usize;
+        }
+        Some(total)
+    }
+}
+
+/// Accumulates captured samples for one trace packet.
+///
+/// By default both `TRACE_SAMPLE` and `TRACE_SAMPLE_COMPRESSED` bodies are
+/// built side by side and `finish` emits the smaller one; `push` keeps
+/// accepting samples while either still fits. With sample times enabled the
+/// batch is `TRACE_SAMPLE_TIMED` instead.
+pub struct TraceBatchEncoder {
+    raw: [u8; BODY_BYTES],
+    runs: [u8; BODY_BYTES],
+    run_bytes: usize,
+    runs_overflowed: bool,
+    sample_count: usize,
+    sample_times: bool,
+    first_sample_us: u64,
+    last_sample_us: u64,
+}
+
+impl Default for TraceBatchEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceBatchEncoder {
+    pub const fn new() -> Self {
+        Self {
+            raw: [0u8; BODY_BYTES],
+            runs: [0u8; BODY_BYTES],
+            run_bytes: 0,
+            runs_overflowed: false,
+            sample_count: 0,
+            sample_times: false,
+            first_sample_us: 0,
+            last_sample_us: 0,
+        }
+    }
+
+    /// Selects per-sample deltas. Must only be changed between batches.
+    pub fn set_sample_times(&mut self, enable: bool) {
+        debug_assert!(self.is_empty());
+        self.sample_times = enable;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sample_count == 0
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.sample_count
+    }
+
+    /// Adds one sample captured at device time `at_us`. Returns `false`,
+    /// leaving the batch untouched, once it has no room for the sample.
+    pub fn push(&mut self, sample: u32, at_us: u64) -> bool {
+        let accepted = if self.sample_times {
+            self.push_timed(sample, at_us)
+        } else {
+            self.push_untimed(sample)
+        };
+        if accepted {
+            if self.sample_count == 0 {
+                self.first_sample_us = at_us;
+            }
+            self.last_sample_us = at_us;
+            self.sample_count += 1;
+        }
+        accepted
+    }
+
+    fn push_timed(&mut self, sample: u32, at_us: u64) -> bool {
+        if self.sample_count == TRACE_TIMED_SAMPLES_PER_PACKET {
+            return false;
+        }
+        let delta = if self.sample_count == 0 {
+            0
+        } else {
+            // A gap too long for the delta field starts a new batch.
+            match u16::try_from(at_us.wrapping_sub(self.last_sample_us)) {
+                Ok(delta) => delta,
+                Err(_) => return false,
+            }
+        };
+
+        let at = self.sample_count * TRACE_TIMED_SAMPLE_SIZE;
+        self.raw[at..at + TRACE_PACKED_SAMPLE_SIZE].copy_from_slice(&pack_trace_sample(sample));
+        self.raw[at + TRACE_PACKED_SAMPLE_SIZE..at + TRACE_TIMED_SAMPLE_SIZE]
+            .copy_from_slice(&delta.to_le_bytes());
+        true
+    }
+
+    fn push_untimed(&mut self, sample: u32) -> bool {
+        let packed = pack_trace_sample(sample);
+        let raw_fits = self.sample_count < TRACE_SAMPLES_PER_PACKET;
+        let extends_run = !self.runs_overflowed && self.run_bytes > 0 && {
+            let last = &self.runs[self.run_bytes - TRACE_RUN_SIZE..self.run_bytes];
+            last[..TRACE_PACKED_SAMPLE_SIZE] == packed
+                && (last[TRACE_PACKED_SAMPLE_SIZE] as usize) < TRACE_RUN_MAX
+        };
+        let run_fits = !self.runs_overflowed
+            && (extends_run || self.run_bytes + TRACE_RUN_SIZE <= self.runs.len());
+        if !raw_fits && !run_fits {
+            return false;
+        }
+
+        if raw_fits {
+            let at = self.sample_count * TRACE_PACKED_SAMPLE_SIZE;
+            self.raw[at..at + TRACE_PACKED_SAMPLE_SIZE].copy_from_slice(&packed);
+        }
+        if extends_run {
+            self.runs[self.run_bytes - 1] += 1;
+        } else if run_fits {
+            let at = self.run_bytes;
+            self.runs[at..at + TRACE_PACKED_SAMPLE_SIZE].copy_from_slice(&packed);
+            self.runs[at + TRACE_PACKED_SAMPLE_SIZE] = 1;
+            self.run_bytes += TRACE_RUN_SIZE;
+        } else {
+            self.runs_overflowed = true;
+        }
+        true
+    }
+
+    /// Builds the packet and resets the encoder for the next batch.
+    pub fn finish(
+        &mut self,
+        seq: u16,
+        dropped_samples_total: u32,
+        rx_stall_count_total: u32,
+    ) -> Packet {
+        let raw_len = self.sample_count * TRACE_PACKED_SAMPLE_SIZE;
+        let (msg_type, body) = if self.sample_times {
+            (
+                MsgType::TraceSampleTimed,
+                &self.raw[..self.sample_count * TRACE_TIMED_SAMPLE_SIZE],
+            )
+        } else if self.sample_count <= TRACE_SAMPLES_PER_PACKET
+            && (self.runs_overflowed || raw_len <= self.run_bytes)
+        {
+            (MsgType::TraceSample, &self.raw[..raw_len])
+        } else {
+            (MsgType::TraceSampleCompressed, &self.runs[..self.run_bytes])
+        };
+
+        let mut payload = [0u8; PAYLOAD_SIZE];
+        let used = TraceMetadata {
+            dropped_samples_total,
+            rx_stall_count_total,
+            first_sample_us: self.first_sample_us,
+        }
+        .encode(&mut payload);
+        payload[used..used + body.len()].copy_from_slice(body);
+        let pkt = Packet::new(msg_type, seq, &payload[..used + body.len()])
+            .expect("trace batch fits payload");
+
+        self.run_bytes = 0;
+        self.runs_overflowed = false;
+        self.sample_count = 0;
+        pkt
+    }
+}
+
+/// One expanded trace sample. `at_us` is known for the first sample of every
+/// batch and for all samples of a `TRACE_SAMPLE_TIMED` batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedTraceSample {
+    pub sample: u32,
+    pub at_us: Option<u64>,
+}
+
+/// Walks the sample bytes of any trace encoding.
+#[derive(Clone, Debug)]
+pub struct TimedTraceSampleIter<'a> {
+    bytes: &'a [u8],
+    encoding: TraceEncoding,
+    first: bool,
+    at_us: u64,
+    current: u32,
+    repeat: u8,
+}
+
+impl<'a> TimedTraceSampleIter<'a> {
+    pub(super) fn new(bytes: &'a [u8], encoding: TraceEncoding, first_sample_us: u64) -> Self {
+        Self {
+            bytes,
+            encoding,
+            first: true,
+            at_us: first_sample_us,
+            current: 0,
+            repeat: 0,
+        }
+    }
+}
+
+impl Iterator for TimedTraceSampleIter<'_> {
+    type Item = TimedTraceSample;
+
+    fn next(&mut self) -> Option<TimedTraceSample> {
+        if self.repeat > 0 {
+            self.repeat -= 1;
+            return Some(TimedTraceSample {
+                sample: self.current,
+                at_us: None,
+            });
+        }
+
+        let (entry, rest) = self.bytes.split_at_checked(self.encoding.entry_size())?;
+        self.bytes = rest;
+        self.current = unpack_trace_sample([entry[0], entry[1], entry[2]]);
+        let first = core::mem::take(&mut self.first);
+        let at_us = match self.encoding {
+            TraceEncoding::Packed => first.then_some(self.at_us),
+            TraceEncoding::Runs => {
+                self.repeat = entry[TRACE_PACKED_SAMPLE_SIZE].saturating_sub(1);
+                first.then_some(self.at_us)
+            }
+            TraceEncoding::Timed => {
+                let delta = u16::from_le_bytes([entry[3], entry[4]]);
+                self.at_us = self.at_us.wrapping_add(delta as u64);
+                Some(self.at_us)
+            }
+        };
+        Some(TimedTraceSample {
+            sample: self.current,
+            at_us,
+        })
+    }
+}
+
+/// Sample words only, for callers that do not care about time.
+#[derive(Clone, Debug)]
+pub struct TraceSampleIter<'a>(pub(super) TimedTraceSampleIter<'a>);
+
+impl Iterator for TraceSampleIter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.0.next().map(|timed| timed.sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        TraceBatchEncoder, TRACE_COMPRESSED_MAX_SAMPLES, TRACE_RUNS_PER_PACKET, TRACE_RUN_SIZE,
+        TRACE_TIMED_SAMPLES_PER_PACKET,
+    };
+    use crate::bridge_proto::{MsgType, Packet, TRACE_METADATA_SIZE, TRACE_SAMPLES_PER_PACKET};
+
+    const T0: u64 = 5_000_000;
+
+    fn sample(n: u32) -> u32 {
+        (n & 0xFFFF) | (1 << 17)
+    }
+
+    fn fill(encoder: &mut TraceBatchEncoder, samples: impl Iterator<Item = u32>) -> usize {
+        samples
+            .enumerate()
+            .take_while(|&(i, s)| encoder.push(s, T0 + i as u64))
+            .count()
+    }
+
+    fn assert_expands_to(pkt: &Packet, expected: impl Iterator<Item = u32> + Clone) {
+        let raw = pkt.encode();
+        let got = Packet::decode(&raw[..pkt.encoded_len()]).expect("decode");
+        let trace = got.decode_trace_samples().expect("trace payload");
+        assert_eq!(trace.dropped_samples_total, 3);
+        assert_eq!(trace.rx_stall_count_total, 1);
+        assert_eq!(trace.first_sample_us, T0);
+        assert_eq!(trace.sample_count(), expected.clone().count());
+        assert!(trace.iter_samples().eq(expected));
+    }
+
+    #[test]
+    fn busy_polls_compress_into_runs() {
+        let poll = sample(0x00F0);
+        let stream = || {
+            core::iter::repeat_n(poll, 600)
+                .chain([sample(0x0305)])
+                .chain(core::iter::repeat_n(poll, 40))
+        };
+        let mut encoder = TraceBatchEncoder::new();
+        assert_eq!(fill(&mut encoder, stream()), 641);
+
+        let pkt = encoder.finish(7, 3, 1);
+        assert_eq!(pkt.msg_type, MsgType::TraceSampleCompressed);
+        // 600 = 255 + 255 + 90, then the response, then the trailing polls.
+        assert_eq!(
+            pkt.payload_len as usize,
+            TRACE_METADATA_SIZE + 5 * TRACE_RUN_SIZE
+        );
+        assert_expands_to(&pkt, stream());
+        assert!(encoder.is_empty());
+    }
+
+    #[test]
+    fn distinct_samples_stay_uncompressed() {
+        let stream = || (0..TRACE_SAMPLES_PER_PACKET as u32).map(sample);
+        let mut encoder = TraceBatchEncoder::new();
+        assert_eq!(
+            fill(&mut encoder, (0..).map(sample)),
+            TRACE_SAMPLES_PER_PACKET
+        );
+
+        let pkt = encoder.finish(8, 3, 1);
+        assert_eq!(pkt.msg_type, MsgType::TraceSample);
+        assert_expands_to(&pkt, stream());
+    }
+
+    #[test]
+    fn short_mixed_batch_picks_smaller_encoding() {
+        let mut encoder = TraceBatchEncoder::new();
+        for s in [sample(1), sample(1), sample(2)] {
+            assert!(encoder.push(s, T0));
+        }
+        // Raw is 9 bytes, runs would be 8.
+        let pkt = encoder.finish(9, 3, 1);
+        assert_eq!(pkt.msg_type, MsgType::TraceSampleCompressed);
+        assert_expands_to(&pkt, [sample(1), sample(1), sample(2)].into_iter());
+
+        assert!(encoder.push(sample(4), T0));
+        assert_eq!(encoder.finish(11, 3, 1).msg_type, MsgType::TraceSample);
+    }
+
+    #[test]
+    fn encoder_stops_at_compressed_capacity() {
+        let mut encoder = TraceBatchEncoder::new();
+        let stream = (0..).flat_map(|n| core::iter::repeat_n(sample(n), 255));
+        assert_eq!(fill(&mut encoder, stream), TRACE_COMPRESSED_MAX_SAMPLES);
+        let pkt = encoder.finish(12, 3, 1);
+        assert_eq!(
+            pkt.payload_len as usize,
+            TRACE_METADATA_SIZE + TRACE_RUNS_PER_PACKET * TRACE_RUN_SIZE
+        );
+    }
+
+    #[test]
+    fn timed_batch_carries_every_sample_time() {
+        let mut encoder = TraceBatchEncoder::new();
+        encoder.set_sample_times(true);
+        let times = [T0, T0 + 1, T0 + 1, T0 + 900];
+        for (i, &at) in times.iter().enumerate() {
+            assert!(encoder.push(sample(0xF0), at), "sample {i}");
+        }
+        // Gaps beyond the u16 delta start a new batch.
+        assert!(!encoder.push(sample(0xF0), T0 + 900 + 70_000));
+
+        let pkt = encoder.finish(13, 3, 1);
+        assert_eq!(pkt.msg_type, MsgType::TraceSampleTimed);
+        assert_expands_to(&pkt, core::iter::repeat_n(sample(0xF0), 4));
+        let trace = pkt.decode_trace_samples().expect("trace payload");
+        assert!(trace.has_sample_times());
+        assert!(trace
+            .iter_timed_samples()
+            .map(|s| s.at_us)
+            .eq(times.map(Some)));
+
+        let mut encoder = TraceBatchEncoder::new();
+        encoder.set_sample_times(true);
+        assert_eq!(
+            fill(&mut encoder, (0..).map(sample)),
+            TRACE_TIMED_SAMPLES_PER_PACKET
+        );
+    }
+
+    #[test]
+    fn trace_payload_rejects_bad_bodies() {
+        let mut payload = [0u8; TRACE_METADATA_SIZE + TRACE_RUN_SIZE];
+        payload[TRACE_METADATA_SIZE..].copy_from_slice(&[0xF0, 0xFC, 0x01, 0x00]);
+        let pkt = Packet::new(MsgType::TraceSampleCompressed, 1, &payload).expect("packet");
+        assert!(pkt.decode_trace_samples().is_none());
+
+        let short = &payload[..TRACE_METADATA_SIZE + 2];
+        let pkt = Packet::new(MsgType::TraceSampleCompressed, 1, short).expect("packet");
+        assert!(pkt.decode_trace_samples().is_none());
+        let pkt = Packet::new(MsgType::TraceSampleTimed, 1, &payload).expect("packet");
+        assert!(pkt.decode_trace_samples().is_none());
+    }
+}
@@ -0,0 +1,128 @@
+//! Which FC80 command the ROM writes to read each DRO field.
+//!
+//! The ROM polls ten fields, always in the same order: the X sign byte and
+//! its three digit pairs (most significant first), the same for Z, then the
+//! two spindle speed pairs. [`CommandMap`] gives the command ID of each, so
+//! other ROM revisions only need a different table. TCL125 v2.02
+//! (`fred80_table` at `$9538`) is the default.
+
+/// Fields the ROM polls per cadence.
+pub const CADENCE_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DroAxis {
+    X,
+    Z,
+}
+
+/// What the response to one command holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DroField {
+    /// Non-zero for a negative value.
+    Sign(DroAxis),
+    /// Digit pair `0..3`, most significant first.
+    Digits(DroAxis, usize),
+    /// Speed pair `0..2`, most significant first.
+    Rpm(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandMap {
+    /// Sign, then the digit pairs most significant first.
+    pub x: [u8; 4],
+    pub z: [u8; 4],
+    /// Most significant pair first.
+    pub rpm: [u8; 2],
+}
+
+impl Default for CommandMap {
+    fn default() -> Self {
+        Self::TCL125_V202
+    }
+}
+
+impl CommandMap {
+    /// "MW CONTROLS TCL 125/240", ROM v2.02.
+    pub const TCL125_V202: Self = Self {
+        x: [0x03, 0x02, 0x01, 0x00],
+        z: [0x07, 0x06, 0x05, 0x04],
+        rpm: [0x0D, 0x0C],
+    };
+
+    /// The commands in the order the ROM issues them.
+    pub const fn cadence(&self) -> [u8; CADENCE_LEN] {
+        [
+            self.x[0],
+            self.x[1],
+            self.x[2],
+            self.x[3],
+            self.z[0],
+            self.z[1],
+            self.z[2],
+            self.z[3],
+            self.rpm[0],
+            self.rpm[1],
+        ]
+    }
+
+    /// Index of `cmd` in [`cadence`](Self::cadence); `None` for a command
+    /// outside it.
+    pub fn position(&self, cmd: u8) -> Option<usize> {
+        self.cadence().iter().position(|&c| c == cmd)
+    }
+
+    pub fn field(&self, cmd: u8) -> Option<DroField> {
+        let field = match self.position(cmd)? {
+            0 => DroField::Sign(DroAxis::X),
+            pos @ 1..=3 => DroField::Digits(DroAxis::X, pos - 1),
+            4 => DroField::Sign(DroAxis::Z),
+            pos @ 5..=7 => DroField::Digits(DroAxis::Z, pos - 5),
+            pos => DroField::Rpm(pos - 8),
+        };
+        Some(field)
+    }
+
+    /// The command that ends a cadence, after which a snapshot is complete.
+    pub const fn last_command(&self) -> u8 {
+        self.rpm[1]
+    }
+
+    /// Every command is distinct, so each response has one meaning.
+    pub fn is_valid(&self) -> bool {
+        let cadence = self.cadence();
+        cadence
+            .iter()
+            .enumerate()
+            .all(|(i, cmd)| !cadence[..i].contains(cmd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandMap, DroAxis, DroField};
+
+    #[test]
+    fn default_map_is_the_tcl125_cadence() {
+        let map = CommandMap::default();
+        assert!(map.is_valid());
+        assert_eq!(
+            map.cadence(),
+            [0x03, 0x02, 0x01, 0x00, 0x07, 0x06, 0x05, 0x04, 0x0D, 0x0C]
+        );
+        assert_eq!(map.field(0x03), Some(DroField::Sign(DroAxis::X)));
+        assert_eq!(map.field(0x00), Some(DroField::Digits(DroAxis::X, 2)));
+        assert_eq!(map.field(0x06), Some(DroField::Digits(DroAxis::Z, 0)));
+        assert_eq!(map.field(0x0C), Some(DroField::Rpm(1)));
+        assert_eq!(map.field(0x08), None);
+        assert_eq!(map.last_command(), 0x0C);
+    }
+
+    #[test]
+    fn rejects_maps_that_reuse_a_command() {
+        let map = CommandMap {
+            rpm: [0x0D, 0x03],
+            ..CommandMap::TCL125_V202
+        };
+        assert!(!map.is_valid());
+    }
+}
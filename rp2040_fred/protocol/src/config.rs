@@ -0,0 +1,741 @@
+//! Device configuration: the values applied at power-up, their
+//! `CONFIG_GET`/`CONFIG_SET` keys, and the record the firmware keeps in flash.
+//!
+//! On the wire and in flash every setting is a `(u8 key, u32 value)` pair.
+//! [`ConfigValue`] gives each key its type and range, so a value that reaches
+//! [`DeviceConfig`] is always valid.
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::bridge_proto::{crc32_ieee, LinearUnits, UnitConfig, XAxisMode};
+use crate::command_map::CommandMap;
+use crate::dro_decode::{Calibration, DEFAULT_MAX_COUNTS_PER_S};
+
+/// `"FCFG"`, little-endian.
+pub const CONFIG_RECORD_MAGIC: u32 = u32::from_le_bytes(*b"FCFG");
+/// Bumped when a key changes meaning. New keys do not need a bump: readers
+/// skip keys they do not know and default keys that are missing.
+pub const CONFIG_RECORD_VERSION: u8 = 1;
+pub const CONFIG_ENTRY_SIZE: usize = 5;
+/// Entries a record or `CONFIG`/`CONFIG_SET` payload can hold.
+pub const CONFIG_MAX_ENTRIES: usize = 16;
+const CONFIG_RECORD_HEADER_SIZE: usize = 6;
+pub const CONFIG_RECORD_MAX_SIZE: usize =
+    CONFIG_RECORD_HEADER_SIZE + CONFIG_MAX_ENTRIES * CONFIG_ENTRY_SIZE + 4;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigKey {
+    PowerUpMode = 0x01,
+    TelemetryPeriodMs = 0x02,
+    HealthPeriodMs = 0x03,
+    Units = 0x04,
+    XMode = 0x05,
+    XCountsPerMm = 0x06,
+    ZCountsPerMm = 0x07,
+    MaxCountsPerS = 0x08,
+    XCommands = 0x09,
+    ZCommands = 0x0A,
+    RpmCommands = 0x0B,
+    XInverted = 0x0C,
+    ZInverted = 0x0D,
+    XZeroOffset = 0x0E,
+    ZZeroOffset = 0x0F,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; 15] = [
+        Self::PowerUpMode,
+        Self::TelemetryPeriodMs,
+        Self::HealthPeriodMs,
+        Self::Units,
+        Self::XMode,
+        Self::XCountsPerMm,
+        Self::ZCountsPerMm,
+        Self::MaxCountsPerS,
+        Self::XCommands,
+        Self::ZCommands,
+        Self::RpmCommands,
+        Self::XInverted,
+        Self::ZInverted,
+        Self::XZeroOffset,
+        Self::ZZeroOffset,
+    ];
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&key| key as u8 == v)
+    }
+
+    /// Name used by `fredctl config`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PowerUpMode => "power_up_mode",
+            Self::TelemetryPeriodMs => "telemetry_period_ms",
+            Self::HealthPeriodMs => "health_period_ms",
+            Self::Units => "units",
+            Self::XMode => "x_mode",
+            Self::XCountsPerMm => "x_counts_per_mm",
+            Self::ZCountsPerMm => "z_counts_per_mm",
+            Self::MaxCountsPerS => "max_counts_per_s",
+            Self::XCommands => "x_commands",
+            Self::ZCommands => "z_commands",
+            Self::RpmCommands => "rpm_commands",
+            Self::XInverted => "x_inverted",
+            Self::ZInverted => "z_inverted",
+            Self::XZeroOffset => "x_zero_offset",
+            Self::ZZeroOffset => "z_zero_offset",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|key| key.name() == name)
+    }
+}
+
+/// What the firmware streams after reset.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowerUpMode {
+    /// Decode the bus for `SNAPSHOT_REQ` but stream nothing.
+    #[default]
+    Idle = 0,
+    /// Stream trace batches, as after `CAPTURE_SET(enable=1)`.
+    Capture = 1,
+    /// Stream `TELEMETRY`, as after `TELEMETRY_SET(enable=1)`.
+    Telemetry = 2,
+}
+
+impl PowerUpMode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Idle),
+            1 => Some(Self::Capture),
+            2 => Some(Self::Telemetry),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    UnknownKey,
+    /// The value is outside the key's range or does not parse, or leaves
+    /// the command map using one command for two fields.
+    BadValue,
+}
+
+/// One setting, typed by its key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigValue {
+    PowerUpMode(PowerUpMode),
+    /// Telemetry period applied at power-up, `1..=65535`.
+    TelemetryPeriodMs(u16),
+    /// `HEALTH` period applied at power-up; `0` leaves `HEALTH` off.
+    HealthPeriodMs(u16),
+    Units(LinearUnits),
+    XMode(XAxisMode),
+    /// Calibration for host-side conversion, finite and positive.
+    XCountsPerMm(f32),
+    ZCountsPerMm(f32),
+    /// Fastest plausible axis movement for the torn-read filter; `0` turns
+    /// the filter off.
+    MaxCountsPerS(u32),
+    /// [`CommandMap::x`]: sign command, then digit pair commands.
+    XCommands([u8; 4]),
+    ZCommands([u8; 4]),
+    RpmCommands([u8; 2]),
+    /// Calibration for host-side conversion: the axis counts the other way.
+    XInverted(bool),
+    ZInverted(bool),
+    /// Calibration for host-side conversion, in counts; travels as the
+    /// `i32` bit pattern.
+    XZeroOffset(i32),
+    ZZeroOffset(i32),
+}
+
+impl ConfigValue {
+    pub fn key(&self) -> ConfigKey {
+        match self {
+            Self::PowerUpMode(_) => ConfigKey::PowerUpMode,
+            Self::TelemetryPeriodMs(_) => ConfigKey::TelemetryPeriodMs,
+            Self::HealthPeriodMs(_) => ConfigKey::HealthPeriodMs,
+            Self::Units(_) => ConfigKey::Units,
+            Self::XMode(_) => ConfigKey::XMode,
+            Self::XCountsPerMm(_) => ConfigKey::XCountsPerMm,
+            Self::ZCountsPerMm(_) => ConfigKey::ZCountsPerMm,
+            Self::MaxCountsPerS(_) => ConfigKey::MaxCountsPerS,
+            Self::XCommands(_) => ConfigKey::XCommands,
+            Self::ZCommands(_) => ConfigKey::ZCommands,
+            Self::RpmCommands(_) => ConfigKey::RpmCommands,
+            Self::XInverted(_) => ConfigKey::XInverted,
+            Self::ZInverted(_) => ConfigKey::ZInverted,
+            Self::XZeroOffset(_) => ConfigKey::XZeroOffset,
+            Self::ZZeroOffset(_) => ConfigKey::ZZeroOffset,
+        }
+    }
+
+    /// Wire value. Calibration travels as the `f32` bit pattern, command
+    /// lists big-endian so `0x03020100` reads in cadence order.
+    pub fn to_raw(&self) -> u32 {
+        match *self {
+            Self::PowerUpMode(mode) => mode as u32,
+            Self::TelemetryPeriodMs(ms) | Self::HealthPeriodMs(ms) => ms as u32,
+            Self::Units(units) => units as u32,
+            Self::XMode(mode) => mode as u32,
+            Self::XCountsPerMm(v) | Self::ZCountsPerMm(v) => v.to_bits(),
+            Self::MaxCountsPerS(v) => v,
+            Self::XCommands(cmds) | Self::ZCommands(cmds) => u32::from_be_bytes(cmds),
+            Self::RpmCommands([hi, lo]) => u32::from_be_bytes([0, 0, hi, lo]),
+            Self::XInverted(v) | Self::ZInverted(v) => v as u32,
+            Self::XZeroOffset(v) | Self::ZZeroOffset(v) => v as u32,
+        }
+    }
+
+    pub fn from_raw(key: ConfigKey, raw: u32) -> Result<Self, ConfigError> {
+        let small = u8::try_from(raw).ok();
+        let value = match key {
+            ConfigKey::PowerUpMode => small.and_then(PowerUpMode::from_u8).map(Self::PowerUpMode),
+            ConfigKey::TelemetryPeriodMs => u16::try_from(raw)
+                .ok()
+                .filter(|&ms| ms > 0)
+                .map(Self::TelemetryPeriodMs),
+            ConfigKey::HealthPeriodMs => u16::try_from(raw).ok().map(Self::HealthPeriodMs),
+            ConfigKey::Units => small.and_then(LinearUnits::from_u8).map(Self::Units),
+            ConfigKey::XMode => small.and_then(XAxisMode::from_u8).map(Self::XMode),
+            ConfigKey::XCountsPerMm => counts_per_mm(raw).map(Self::XCountsPerMm),
+            ConfigKey::ZCountsPerMm => counts_per_mm(raw).map(Self::ZCountsPerMm),
+            ConfigKey::MaxCountsPerS => Some(Self::MaxCountsPerS(raw)),
+            ConfigKey::XCommands => Some(Self::XCommands(raw.to_be_bytes())),
+            ConfigKey::ZCommands => Some(Self::ZCommands(raw.to_be_bytes())),
+            ConfigKey::RpmCommands => match raw.to_be_bytes() {
+                [0, 0, hi, lo] => Some(Self::RpmCommands([hi, lo])),
+                _ => None,
+            },
+            ConfigKey::XInverted => flag(raw).map(Self::XInverted),
+            ConfigKey::ZInverted => flag(raw).map(Self::ZInverted),
+            ConfigKey::XZeroOffset => Some(Self::XZeroOffset(raw as i32)),
+            ConfigKey::ZZeroOffset => Some(Self::ZZeroOffset(raw as i32)),
+        };
+        value.ok_or(ConfigError::BadValue)
+    }
+
+    /// Parses the text form printed by `Display`, e.g. `capture`, `250`,
+    /// `imperial`, `radius`, `100.5`, `03,02,01,00`, `true` or `-250`.
+    pub fn parse(key: ConfigKey, text: &str) -> Result<Self, ConfigError> {
+        let raw = match key {
+            ConfigKey::PowerUpMode => match text {
+                "idle" => PowerUpMode::Idle as u32,
+                "capture" => PowerUpMode::Capture as u32,
+                "telemetry" => PowerUpMode::Telemetry as u32,
+                _ => return Err(ConfigError::BadValue),
+            },
+            ConfigKey::TelemetryPeriodMs | ConfigKey::HealthPeriodMs | ConfigKey::MaxCountsPerS => {
+                u32::from_str(text).map_err(|_| ConfigError::BadValue)?
+            }
+            ConfigKey::Units => match text {
+                "metric" => LinearUnits::Metric as u32,
+                "imperial" => LinearUnits::Imperial as u32,
+                _ => return Err(ConfigError::BadValue),
+            },
+            ConfigKey::XMode => match text {
+                "diameter" => XAxisMode::Diameter as u32,
+                "radius" => XAxisMode::Radius as u32,
+                _ => return Err(ConfigError::BadValue),
+            },
+            ConfigKey::XCountsPerMm | ConfigKey::ZCountsPerMm => f32::from_str(text)
+                .map_err(|_| ConfigError::BadValue)?
+                .to_bits(),
+            ConfigKey::XCommands | ConfigKey::ZCommands => {
+                u32::from_be_bytes(parse_commands(text)?)
+            }
+            ConfigKey::RpmCommands => {
+                let [hi, lo] = parse_commands(text)?;
+                u32::from_be_bytes([0, 0, hi, lo])
+            }
+            ConfigKey::XInverted | ConfigKey::ZInverted => {
+                bool::from_str(text).map_err(|_| ConfigError::BadValue)? as u32
+            }
+            ConfigKey::XZeroOffset | ConfigKey::ZZeroOffset => {
+                i32::from_str(text).map_err(|_| ConfigError::BadValue)? as u32
+            }
+        };
+        Self::from_raw(key, raw)
+    }
+}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::PowerUpMode(PowerUpMode::Idle) => f.write_str("idle"),
+            Self::PowerUpMode(PowerUpMode::Capture) => f.write_str("capture"),
+            Self::PowerUpMode(PowerUpMode::Telemetry) => f.write_str("telemetry"),
+            Self::TelemetryPeriodMs(ms) | Self::HealthPeriodMs(ms) => write!(f, "{ms}"),
+            Self::Units(LinearUnits::Metric) => f.write_str("metric"),
+            Self::Units(LinearUnits::Imperial) => f.write_str("imperial"),
+            Self::XMode(XAxisMode::Diameter) => f.write_str("diameter"),
+            Self::XMode(XAxisMode::Radius) => f.write_str("radius"),
+            Self::XCountsPerMm(v) | Self::ZCountsPerMm(v) => write!(f, "{v}"),
+            Self::MaxCountsPerS(v) => write!(f, "{v}"),
+            Self::XCommands(cmds) | Self::ZCommands(cmds) => write_commands(f, &cmds),
+            Self::RpmCommands(cmds) => write_commands(f, &cmds),
+            Self::XInverted(v) | Self::ZInverted(v) => write!(f, "{v}"),
+            Self::XZeroOffset(v) | Self::ZZeroOffset(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+/// Exactly `N` comma-separated hex bytes, e.g. `0D,0C`.
+fn parse_commands<const N: usize>(text: &str) -> Result<[u8; N], ConfigError> {
+    let mut cmds = [0u8; N];
+    let mut parts = text.split(',');
+    for cmd in cmds.iter_mut() {
+        let part = parts.next().ok_or(ConfigError::BadValue)?.trim();
+        *cmd = u8::from_str_radix(part, 16).map_err(|_| ConfigError::BadValue)?;
+    }
+    if parts.next().is_some() {
+        return Err(ConfigError::BadValue);
+    }
+    Ok(cmds)
+}
+
+fn write_commands(f: &mut fmt::Formatter<'_>, cmds: &[u8]) -> fmt::Result {
+    for (i, cmd) in cmds.iter().enumerate() {
+        if i > 0 {
+            f.write_str(",")?;
+        }
+        write!(f, "{cmd:02X}")?;
+    }
+    Ok(())
+}
+
+fn flag(raw: u32) -> Option<bool> {
+    match raw {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+fn counts_per_mm(raw: u32) -> Option<f32> {
+    let v = f32::from_bits(raw);
+    (v.is_finite() && v > 0.0).then_some(v)
+}
+
+/// Raw `(key, value)` pair as carried by `CONFIG`, `CONFIG_SET` and the flash
+/// record. The key stays a byte so newer keys survive a round trip.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigEntry {
+    pub key: u8,
+    pub value: u32,
+}
+
+impl ConfigEntry {
+    pub fn decode_value(&self) -> Result<ConfigValue, ConfigError> {
+        let key = ConfigKey::from_u8(self.key).ok_or(ConfigError::UnknownKey)?;
+        ConfigValue::from_raw(key, self.value)
+    }
+}
+
+impl From<ConfigValue> for ConfigEntry {
+    fn from(value: ConfigValue) -> Self {
+        Self {
+            key: value.key() as u8,
+            value: value.to_raw(),
+        }
+    }
+}
+
+/// Up to [`CONFIG_MAX_ENTRIES`] entries, encoded as `u8 count` followed by
+/// `u8 key`, `u32 value` per entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigEntries {
+    entries: [ConfigEntry; CONFIG_MAX_ENTRIES],
+    len: usize,
+}
+
+impl ConfigEntries {
+    pub const MAX_ENCODED_LEN: usize = 1 + CONFIG_MAX_ENTRIES * CONFIG_ENTRY_SIZE;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `false` when full.
+    pub fn push(&mut self, entry: ConfigEntry) -> bool {
+        if self.len == CONFIG_MAX_ENTRIES {
+            return false;
+        }
+        self.entries[self.len] = entry;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[ConfigEntry] {
+        &self.entries[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        1 + self.len * CONFIG_ENTRY_SIZE
+    }
+
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        out[0] = self.len as u8;
+        for (entry, chunk) in self
+            .as_slice()
+            .iter()
+            .zip(out[1..].chunks_exact_mut(CONFIG_ENTRY_SIZE))
+        {
+            chunk[0] = entry.key;
+            chunk[1..].copy_from_slice(&entry.value.to_le_bytes());
+        }
+        self.encoded_len()
+    }
+
+    /// Decodes a count-prefixed list and returns it with the bytes it used.
+    /// `None` if the count exceeds the limit or the input is too short.
+    pub fn decode(input: &[u8]) -> Option<(Self, usize)> {
+        let count = *input.first()? as usize;
+        let used = 1 + count * CONFIG_ENTRY_SIZE;
+        if count > CONFIG_MAX_ENTRIES || input.len() < used {
+            return None;
+        }
+        let mut entries = Self::new();
+        for chunk in input[1..used].chunks_exact(CONFIG_ENTRY_SIZE) {
+            entries.push(ConfigEntry {
+                key: chunk[0],
+                value: u32::from_le_bytes([chunk[1], chunk[2], chunk[3], chunk[4]]),
+            });
+        }
+        Some((entries, used))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigRecordError {
+    /// No record, e.g. an erased sector.
+    Missing,
+    /// Written by firmware with a newer record version.
+    UnsupportedVersion(u8),
+    Length,
+    BadCrc,
+}
+
+/// Settings the firmware applies at power-up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub power_up_mode: PowerUpMode,
+    pub telemetry_period_ms: u16,
+    /// `0` leaves `HEALTH` off at power-up.
+    pub health_period_ms: u16,
+    pub units: UnitConfig,
+    pub calibration: Calibration,
+    /// `0` turns the torn-read filter off.
+    pub max_counts_per_s: u32,
+    /// Always [valid](CommandMap::is_valid).
+    pub command_map: CommandMap,
+}
+
+impl Default for DeviceConfig {
+    /// The firmware's behaviour before configuration was stored.
+    fn default() -> Self {
+        Self {
+            power_up_mode: PowerUpMode::Idle,
+            telemetry_period_ms: 100,
+            health_period_ms: 0,
+            units: UnitConfig::default(),
+            calibration: Calibration::default(),
+            max_counts_per_s: DEFAULT_MAX_COUNTS_PER_S,
+            command_map: CommandMap::TCL125_V202,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn get(&self, key: ConfigKey) -> ConfigValue {
+        match key {
+            ConfigKey::PowerUpMode => ConfigValue::PowerUpMode(self.power_up_mode),
+            ConfigKey::TelemetryPeriodMs => {
+                ConfigValue::TelemetryPeriodMs(self.telemetry_period_ms)
+            }
+            ConfigKey::HealthPeriodMs => ConfigValue::HealthPeriodMs(self.health_period_ms),
+            ConfigKey::Units => ConfigValue::Units(self.units.units),
+            ConfigKey::XMode => ConfigValue::XMode(self.units.x_mode),
+            ConfigKey::XCountsPerMm => ConfigValue::XCountsPerMm(self.calibration.x.counts_per_mm),
+            ConfigKey::ZCountsPerMm => ConfigValue::ZCountsPerMm(self.calibration.z.counts_per_mm),
+            ConfigKey::MaxCountsPerS => ConfigValue::MaxCountsPerS(self.max_counts_per_s),
+            ConfigKey::XCommands => ConfigValue::XCommands(self.command_map.x),
+            ConfigKey::ZCommands => ConfigValue::ZCommands(self.command_map.z),
+            ConfigKey::RpmCommands => ConfigValue::RpmCommands(self.command_map.rpm),
+            ConfigKey::XInverted => ConfigValue::XInverted(self.calibration.x.inverted),
+            ConfigKey::ZInverted => ConfigValue::ZInverted(self.calibration.z.inverted),
+            ConfigKey::XZeroOffset => ConfigValue::XZeroOffset(self.calibration.x.zero_offset),
+            ConfigKey::ZZeroOffset => ConfigValue::ZZeroOffset(self.calibration.z.zero_offset),
+        }
+    }
+
+    /// Command lists are set as given; [`apply`](Self::apply) checks the
+    /// resulting map.
+    pub fn set(&mut self, value: ConfigValue) {
+        match value {
+            ConfigValue::PowerUpMode(mode) => self.power_up_mode = mode,
+            ConfigValue::TelemetryPeriodMs(ms) => self.telemetry_period_ms = ms,
+            ConfigValue::HealthPeriodMs(ms) => self.health_period_ms = ms,
+            ConfigValue::Units(units) => self.units.units = units,
+            ConfigValue::XMode(mode) => self.units.x_mode = mode,
+            ConfigValue::XCountsPerMm(v) => self.calibration.x.counts_per_mm = v,
+            ConfigValue::ZCountsPerMm(v) => self.calibration.z.counts_per_mm = v,
+            ConfigValue::MaxCountsPerS(v) => self.max_counts_per_s = v,
+            ConfigValue::XCommands(cmds) => self.command_map.x = cmds,
+            ConfigValue::ZCommands(cmds) => self.command_map.z = cmds,
+            ConfigValue::RpmCommands(cmds) => self.command_map.rpm = cmds,
+            ConfigValue::XInverted(v) => self.calibration.x.inverted = v,
+            ConfigValue::ZInverted(v) => self.calibration.z.inverted = v,
+            ConfigValue::XZeroOffset(v) => self.calibration.x.zero_offset = v,
+            ConfigValue::ZZeroOffset(v) => self.calibration.z.zero_offset = v,
+        }
+    }
+
+    /// Applies every entry or none: the first unknown key or bad value is
+    /// returned and `self` is left unchanged. A command map is checked once
+    /// all entries are in, so its lists can be changed together.
+    pub fn apply(&mut self, entries: &[ConfigEntry]) -> Result<(), ConfigError> {
+        let mut next = *self;
+        for entry in entries {
+            next.set(entry.decode_value()?);
+        }
+        if !next.command_map.is_valid() {
+            return Err(ConfigError::BadValue);
+        }
+        *self = next;
+        Ok(())
+    }
+
+    /// Every key, in [`ConfigKey::ALL`] order.
+    pub fn entries(&self) -> ConfigEntries {
+        let mut entries = ConfigEntries::new();
+        for key in ConfigKey::ALL {
+            entries.push(self.get(key).into());
+        }
+        entries
+    }
+
+    /// Flash record: `u32 magic`, `u8 version`, the entry list, then a CRC32
+    /// of everything before it. Returns the length written.
+    pub fn encode_record(&self, out: &mut [u8; CONFIG_RECORD_MAX_SIZE]) -> usize {
+        out[..4].copy_from_slice(&CONFIG_RECORD_MAGIC.to_le_bytes());
+        out[4] = CONFIG_RECORD_VERSION;
+        let n = 5 + self.entries().encode(&mut out[5..]);
+        let crc = crc32_ieee(&out[..n]);
+        out[n..n + 4].copy_from_slice(&crc.to_le_bytes());
+        n + 4
+    }
+
+    /// Reads a record written by [`encode_record`](Self::encode_record).
+    /// Unknown keys and out-of-range values fall back to the defaults, as
+    /// does a command map that reuses a command.
+    pub fn decode_record(record: &[u8]) -> Result<Self, ConfigRecordError> {
+        if record.len() < CONFIG_RECORD_HEADER_SIZE
+            || u32::from_le_bytes([record[0], record[1], record[2], record[3]])
+                != CONFIG_RECORD_MAGIC
+        {
+            return Err(ConfigRecordError::Missing);
+        }
+        if record[4] > CONFIG_RECORD_VERSION {
+            return Err(ConfigRecordError::UnsupportedVersion(record[4]));
+        }
+        let (entries, used) =
+            ConfigEntries::decode(&record[5..]).ok_or(ConfigRecordError::Length)?;
+        let n = 5 + used;
+        let Some(crc) = record.get(n..n + 4) else {
+            return Err(ConfigRecordError::Length);
+        };
+        if u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) != crc32_ieee(&record[..n]) {
+            return Err(ConfigRecordError::BadCrc);
+        }
+
+        let mut config = Self::default();
+        for entry in entries.as_slice() {
+            if let Ok(value) = entry.decode_value() {
+                config.set(value);
+            }
+        }
+        if !config.command_map.is_valid() {
+            config.command_map = CommandMap::TCL125_V202;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{
+        ConfigEntries, ConfigEntry, ConfigError, ConfigKey, ConfigRecordError, ConfigValue,
+        DeviceConfig, PowerUpMode, CONFIG_RECORD_MAX_SIZE, CONFIG_RECORD_VERSION,
+    };
+    use crate::bridge_proto::{crc32_ieee, LinearUnits, XAxisMode};
+
+    fn custom() -> DeviceConfig {
+        let mut config = DeviceConfig::default();
+        config.set(ConfigValue::PowerUpMode(PowerUpMode::Capture));
+        config.set(ConfigValue::TelemetryPeriodMs(250));
+        config.set(ConfigValue::HealthPeriodMs(1000));
+        config.set(ConfigValue::Units(LinearUnits::Imperial));
+        config.set(ConfigValue::XMode(XAxisMode::Radius));
+        config.set(ConfigValue::XCountsPerMm(200.5));
+        config.set(ConfigValue::ZInverted(true));
+        config.set(ConfigValue::XZeroOffset(-1250));
+        config.set(ConfigValue::MaxCountsPerS(0));
+        config
+    }
+
+    #[test]
+    fn record_roundtrips() {
+        let config = custom();
+        let mut buf = [0u8; CONFIG_RECORD_MAX_SIZE];
+        let n = config.encode_record(&mut buf);
+        assert_eq!(&buf[..4], b"FCFG");
+        assert_eq!(buf[4], CONFIG_RECORD_VERSION);
+        assert_eq!(DeviceConfig::decode_record(&buf[..n]), Ok(config));
+        // Trailing erased flash after the record is ignored.
+        buf[n..].fill(0xFF);
+        assert_eq!(DeviceConfig::decode_record(&buf), Ok(config));
+    }
+
+    #[test]
+    fn record_rejects_blank_corrupt_and_future_records() {
+        assert_eq!(
+            DeviceConfig::decode_record(&[0xFF; CONFIG_RECORD_MAX_SIZE]),
+            Err(ConfigRecordError::Missing)
+        );
+
+        let mut buf = [0u8; CONFIG_RECORD_MAX_SIZE];
+        let n = custom().encode_record(&mut buf);
+        let mut corrupt = buf;
+        corrupt[8] ^= 0x01;
+        assert_eq!(
+            DeviceConfig::decode_record(&corrupt[..n]),
+            Err(ConfigRecordError::BadCrc)
+        );
+        assert_eq!(
+            DeviceConfig::decode_record(&buf[..n - 1]),
+            Err(ConfigRecordError::Length)
+        );
+
+        let mut future = buf;
+        future[4] = CONFIG_RECORD_VERSION + 1;
+        assert_eq!(
+            DeviceConfig::decode_record(&future[..n]),
+            Err(ConfigRecordError::UnsupportedVersion(
+                CONFIG_RECORD_VERSION + 1
+            ))
+        );
+    }
+
+    #[test]
+    fn record_skips_unknown_keys_and_bad_values() {
+        let mut entries = ConfigEntries::new();
+        entries.push(ConfigValue::TelemetryPeriodMs(40).into());
+        entries.push(ConfigEntry {
+            key: 0x7E,
+            value: 1,
+        });
+        entries.push(ConfigEntry {
+            key: ConfigKey::PowerUpMode as u8,
+            value: 9,
+        });
+        let mut buf = [0u8; CONFIG_RECORD_MAX_SIZE];
+        buf[..4].copy_from_slice(b"FCFG");
+        buf[4] = CONFIG_RECORD_VERSION;
+        let n = 5 + entries.encode(&mut buf[5..]);
+        let crc = crc32_ieee(&buf[..n]);
+        buf[n..n + 4].copy_from_slice(&crc.to_le_bytes());
+
+        let config = DeviceConfig::decode_record(&buf[..n + 4]).expect("record");
+        assert_eq!(config.telemetry_period_ms, 40);
+        assert_eq!(config.power_up_mode, PowerUpMode::Idle);
+    }
+
+    #[test]
+    fn values_validate_and_parse() {
+        for key in ConfigKey::ALL {
+            assert_eq!(ConfigKey::from_u8(key as u8), Some(key));
+            assert_eq!(ConfigKey::from_name(key.name()), Some(key));
+            let value = DeviceConfig::default().get(key);
+            let text = std::format!("{value}");
+            assert_eq!(ConfigValue::parse(key, &text), Ok(value));
+            assert_eq!(ConfigValue::from_raw(key, value.to_raw()), Ok(value));
+        }
+        assert_eq!(
+            ConfigValue::parse(ConfigKey::TelemetryPeriodMs, "0"),
+            Err(ConfigError::BadValue)
+        );
+        assert_eq!(
+            ConfigValue::parse(ConfigKey::HealthPeriodMs, "70000"),
+            Err(ConfigError::BadValue)
+        );
+        assert_eq!(
+            ConfigValue::parse(ConfigKey::ZCountsPerMm, "-1"),
+            Err(ConfigError::BadValue)
+        );
+        assert_eq!(
+            ConfigValue::from_raw(ConfigKey::XCountsPerMm, f32::NAN.to_bits()),
+            Err(ConfigError::BadValue)
+        );
+    }
+
+    #[test]
+    fn apply_is_all_or_nothing() {
+        let mut config = DeviceConfig::default();
+        let good: ConfigEntry = ConfigValue::HealthPeriodMs(500).into();
+        let unknown = ConfigEntry {
+            key: 0x7E,
+            value: 0,
+        };
+        assert_eq!(config.apply(&[good, unknown]), Err(ConfigError::UnknownKey));
+        assert_eq!(config, DeviceConfig::default());
+        assert_eq!(config.apply(&[good]), Ok(()));
+        assert_eq!(config.health_period_ms, 500);
+    }
+
+    #[test]
+    fn command_lists_parse_and_must_not_collide() {
+        let z = ConfigValue::parse(ConfigKey::ZCommands, "17,16,15,14").expect("valid list");
+        assert_eq!(z, ConfigValue::ZCommands([0x17, 0x16, 0x15, 0x14]));
+        assert_eq!(z.to_raw(), 0x1716_1514);
+        assert_eq!(std::format!("{z}"), "17,16,15,14");
+        assert_eq!(
+            ConfigValue::parse(ConfigKey::RpmCommands, "0D,0C,0B"),
+            Err(ConfigError::BadValue)
+        );
+        assert_eq!(
+            ConfigValue::from_raw(ConfigKey::RpmCommands, 0x0001_0D0C),
+            Err(ConfigError::BadValue)
+        );
+
+        let mut config = DeviceConfig::default();
+        let clash: ConfigEntry = ConfigValue::RpmCommands([0x0D, 0x03]).into();
+        assert_eq!(config.apply(&[clash]), Err(ConfigError::BadValue));
+        assert_eq!(config.apply(&[z.into()]), Ok(()));
+        assert_eq!(config.command_map.z, [0x17, 0x16, 0x15, 0x14]);
+        assert_eq!(config.command_map.x, [0x03, 0x02, 0x01, 0x00]);
+
+        // A record whose lists collide falls back to the default map.
+        let mut stored = DeviceConfig::default();
+        stored.set(ConfigValue::XCommands([0x0C, 0x02, 0x01, 0x00]));
+        let mut buf = [0u8; CONFIG_RECORD_MAX_SIZE];
+        let n = stored.encode_record(&mut buf);
+        let loaded = DeviceConfig::decode_record(&buf[..n]).expect("record");
+        assert_eq!(loaded.command_map, DeviceConfig::default().command_map);
+    }
+}
@@ -0,0 +1,172 @@
+//! CRC-32/IEEE (reflected, poly `0xEDB88320`) as used by every bridge packet.
+//!
+//! The default `crc-slice8` feature uses slicing-by-8 over an 8 KiB table.
+//! Builds without it fall back to the classic byte-at-a-time 1 KiB table.
+//! Both tables are generated at compile time and live in flash.
+
+const POLY: u32 = 0xEDB8_8320;
+
+#[cfg(feature = "crc-slice8")]
+const TABLE_COUNT: usize = 8;
+#[cfg(not(feature = "crc-slice8"))]
+const TABLE_COUNT: usize = 1;
+
+static TABLES: [[u32; 256]; TABLE_COUNT] = make_tables();
+
+const fn make_tables() -> [[u32; 256]; TABLE_COUNT] {
+    let mut tables = [[0u32; 256]; TABLE_COUNT];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+
+    // tables[k][i] is the CRC of byte `i` followed by `k` zero bytes.
+    let mut k = 1;
+    while k < TABLE_COUNT {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xFF) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+
+    tables
+}
+
+pub fn crc32_ieee(data: &[u8]) -> u32 {
+    !update(0xFFFF_FFFF, data)
+}
+
+#[cfg(feature = "crc-slice8")]
+fn update(mut crc: u32, data: &[u8]) -> u32 {
+    let t = &TABLES;
+    let mut chunks = data.chunks_exact(8);
+    for c in &mut chunks {
+        let lo = crc ^ u32::from_le_bytes([c[0], c[1], c[2], c[3]]);
+        let hi = u32::from_le_bytes([c[4], c[5], c[6], c[7]]);
+        crc = t[7][(lo & 0xFF) as usize]
+            ^ t[6][((lo >> 8) & 0xFF) as usize]
+            ^ t[5][((lo >> 16) & 0xFF) as usize]
+            ^ t[4][(lo >> 24) as usize]
+            ^ t[3][(hi & 0xFF) as usize]
+            ^ t[2][((hi >> 8) & 0xFF) as usize]
+            ^ t[1][((hi >> 16) & 0xFF) as usize]
+            ^ t[0][(hi >> 24) as usize];
+    }
+    update_bytes(crc, chunks.remainder())
+}
+
+#[cfg(not(feature = "crc-slice8"))]
+fn update(crc: u32, data: &[u8]) -> u32 {
+    update_bytes(crc, data)
+}
+
+fn update_bytes(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = (crc >> 8) ^ TABLES[0][((crc ^ byte as u32) & 0xFF) as usize];
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::crc32_ieee;
+
+    /// The original bit-at-a-time implementation, kept as the reference.
+    fn crc32_bitwise(data: &[u8]) -> u32 {
+        let mut crc = 0xFFFF_FFFFu32;
+        for &byte in data {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                if (crc & 1) != 0 {
+                    crc = (crc >> 1) ^ 0xEDB8_8320;
+                } else {
+                    crc >>= 1;
+                }
+            }
+        }
+        !crc
+    }
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn golden_vector() {
+        assert_eq!(crc32_ieee(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32_ieee(b""), 0);
+    }
+
+    #[test]
+    fn matches_bitwise_reference_at_every_length_and_alignment() {
+        let data = pseudo_random(64 + 7);
+        for start in 0..8 {
+            for end in start..data.len() {
+                let slice = &data[start..end];
+                assert_eq!(crc32_ieee(slice), crc32_bitwise(slice), "{start}..{end}");
+            }
+        }
+    }
+
+    /// `cargo test --release -p rp2040-fred-protocol -- --ignored --nocapture crc32_throughput`
+    #[cfg(feature = "crc-slice8")]
+    #[test]
+    #[ignore = "benchmark; run explicitly in release mode"]
+    fn crc32_throughput() {
+        use std::time::Instant;
+
+        const PACKET_LEN: usize = 317;
+        const ROUNDS: usize = 20_000;
+        let data = pseudo_random(PACKET_LEN);
+
+        let mut acc = 0u32;
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            acc ^= crc32_bitwise(core::hint::black_box(&data));
+        }
+        let bitwise = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            acc ^= crc32_ieee(core::hint::black_box(&data));
+        }
+        let table = start.elapsed();
+        core::hint::black_box(acc);
+
+        let mib = (PACKET_LEN * ROUNDS) as f64 / (1024.0 * 1024.0);
+        std::println!(
+            "crc32 over {ROUNDS} x {PACKET_LEN}-byte packets: bitwise {:.1} MiB/s, table {:.1} MiB/s ({:.1}x)",
+            mib / bitwise.as_secs_f64(),
+            mib / table.as_secs_f64(),
+            bitwise.as_secs_f64() / table.as_secs_f64()
+        );
+        assert!(table < bitwise);
+    }
+}
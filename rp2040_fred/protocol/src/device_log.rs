@@ -0,0 +1,318 @@
+//! Firmware log records forwarded over the bridge as `LOG` packets.
+//!
+//! Fixed messages are interned as a [`LogId`] so both ends share the text and
+//! only two bytes cross the wire; anything else is sent as up to
+//! [`LOG_TEXT_MAX`] bytes of UTF-8. The firmware keeps records in a
+//! [`LogRing`] until a host asks for them with `LOG_SET`, so messages logged
+//! while no host was connected are not lost.
+
+use core::fmt;
+
+/// Longest free-text message; longer text is cut at a char boundary.
+pub const LOG_TEXT_MAX: usize = 48;
+/// `id` of a record that carries text instead of an interned message.
+pub const LOG_ID_TEXT: u16 = 0;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    #[default]
+    Info = 3,
+    Debug = 4,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 4] = [Self::Error, Self::Warn, Self::Info, Self::Debug];
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&level| level as u8 == v)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name() == name)
+    }
+}
+
+/// Interned firmware messages. Ids are never reused; a host that does not
+/// know one prints it numerically.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogId {
+    FirmwareStarted = 0x0001,
+    UsbConnected = 0x0002,
+    UsbReplyWriteFailed = 0x0003,
+    UsbReadFailed = 0x0004,
+    UsbStreamWriteFailed = 0x0005,
+    /// `arg` is the record version when it came from newer firmware.
+    ConfigUnreadable = 0x0010,
+    ConfigSaveFailed = 0x0011,
+}
+
+impl LogId {
+    pub const ALL: [LogId; 7] = [
+        Self::FirmwareStarted,
+        Self::UsbConnected,
+        Self::UsbReplyWriteFailed,
+        Self::UsbReadFailed,
+        Self::UsbStreamWriteFailed,
+        Self::ConfigUnreadable,
+        Self::ConfigSaveFailed,
+    ];
+
+    pub fn from_u16(v: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|&id| id as u16 == v)
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            Self::FirmwareStarted => "firmware started",
+            Self::UsbConnected => "USB host connected",
+            Self::UsbReplyWriteFailed => "USB write failed; dropping connection",
+            Self::UsbReadFailed => "USB read failed; dropping connection",
+            Self::UsbStreamWriteFailed => "USB telemetry write failed; dropping connection",
+            Self::ConfigUnreadable => "stored config unreadable; using defaults",
+            Self::ConfigSaveFailed => "config save to flash failed",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// A [`LogId`], or [`LOG_ID_TEXT`] for a free-text record.
+    pub id: u16,
+    /// Message-specific value, `0` when unused.
+    pub arg: u32,
+    /// Device microsecond timer when the record was made.
+    pub at_us: u64,
+    text: [u8; LOG_TEXT_MAX],
+    text_len: u8,
+}
+
+impl LogRecord {
+    pub const EMPTY: Self = Self {
+        level: LogLevel::Info,
+        id: LOG_ID_TEXT,
+        arg: 0,
+        at_us: 0,
+        text: [0; LOG_TEXT_MAX],
+        text_len: 0,
+    };
+
+    pub fn event(level: LogLevel, id: LogId, arg: u32, at_us: u64) -> Self {
+        Self {
+            level,
+            id: id as u16,
+            arg,
+            at_us,
+            ..Self::EMPTY
+        }
+    }
+
+    pub fn text(level: LogLevel, text: &str, at_us: u64) -> Self {
+        let mut len = text.len().min(LOG_TEXT_MAX);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut record = Self {
+            level,
+            at_us,
+            text_len: len as u8,
+            ..Self::EMPTY
+        };
+        record.text[..len].copy_from_slice(&text.as_bytes()[..len]);
+        record
+    }
+
+    /// Builds a record from wire fields; `None` if `text` is too long or
+    /// not UTF-8.
+    pub fn from_parts(level: LogLevel, id: u16, arg: u32, at_us: u64, text: &[u8]) -> Option<Self> {
+        if text.len() > LOG_TEXT_MAX || core::str::from_utf8(text).is_err() {
+            return None;
+        }
+        let mut record = Self {
+            level,
+            id,
+            arg,
+            at_us,
+            text_len: text.len() as u8,
+            ..Self::EMPTY
+        };
+        record.text[..text.len()].copy_from_slice(text);
+        Some(record)
+    }
+
+    pub fn log_id(&self) -> Option<LogId> {
+        LogId::from_u16(self.id)
+    }
+
+    /// The free text; empty for interned records.
+    pub fn text_str(&self) -> &str {
+        core::str::from_utf8(self.text_bytes()).unwrap_or("")
+    }
+
+    pub fn text_bytes(&self) -> &[u8] {
+        &self.text[..self.text_len as usize]
+    }
+}
+
+/// The message as a person should read it: the free text, the interned
+/// text, or the raw id for messages from newer firmware. A non-zero `arg`
+/// is appended.
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.id, self.log_id()) {
+            (LOG_ID_TEXT, _) => f.write_str(self.text_str())?,
+            (_, Some(id)) => f.write_str(id.text())?,
+            (id, None) => write!(f, "log message 0x{id:04X}")?,
+        }
+        if self.arg != 0 {
+            write!(f, " ({})", self.arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bounded log buffer. When full, a new record replaces the oldest and the
+/// loss is counted so the reader can report it.
+pub struct LogRing<const N: usize> {
+    records: [LogRecord; N],
+    head: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const N: usize> LogRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            records: [LogRecord::EMPTY; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: LogRecord) {
+        if N == 0 {
+            self.dropped = self.dropped.saturating_add(1);
+            return;
+        }
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        self.records[(self.head + self.len) % N] = record;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<LogRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        let record = self.records[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(record)
+    }
+
+    /// Records overwritten since the last call.
+    pub fn take_dropped(&mut self) -> u32 {
+        core::mem::take(&mut self.dropped)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const N: usize> Default for LogRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use super::{LogId, LogLevel, LogRecord, LogRing, LOG_TEXT_MAX};
+
+    #[test]
+    fn text_is_truncated_at_a_char_boundary() {
+        let long = "é".repeat(LOG_TEXT_MAX);
+        let record = LogRecord::text(LogLevel::Warn, &long, 5);
+        assert_eq!(record.text_str().len(), LOG_TEXT_MAX);
+        assert!(record.text_str().chars().all(|c| c == 'é'));
+
+        // A three-byte char straddling the limit is dropped whole.
+        let straddle = "a".repeat(LOG_TEXT_MAX - 1) + "€";
+        let record = LogRecord::text(LogLevel::Info, &straddle, 0);
+        assert_eq!(record.text_str(), &straddle[..LOG_TEXT_MAX - 1]);
+
+        assert_eq!(
+            LogRecord::from_parts(LogLevel::Info, 0, 0, 0, &[0xFF]),
+            None,
+            "not UTF-8"
+        );
+    }
+
+    #[test]
+    fn record_display_names_interned_messages() {
+        let record = LogRecord::event(LogLevel::Warn, LogId::UsbReplyWriteFailed, 0, 0);
+        assert_eq!(record.to_string(), "USB write failed; dropping connection");
+        let record = LogRecord::event(LogLevel::Warn, LogId::ConfigUnreadable, 3, 0);
+        assert_eq!(
+            record.to_string(),
+            "stored config unreadable; using defaults (3)"
+        );
+        let mut record = LogRecord::text(LogLevel::Debug, "hello", 0);
+        assert_eq!(record.to_string(), "hello");
+        record.id = 0x7777;
+        assert_eq!(record.to_string(), "log message 0x7777");
+
+        for id in LogId::ALL {
+            assert_eq!(LogId::from_u16(id as u16), Some(id));
+        }
+        assert!(LogLevel::Error < LogLevel::Debug);
+        assert_eq!(LogLevel::from_name("warn"), Some(LogLevel::Warn));
+    }
+
+    #[test]
+    fn ring_drops_oldest_and_counts_losses() {
+        let mut ring = LogRing::<3>::new();
+        for at_us in 0..5 {
+            ring.push(LogRecord::event(
+                LogLevel::Info,
+                LogId::UsbConnected,
+                0,
+                at_us,
+            ));
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.take_dropped(), 2);
+        assert_eq!(ring.take_dropped(), 0);
+        let times: std::vec::Vec<u64> = core::iter::from_fn(|| ring.pop())
+            .map(|r| r.at_us)
+            .collect();
+        assert_eq!(times, [2, 3, 4]);
+        assert!(ring.is_empty());
+    }
+}
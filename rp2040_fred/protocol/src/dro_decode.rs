@@ -1,5 +1,9 @@
 #![allow(dead_code)]
 
+use crate::bcd;
+use crate::bridge_proto::{LinearUnits, UnitConfig, XAxisMode};
+use crate::command_map::{CommandMap, DroAxis, DroField};
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct DroSnapshot {
     pub x_counts: i32,
@@ -7,117 +11,582 @@ pub struct DroSnapshot {
     pub rpm: u16,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Calibration {
-    pub x_counts_per_mm: f32,
-    pub z_counts_per_mm: f32,
+/// How one axis's counts become a position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisCalibration {
+    /// Finite and positive.
+    pub counts_per_mm: f32,
+    /// The axis counts the other way from how positions should read.
+    pub inverted: bool,
+    /// Added after inversion, in counts. On X it is a radius, doubled with
+    /// the reading in diameter mode, so a zero set in one mode holds in the
+    /// other.
+    pub zero_offset: i32,
 }
 
-impl Default for Calibration {
-    fn default() -> Self {
-        Self {
-            x_counts_per_mm: 100.0,
-            z_counts_per_mm: 100.0,
+impl AxisCalibration {
+    pub const DEFAULT: Self = Self {
+        counts_per_mm: 100.0,
+        inverted: false,
+        zero_offset: 0,
+    };
+
+    /// The reading with direction and zero offset applied, still in counts.
+    pub fn adjust(&self, counts: i32) -> i32 {
+        adjust(counts, self.inverted, self.zero_offset)
+    }
+
+    /// The integer form, counts per mm rounded to three decimals; `None`
+    /// if the scale does not fit.
+    pub fn to_fixed(&self) -> Option<FixedAxisCalibration> {
+        let counts_per_m = self.counts_per_mm * 1000.0 + 0.5;
+        if !(1.0..u32::MAX as f32).contains(&counts_per_m) {
+            return None;
+        }
+        Some(FixedAxisCalibration {
+            counts_per_m: counts_per_m as u32,
+            inverted: self.inverted,
+            zero_offset: self.zero_offset,
+        })
+    }
+
+    /// CNCMAN's `N%`: counts per displayed unit, `100` metric and `2540`
+    /// imperial at the default scale.
+    pub fn counts_per_unit(&self, units: LinearUnits) -> f32 {
+        match units {
+            LinearUnits::Metric => self.counts_per_mm,
+            LinearUnits::Imperial => self.counts_per_mm * MM_PER_INCH,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct AxisScratch {
-    sign_neg: bool,
-    b2: u8,
-    b1: u8,
-    b0: u8,
+fn adjust(counts: i32, inverted: bool, zero_offset: i32) -> i32 {
+    let counts = if inverted {
+        counts.saturating_neg()
+    } else {
+        counts
+    };
+    counts.saturating_add(zero_offset)
 }
 
-impl Default for AxisScratch {
+impl Default for AxisCalibration {
     fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Calibration {
+    pub x: AxisCalibration,
+    pub z: AxisCalibration,
+}
+
+impl Calibration {
+    /// See [`AxisCalibration::to_fixed`].
+    pub fn to_fixed(&self) -> Option<FixedCalibration> {
+        Some(FixedCalibration {
+            x: self.x.to_fixed()?,
+            z: self.z.to_fixed()?,
+        })
+    }
+}
+
+/// A [`Calibration`] and how to present positions. Units and X mode left
+/// at `None` follow what the device reports (`UNIT_CFG`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CalibrationProfile {
+    pub calibration: Calibration,
+    pub units: Option<LinearUnits>,
+    pub x_mode: Option<XAxisMode>,
+}
+
+impl CalibrationProfile {
+    /// The presentation to use when the device reports `device`.
+    pub fn unit_config(&self, device: UnitConfig) -> UnitConfig {
+        UnitConfig {
+            units: self.units.unwrap_or(device.units),
+            x_mode: self.x_mode.unwrap_or(device.x_mode),
+        }
+    }
+}
+
+/// How the lathe encodes the six axis digits and four speed digits it
+/// answers on FCF1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DroEncoding {
+    /// Packed BCD, two digits per byte, as TCL125 v2.02 sends them.
+    #[default]
+    Bcd,
+    /// Big-endian binary magnitudes: 24-bit axes, 16-bit speed.
+    Binary,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AxisSnapshot {
+    pub negative: bool,
+    pub value: u32,
+}
+
+impl AxisSnapshot {
+    pub fn from_count(count: i32) -> Self {
         Self {
-            sign_neg: false,
-            b2: 0,
-            b1: 0,
-            b0: 0,
+            negative: count < 0,
+            value: count.unsigned_abs(),
         }
     }
+
+    pub fn count(&self) -> i32 {
+        if self.negative {
+            -(self.value as i32)
+        } else {
+            self.value as i32
+        }
+    }
+
+    /// Signed seven-character field as the ROM writes it to
+    /// `event_feedback_buf`, e.g. `-000652`.
+    pub fn digits(&self) -> AxisDigits {
+        let mut text = [b'0'; 7];
+        text[0] = if self.negative { b'-' } else { b'+' };
+        let mut value = self.value;
+        for slot in text[1..].iter_mut().rev() {
+            *slot = b'0' + (value % 10) as u8;
+            value /= 10;
+        }
+        AxisDigits(text)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AxisDigits([u8; 7]);
+
+impl AxisDigits {
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.0).unwrap_or("")
+    }
+}
+
+impl PartialEq<&str> for AxisDigits {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl core::fmt::Display for AxisDigits {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The speed the ROM displays: its callback for the last speed pair (`0C`
+/// on TCL125) forces the last digit to zero.
+pub fn rpm_display(rpm_raw: u16) -> u16 {
+    (rpm_raw / 10) * 10
+}
+
+#[derive(Clone, Copy, Debug)]
+struct AxisScratch {
+    sign_seen: bool,
+    negative: bool,
+    /// Most significant first: responses to the axis's digit commands.
+    bytes: [u8; 3],
+    byte_mask: u8,
 }
 
 impl AxisScratch {
-    fn counts(&self) -> i32 {
-        let mag = ((self.b2 as u32) << 16) | ((self.b1 as u32) << 8) | self.b0 as u32;
-        if self.sign_neg {
-            -(mag as i32)
-        } else {
-            mag as i32
+    const EMPTY: Self = Self {
+        sign_seen: false,
+        negative: false,
+        bytes: [0; 3],
+        byte_mask: 0,
+    };
+
+    fn magnitude(&self, encoding: DroEncoding) -> u32 {
+        match encoding {
+            DroEncoding::Bcd => bcd::decode_axis(self.bytes).unwrap_or(0),
+            DroEncoding::Binary => {
+                ((self.bytes[0] as u32) << 16)
+                    | ((self.bytes[1] as u32) << 8)
+                    | self.bytes[2] as u32
+            }
+        }
+    }
+
+    fn snapshot(&self, encoding: DroEncoding) -> Option<AxisSnapshot> {
+        if !self.sign_seen || self.byte_mask != 0b111 {
+            return None;
         }
+        Some(AxisSnapshot {
+            negative: self.negative,
+            value: self.magnitude(encoding),
+        })
     }
 }
 
+/// Rebuilds axis counts and spindle speed from FC80 command / FCF1
+/// response pairs, in BCD or binary, reading commands through a
+/// [`CommandMap`].
+///
+/// [`new`](Self::new) detects the encoding: it starts as BCD and switches
+/// to binary for good on the first digit byte that is not packed BCD. A
+/// binary stream whose bytes all happen to be valid BCD is read as BCD until
+/// then, so use [`with_encoding`](Self::with_encoding) when the source is
+/// known.
 pub struct DroAssembler {
+    map: CommandMap,
+    fixed: Option<DroEncoding>,
+    encoding: DroEncoding,
     x: AxisScratch,
     z: AxisScratch,
-    rpm_hi: u8,
-    rpm_lo: u8,
+    /// Responses to the speed commands, most significant first.
+    rpm: [u8; 2],
+    rpm_mask: u8,
+}
+
+impl Default for DroAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DroAssembler {
     pub const fn new() -> Self {
         Self {
-            x: AxisScratch {
-                sign_neg: false,
-                b2: 0,
-                b1: 0,
-                b0: 0,
-            },
-            z: AxisScratch {
-                sign_neg: false,
-                b2: 0,
-                b1: 0,
-                b0: 0,
-            },
-            rpm_hi: 0,
-            rpm_lo: 0,
+            map: CommandMap::TCL125_V202,
+            fixed: None,
+            encoding: DroEncoding::Bcd,
+            x: AxisScratch::EMPTY,
+            z: AxisScratch::EMPTY,
+            rpm: [0; 2],
+            rpm_mask: 0,
         }
     }
 
-    pub fn on_fc80_fcf1(&mut self, cmd: u8, response: u8) {
-        match cmd {
-            0x03 => self.x.sign_neg = response != 0,
-            0x02 => self.x.b2 = response,
-            0x01 => self.x.b1 = response,
-            0x00 => self.x.b0 = response,
-            0x07 => self.z.sign_neg = response != 0,
-            0x06 => self.z.b2 = response,
-            0x05 => self.z.b1 = response,
-            0x04 => self.z.b0 = response,
-            0x0D => self.rpm_hi = response,
-            0x0C => self.rpm_lo = response,
-            _ => {}
+    pub const fn with_encoding(encoding: DroEncoding) -> Self {
+        let mut assembler = Self::new();
+        assembler.fixed = Some(encoding);
+        assembler.encoding = encoding;
+        assembler
+    }
+
+    /// For a ROM whose commands differ from TCL125 v2.02.
+    pub const fn with_command_map(encoding: DroEncoding, map: CommandMap) -> Self {
+        let mut assembler = Self::with_encoding(encoding);
+        assembler.map = map;
+        assembler
+    }
+
+    pub fn command_map(&self) -> &CommandMap {
+        &self.map
+    }
+
+    /// The encoding in use: fixed, or detected so far.
+    pub fn encoding(&self) -> DroEncoding {
+        self.encoding
+    }
+
+    /// Stores one response. Returns `false`, keeping the previous value, for
+    /// a digit byte that is not packed BCD while fixed to BCD.
+    pub fn on_fc80_fcf1(&mut self, cmd: u8, response: u8) -> bool {
+        let Some(field) = self.map.field(cmd) else {
+            return true;
+        };
+        let digits = !matches!(field, DroField::Sign(_));
+        if digits && !bcd::is_packed(response) && self.encoding == DroEncoding::Bcd {
+            if self.fixed.is_some() {
+                return false;
+            }
+            self.encoding = DroEncoding::Binary;
+        }
+        match field {
+            DroField::Sign(axis) => set_sign(self.axis_mut(axis), response),
+            DroField::Digits(axis, idx) => set_byte(self.axis_mut(axis), idx, response),
+            DroField::Rpm(idx) => self.set_rpm(idx, response),
         }
+        true
+    }
+
+    /// `None` until the sign and all three digit bytes have been seen.
+    pub fn x_axis(&self) -> Option<AxisSnapshot> {
+        self.x.snapshot(self.encoding)
+    }
+
+    pub fn z_axis(&self) -> Option<AxisSnapshot> {
+        self.z.snapshot(self.encoding)
     }
 
+    /// `None` until both speed bytes have been seen.
+    pub fn rpm_raw(&self) -> Option<u16> {
+        (self.rpm_mask == 0b11).then(|| self.rpm_value())
+    }
+
+    /// Latest values, with zero for anything not yet seen.
     pub fn snapshot(&self) -> DroSnapshot {
+        let count = |axis: &AxisScratch| {
+            let magnitude = axis.magnitude(self.encoding) as i32;
+            if axis.negative {
+                -magnitude
+            } else {
+                magnitude
+            }
+        };
         DroSnapshot {
-            x_counts: self.x.counts(),
-            z_counts: self.z.counts(),
-            rpm: ((self.rpm_hi as u16) << 8) | self.rpm_lo as u16,
+            x_counts: count(&self.x),
+            z_counts: count(&self.z),
+            rpm: self.rpm_value(),
         }
     }
+
+    fn axis_mut(&mut self, axis: DroAxis) -> &mut AxisScratch {
+        match axis {
+            DroAxis::X => &mut self.x,
+            DroAxis::Z => &mut self.z,
+        }
+    }
+
+    fn set_rpm(&mut self, idx: usize, response: u8) {
+        self.rpm[idx] = response;
+        self.rpm_mask |= 1 << idx;
+    }
+
+    fn rpm_value(&self) -> u16 {
+        match self.encoding {
+            DroEncoding::Bcd => bcd::decode_rpm(self.rpm).unwrap_or(0),
+            DroEncoding::Binary => ((self.rpm[0] as u16) << 8) | self.rpm[1] as u16,
+        }
+    }
+}
+
+fn set_sign(axis: &mut AxisScratch, response: u8) {
+    axis.sign_seen = true;
+    axis.negative = response != 0;
+}
+
+fn set_byte(axis: &mut AxisScratch, idx: usize, response: u8) {
+    axis.bytes[idx] = response;
+    axis.byte_mask |= 1 << idx;
+}
+
+/// Default [`AxisJumpFilter`] limit: 40 mm/s at the default 100 counts/mm,
+/// well above the lathe's rapid rate.
+pub const DEFAULT_MAX_COUNTS_PER_S: u32 = 4_000;
+/// Time between two readings assumed by callers whose trace has no
+/// timestamps.
+pub const ASSUMED_CADENCE_US: u64 = 20_000;
+/// Jumps a torn read produces: the three digit pairs are read
+/// milliseconds apart, so a carry between two pairs leaves one of them a
+/// step behind.
+const TEAR_STEPS: [i32; 4] = [100, -100, 10_000, -10_000];
+/// Allowed on top of the velocity limit, for readings close together.
+const JUMP_SLACK_COUNTS: u64 = 2;
+
+/// What [`AxisJumpFilter::check`] did with a reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AxisCheck {
+    /// Within the limit of the last value.
+    Accepted,
+    /// Too far from the last value but consistent with the reading before:
+    /// a real jump, e.g. the axis was zeroed.
+    Resynced,
+    /// A torn read, off from a plausible value by a digit-pair carry; the
+    /// plausible value was used instead of `raw`.
+    Corrected { raw: i32 },
+    /// Too far and not a carry; the last value was kept.
+    Rejected { raw: i32 },
+}
+
+impl AxisCheck {
+    /// The reading was replaced.
+    pub fn is_correction(&self) -> bool {
+        matches!(self, Self::Corrected { .. } | Self::Rejected { .. })
+    }
 }
 
+/// Rejects axis readings that would need the axis to move faster than a
+/// set limit, mending those off by a digit-pair carry.
+#[derive(Clone, Copy, Debug)]
+pub struct AxisJumpFilter {
+    /// `0` passes every reading.
+    max_counts_per_s: u32,
+    /// Last value passed on, and when it was read.
+    last: Option<(i32, u64)>,
+    /// Last reading as received.
+    last_raw: Option<(i32, u64)>,
+    corrections: u32,
+}
+
+impl AxisJumpFilter {
+    pub const fn new(max_counts_per_s: u32) -> Self {
+        Self {
+            max_counts_per_s,
+            last: None,
+            last_raw: None,
+            corrections: 0,
+        }
+    }
+
+    /// Returns the value to show for a reading taken at `at_us`, which
+    /// must not go backwards.
+    pub fn check(&mut self, raw: i32, at_us: u64) -> (i32, AxisCheck) {
+        let previous_raw = self.last_raw.replace((raw, at_us));
+        let Some(last) = self.last.filter(|_| self.max_counts_per_s != 0) else {
+            self.last = Some((raw, at_us));
+            return (raw, AxisCheck::Accepted);
+        };
+
+        let (value, check) = if self.plausible(last, raw, at_us) {
+            (raw, AxisCheck::Accepted)
+        } else if previous_raw.is_some_and(|previous| self.plausible(previous, raw, at_us)) {
+            (raw, AxisCheck::Resynced)
+        } else if let Some(value) = TEAR_STEPS
+            .iter()
+            .map(|step| raw.saturating_add(*step))
+            .find(|&value| self.plausible(last, value, at_us))
+        {
+            (value, AxisCheck::Corrected { raw })
+        } else {
+            (last.0, AxisCheck::Rejected { raw })
+        };
+        if check.is_correction() {
+            self.corrections = self.corrections.wrapping_add(1);
+        }
+        if !matches!(check, AxisCheck::Rejected { .. }) {
+            self.last = Some((value, at_us));
+        }
+        (value, check)
+    }
+
+    /// Readings replaced so far.
+    pub fn corrections(&self) -> u32 {
+        self.corrections
+    }
+
+    fn plausible(&self, (from, from_us): (i32, u64), to: i32, at_us: u64) -> bool {
+        let elapsed_us = at_us.saturating_sub(from_us);
+        let allowed = self.max_counts_per_s as u64 * elapsed_us / 1_000_000 + JUMP_SLACK_COUNTS;
+        from.abs_diff(to) as u64 <= allowed
+    }
+}
+
+pub const MM_PER_INCH: f32 = 25.4;
+
+/// Millimetres with X as a diameter, as CNCMAN shows them by default.
 pub fn counts_to_mm(snapshot: DroSnapshot, cal: Calibration) -> (f32, f32, u16) {
-    // CNCMAN uses diameter semantics for X (x*2), direct for Z.
-    let x_mm = ((snapshot.x_counts as f32) * 2.0) / cal.x_counts_per_mm;
-    let z_mm = (snapshot.z_counts as f32) / cal.z_counts_per_mm;
-    (x_mm, z_mm, snapshot.rpm)
+    counts_to_units(snapshot, cal, UnitConfig::default())
+}
+
+/// Converts to millimetres or inches: each axis adjusted by its
+/// calibration, X doubled in diameter mode (CNCMAN's `TEMP*2`), then
+/// divided by `N%`.
+pub fn counts_to_units(
+    snapshot: DroSnapshot,
+    cal: Calibration,
+    units: UnitConfig,
+) -> (f32, f32, u16) {
+    let x_scale = match units.x_mode {
+        XAxisMode::Diameter => 2.0,
+        XAxisMode::Radius => 1.0,
+    };
+    let x = cal.x.adjust(snapshot.x_counts) as f32 * x_scale / cal.x.counts_per_unit(units.units);
+    let z = cal.z.adjust(snapshot.z_counts) as f32 / cal.z.counts_per_unit(units.units);
+    (x, z, snapshot.rpm)
+}
+
+/// Default scale of [`FixedAxisCalibration`]: 100 counts/mm.
+pub const DEFAULT_COUNTS_PER_M: u32 = 100_000;
+/// Micrometres in a metre.
+const UM_PER_M: i64 = 1_000_000;
+/// Ten-thousandths of an inch in a metre, over [`TENTHS_PER_M_DIV`]:
+/// `10_000 / 0.0254`.
+const TENTHS_PER_M: i64 = 100_000_000;
+const TENTHS_PER_M_DIV: i64 = 254;
+
+/// [`AxisCalibration`] with an integer scale, for firmware without an FPU.
+/// Counts per metre keep three decimals of counts per mm exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedAxisCalibration {
+    /// Non-zero.
+    pub counts_per_m: u32,
+    pub inverted: bool,
+    pub zero_offset: i32,
+}
+
+impl FixedAxisCalibration {
+    pub const DEFAULT: Self = Self {
+        counts_per_m: DEFAULT_COUNTS_PER_M,
+        inverted: false,
+        zero_offset: 0,
+    };
+
+    /// Same as [`AxisCalibration::adjust`].
+    pub fn adjust(&self, counts: i32) -> i32 {
+        adjust(counts, self.inverted, self.zero_offset)
+    }
+
+    /// Micrometres, `diameter` doubling an X reading.
+    pub fn to_um(&self, counts: i32, diameter: bool) -> i32 {
+        self.scale(counts, diameter, UM_PER_M, 1)
+    }
+
+    /// Ten-thousandths of an inch, `diameter` doubling an X reading.
+    pub fn to_ten_thousandths(&self, counts: i32, diameter: bool) -> i32 {
+        self.scale(counts, diameter, TENTHS_PER_M, TENTHS_PER_M_DIV)
+    }
+
+    /// Micrometres when metric, ten-thousandths of an inch when imperial.
+    pub fn to_units(&self, counts: i32, diameter: bool, units: LinearUnits) -> i32 {
+        match units {
+            LinearUnits::Metric => self.to_um(counts, diameter),
+            LinearUnits::Imperial => self.to_ten_thousandths(counts, diameter),
+        }
+    }
+
+    /// Rounded down, as CNCMAN's `INT()`: -0.5 µm is -1, not 0. Saturates
+    /// at the `i32` range.
+    fn scale(&self, counts: i32, diameter: bool, num: i64, div: i64) -> i32 {
+        let counts = self.adjust(counts) as i64 * if diameter { 2 } else { 1 };
+        let value = (counts * num).div_euclid(self.counts_per_m.max(1) as i64 * div);
+        value.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+}
+
+impl Default for FixedAxisCalibration {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixedCalibration {
+    pub x: FixedAxisCalibration,
+    pub z: FixedAxisCalibration,
+}
+
+/// The integer form of [`counts_to_units`]: micrometres or ten-thousandths
+/// of an inch, rounded down.
+pub fn counts_to_fixed_units(
+    snapshot: DroSnapshot,
+    cal: FixedCalibration,
+    units: UnitConfig,
+) -> (i32, i32, u16) {
+    let diameter = units.x_mode == XAxisMode::Diameter;
+    let x = cal.x.to_units(snapshot.x_counts, diameter, units.units);
+    let z = cal.z.to_units(snapshot.z_counts, false, units.units);
+    (x, z, snapshot.rpm)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{counts_to_mm, Calibration, DroAssembler};
+    use super::{
+        counts_to_fixed_units, counts_to_mm, counts_to_units, rpm_display, AxisCalibration,
+        AxisCheck, AxisJumpFilter, Calibration, CalibrationProfile, DroAssembler, DroEncoding,
+        DroSnapshot, FixedAxisCalibration,
+    };
+    use crate::bridge_proto::{LinearUnits, UnitConfig, XAxisMode};
 
     #[test]
-    fn assembler_rebuilds_values() {
-        let mut a = DroAssembler::new();
+    fn assembler_rebuilds_binary_values() {
+        let mut a = DroAssembler::with_encoding(DroEncoding::Binary);
         a.on_fc80_fcf1(0x03, 0x01); // negative
         a.on_fc80_fcf1(0x02, 0x00);
         a.on_fc80_fcf1(0x01, 0x00);
@@ -136,15 +605,275 @@ mod tests {
         assert_eq!(s.z_counts, 200);
         assert_eq!(s.rpm, 2000);
 
-        let (x_mm, z_mm, rpm) = counts_to_mm(
-            s,
-            Calibration {
-                x_counts_per_mm: 100.0,
-                z_counts_per_mm: 100.0,
-            },
-        );
+        let (x_mm, z_mm, rpm) = counts_to_mm(s, Calibration::default());
         assert!((x_mm + 2.0).abs() < 0.0001);
         assert!((z_mm - 2.0).abs() < 0.0001);
         assert_eq!(rpm, 2000);
     }
+
+    #[test]
+    fn assembler_rebuilds_bcd_values() {
+        let mut a = DroAssembler::new();
+        for (cmd, response) in [
+            (0x03, 0x01),
+            (0x02, 0x00),
+            (0x01, 0x06),
+            (0x00, 0x52),
+            (0x07, 0x00),
+            (0x06, 0x00),
+            (0x05, 0x12),
+        ] {
+            assert!(a.on_fc80_fcf1(cmd, response));
+        }
+        assert_eq!(a.z_axis(), None, "z incomplete");
+        assert_eq!(a.rpm_raw(), None);
+        a.on_fc80_fcf1(0x04, 0x34);
+        a.on_fc80_fcf1(0x0D, 0x07);
+        a.on_fc80_fcf1(0x0C, 0x83);
+
+        assert_eq!(a.encoding(), DroEncoding::Bcd);
+        let s = a.snapshot();
+        assert_eq!((s.x_counts, s.z_counts, s.rpm), (-652, 1234, 783));
+        assert_eq!(a.x_axis().expect("x").digits(), "-000652");
+        assert_eq!(rpm_display(s.rpm), 780);
+    }
+
+    #[test]
+    fn assembler_detects_binary_or_rejects_it_when_fixed() {
+        let mut auto = DroAssembler::new();
+        let mut bcd = DroAssembler::with_encoding(DroEncoding::Bcd);
+        for a in [&mut auto, &mut bcd] {
+            a.on_fc80_fcf1(0x03, 0x00);
+            a.on_fc80_fcf1(0x02, 0x00);
+            a.on_fc80_fcf1(0x01, 0x01);
+        }
+        // 0x2C is not packed BCD: 0x00012C = 300 in binary.
+        assert!(auto.on_fc80_fcf1(0x00, 0x2C));
+        assert!(!bcd.on_fc80_fcf1(0x00, 0x2C));
+
+        assert_eq!(auto.encoding(), DroEncoding::Binary);
+        assert_eq!(auto.snapshot().x_counts, 300);
+        assert_eq!(bcd.encoding(), DroEncoding::Bcd);
+        assert_eq!(bcd.x_axis(), None, "rejected byte leaves x incomplete");
+
+        // Sign bytes are never digits, so they do not trigger detection.
+        let mut sign_only = DroAssembler::new();
+        sign_only.on_fc80_fcf1(0x07, 0xFF);
+        assert_eq!(sign_only.encoding(), DroEncoding::Bcd);
+    }
+
+    #[test]
+    fn jump_filter_mends_carry_tears_and_follows_real_jumps() {
+        // 1000 counts/s: 20 counts per 20 ms reading.
+        let mut filter = AxisJumpFilter::new(1_000);
+        assert_eq!(filter.check(190, 0), (190, AxisCheck::Accepted));
+        assert_eq!(filter.check(199, 20_000), (199, AxisCheck::Accepted));
+        // Moving up through 200: the middle pair was read before the carry,
+        // the low pair after it.
+        assert_eq!(
+            filter.check(105, 40_000),
+            (205, AxisCheck::Corrected { raw: 105 })
+        );
+        assert_eq!(filter.check(212, 60_000), (212, AxisCheck::Accepted));
+
+        // Neither plausible nor a carry: held once, then followed when the
+        // next reading agrees.
+        assert_eq!(
+            filter.check(5_000, 80_000),
+            (212, AxisCheck::Rejected { raw: 5_000 })
+        );
+        assert_eq!(filter.check(5_000, 100_000), (5_000, AxisCheck::Resynced));
+
+        // Zeroing the axis from exactly 100 looks like a tear for one
+        // reading only.
+        let mut zeroed = AxisJumpFilter::new(1_000);
+        zeroed.check(100, 0);
+        assert_eq!(
+            zeroed.check(0, 20_000),
+            (100, AxisCheck::Corrected { raw: 0 })
+        );
+        assert_eq!(zeroed.check(0, 40_000), (0, AxisCheck::Resynced));
+        assert_eq!(filter.corrections() + zeroed.corrections(), 3);
+
+        let mut off = AxisJumpFilter::new(0);
+        off.check(0, 0);
+        assert_eq!(off.check(10_000, 1), (10_000, AxisCheck::Accepted));
+    }
+
+    #[test]
+    fn unit_config_selects_radius_and_imperial() {
+        let s = DroSnapshot {
+            x_counts: 1270,
+            z_counts: -2540,
+            rpm: 500,
+        };
+        let cal = Calibration::default();
+
+        let (x, z, _) = counts_to_units(
+            s,
+            cal,
+            UnitConfig {
+                units: LinearUnits::Metric,
+                x_mode: XAxisMode::Radius,
+            },
+        );
+        assert!((x - 12.7).abs() < 0.0001);
+        assert!((z + 25.4).abs() < 0.0001);
+
+        // N%=2540: counts are read directly as ten-thousandths of an inch.
+        let (x, z, rpm) = counts_to_units(
+            s,
+            cal,
+            UnitConfig {
+                units: LinearUnits::Imperial,
+                x_mode: XAxisMode::Diameter,
+            },
+        );
+        assert!((x - 1.0).abs() < 0.0001);
+        assert!((z + 1.0).abs() < 0.0001);
+        assert_eq!(rpm, 500);
+    }
+
+    #[test]
+    fn calibration_inverts_offsets_and_scales_each_axis() {
+        let s = DroSnapshot {
+            x_counts: 1000,
+            z_counts: 1000,
+            rpm: 0,
+        };
+        let cal = Calibration {
+            x: AxisCalibration {
+                counts_per_mm: 200.0,
+                inverted: true,
+                zero_offset: 500,
+            },
+            z: AxisCalibration {
+                zero_offset: -1000,
+                ..AxisCalibration::DEFAULT
+            },
+        };
+        assert_eq!(cal.x.adjust(1000), -500);
+        assert_eq!(cal.x.adjust(i32::MIN), i32::MAX);
+
+        // (500 - 1000) * 2 / 200
+        let (x, z, _) = counts_to_mm(s, cal);
+        assert!((x + 5.0).abs() < 0.0001);
+        assert_eq!(z, 0.0);
+
+        let (x, _, _) = counts_to_units(
+            s,
+            cal,
+            UnitConfig {
+                units: LinearUnits::Imperial,
+                x_mode: XAxisMode::Radius,
+            },
+        );
+        assert!((x + 500.0 / 5080.0).abs() < 0.000_001);
+        assert_eq!(
+            AxisCalibration::DEFAULT.counts_per_unit(LinearUnits::Imperial),
+            2540.0
+        );
+    }
+
+    #[test]
+    fn fixed_point_rounds_down_like_int() {
+        let cal = FixedAxisCalibration::DEFAULT;
+        assert_eq!(cal.to_um(-652, false), -6520);
+        assert_eq!(cal.to_um(-652, true), -13_040);
+        // 1 count is 10/2.54 tenths: INT() gives 3, and -4 below zero.
+        assert_eq!(cal.to_ten_thousandths(1, false), 3);
+        assert_eq!(cal.to_ten_thousandths(-1, false), -4);
+        assert_eq!(cal.to_ten_thousandths(127, false), 500);
+        assert_eq!(cal.to_ten_thousandths(-127, true), -1000);
+
+        // 3 counts/mm: a third of a millimetre per count.
+        let coarse = FixedAxisCalibration {
+            counts_per_m: 3_000,
+            ..FixedAxisCalibration::DEFAULT
+        };
+        assert_eq!(coarse.to_um(1, false), 333);
+        assert_eq!(coarse.to_um(-1, false), -334);
+        assert_eq!(coarse.to_um(i32::MAX, true), i32::MAX);
+        assert_eq!(
+            AxisCalibration {
+                counts_per_mm: 200.5,
+                ..AxisCalibration::DEFAULT
+            }
+            .to_fixed()
+            .map(|cal| cal.counts_per_m),
+            Some(200_500)
+        );
+        assert_eq!(
+            AxisCalibration {
+                counts_per_mm: 0.0,
+                ..AxisCalibration::DEFAULT
+            }
+            .to_fixed(),
+            None
+        );
+    }
+
+    #[test]
+    fn fixed_point_matches_the_float_path() {
+        let cal = Calibration {
+            x: AxisCalibration {
+                counts_per_mm: 200.0,
+                inverted: true,
+                zero_offset: 500,
+            },
+            z: AxisCalibration {
+                counts_per_mm: 81.92,
+                zero_offset: -1000,
+                ..AxisCalibration::DEFAULT
+            },
+        };
+        let fixed = cal.to_fixed().expect("representable");
+        for units in [LinearUnits::Metric, LinearUnits::Imperial] {
+            for x_mode in [XAxisMode::Diameter, XAxisMode::Radius] {
+                let units = UnitConfig { units, x_mode };
+                let per_unit = match units.units {
+                    LinearUnits::Metric => 1000.0,
+                    LinearUnits::Imperial => 10_000.0,
+                };
+                for counts in (-999_999..=999_999).step_by(7_919) {
+                    let s = DroSnapshot {
+                        x_counts: counts,
+                        z_counts: counts,
+                        rpm: 0,
+                    };
+                    let (x, z, _) = counts_to_units(s, cal, units);
+                    let (fx, fz, _) = counts_to_fixed_units(s, fixed, units);
+                    for (float, fixed) in [(x, fx), (z, fz)] {
+                        // f32 holds about seven significant digits.
+                        let float = float as f64 * per_unit;
+                        let slack = float.abs() * 1e-6 + 0.01;
+                        assert!(
+                            fixed as f64 <= float + slack && float < fixed as f64 + 1.0 + slack,
+                            "{units:?} {counts}: {fixed} vs {float}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn profile_overrides_only_what_it_sets() {
+        let device = UnitConfig {
+            units: LinearUnits::Imperial,
+            x_mode: XAxisMode::Diameter,
+        };
+        assert_eq!(CalibrationProfile::default().unit_config(device), device);
+        let profile = CalibrationProfile {
+            x_mode: Some(XAxisMode::Radius),
+            ..CalibrationProfile::default()
+        };
+        assert_eq!(
+            profile.unit_config(device),
+            UnitConfig {
+                units: LinearUnits::Imperial,
+                x_mode: XAxisMode::Radius,
+            }
+        );
+    }
 }
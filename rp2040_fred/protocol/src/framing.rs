@@ -0,0 +1,249 @@
+//! COBS framing for byte-stream links (UART, CDC-ACM, TCP).
+//!
+//! USB bulk transfers delimit packets for free; a serial stream does not.
+//! Each encoded packet is COBS-stuffed so it contains no `0x00`, then
+//! terminated by a single `0x00`. A receiver that joins mid-frame or sees a
+//! corrupted byte discards at most one frame and resynchronises on the next
+//! delimiter.
+
+use crate::bridge_proto::{DecodeError, Packet, PacketRef, PACKET_SIZE};
+
+pub const FRAME_DELIMITER: u8 = 0x00;
+/// Largest COBS frame for one packet, including the trailing delimiter.
+pub const MAX_FRAME_SIZE: usize = PACKET_SIZE + PACKET_SIZE / 254 + 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// More than `MAX_FRAME_SIZE` bytes arrived without a delimiter.
+    Overflow,
+    /// A COBS code byte pointed past the end of the frame.
+    Cobs,
+    Packet(DecodeError),
+}
+
+/// COBS-encodes `input` into `out` and appends the delimiter. Returns the
+/// number of bytes written. `out` must hold `input.len() + input.len() / 254 + 2`.
+pub fn cobs_encode(input: &[u8], out: &mut [u8]) -> usize {
+    let mut code_at = 0usize;
+    let mut written = 1usize;
+    let mut code = 1u8;
+
+    for &byte in input {
+        if byte == 0 {
+            out[code_at] = code;
+            code_at = written;
+            written += 1;
+            code = 1;
+            continue;
+        }
+        out[written] = byte;
+        written += 1;
+        code += 1;
+        if code == 0xFF {
+            out[code_at] = code;
+            code_at = written;
+            written += 1;
+            code = 1;
+        }
+    }
+
+    out[code_at] = code;
+    out[written] = FRAME_DELIMITER;
+    written + 1
+}
+
+/// Decodes one COBS frame (without its delimiter) in place and returns the
+/// decoded length.
+pub fn cobs_decode_in_place(frame: &mut [u8]) -> Result<usize, FrameError> {
+    let mut read = 0usize;
+    let mut written = 0usize;
+
+    while read < frame.len() {
+        let code = frame[read];
+        if code == 0 {
+            return Err(FrameError::Cobs);
+        }
+        read += 1;
+        let run = code as usize - 1;
+        if read + run > frame.len() {
+            return Err(FrameError::Cobs);
+        }
+        frame.copy_within(read..read + run, written);
+        read += run;
+        written += run;
+        if code != 0xFF && read < frame.len() {
+            frame[written] = 0;
+            written += 1;
+        }
+    }
+
+    Ok(written)
+}
+
+/// Encodes `pkt` as one delimited frame.
+pub fn encode_packet_frame(pkt: &Packet, out: &mut [u8; MAX_FRAME_SIZE]) -> usize {
+    let raw = pkt.encode();
+    cobs_encode(&raw[..pkt.encoded_len()], out)
+}
+
+/// Incremental frame decoder. Feed bytes as they arrive; a result is
+/// returned each time a delimiter closes a non-empty frame. Decoded packets
+/// borrow the decoder's buffer until the next `push`.
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME_SIZE],
+    len: usize,
+    overflowed: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0u8; MAX_FRAME_SIZE],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Result<PacketRef<'_>, FrameError>> {
+        if byte != FRAME_DELIMITER {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(FrameError::Overflow));
+        }
+        if len == 0 {
+            // Back-to-back delimiters are idle fill, not frames.
+            return None;
+        }
+
+        let frame = &mut self.buf[..len];
+        Some(
+            cobs_decode_in_place(frame)
+                .and_then(|n| PacketRef::decode(&frame[..n]).map_err(FrameError::Packet)),
+        )
+    }
+
+    /// Drops any partial frame, e.g. after reopening the port.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.overflowed = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cobs_decode_in_place, cobs_encode, encode_packet_frame, FrameDecoder, FrameError,
+        FRAME_DELIMITER, MAX_FRAME_SIZE,
+    };
+    use crate::bridge_proto::{DecodeError, MsgType, Packet, PAYLOAD_SIZE};
+
+    fn feed(decoder: &mut FrameDecoder, bytes: &[u8]) -> Option<Result<Packet, FrameError>> {
+        let mut last = None;
+        for &b in bytes {
+            if let Some(result) = decoder.push(b) {
+                assert!(last.is_none(), "more than one frame completed");
+                last = Some(result.map(|pkt| pkt.to_packet()));
+            }
+        }
+        last
+    }
+
+    #[test]
+    fn cobs_known_vectors() {
+        let cases: [(&[u8], &[u8]); 4] = [
+            (&[], &[0x01, 0x00]),
+            (&[0x00], &[0x01, 0x01, 0x00]),
+            (
+                &[0x11, 0x22, 0x00, 0x33],
+                &[0x03, 0x11, 0x22, 0x02, 0x33, 0x00],
+            ),
+            (&[0x11, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01, 0x00]),
+        ];
+        for (input, expected) in cases {
+            let mut out = [0u8; 16];
+            let n = cobs_encode(input, &mut out);
+            assert_eq!(&out[..n], expected);
+
+            let mut frame = [0u8; 16];
+            frame[..n - 1].copy_from_slice(&out[..n - 1]);
+            let m = cobs_decode_in_place(&mut frame[..n - 1]).expect("decode");
+            assert_eq!(&frame[..m], input);
+        }
+    }
+
+    #[test]
+    fn cobs_handles_long_runs() {
+        let input = [0xAAu8; 600];
+        let mut out = [0u8; 610];
+        let n = cobs_encode(&input, &mut out);
+        assert!(!out[..n - 1].contains(&FRAME_DELIMITER));
+        let m = cobs_decode_in_place(&mut out[..n - 1]).expect("decode");
+        assert_eq!(&out[..m], &input[..]);
+    }
+
+    #[test]
+    fn full_packet_fits_max_frame() {
+        let payload = [0u8; PAYLOAD_SIZE];
+        let pkt = Packet::new(MsgType::TraceSample, 0xFFFF, &payload).expect("packet");
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let n = encode_packet_frame(&pkt, &mut frame);
+        assert!(n <= MAX_FRAME_SIZE);
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(feed(&mut decoder, &frame[..n]), Some(Ok(pkt)));
+    }
+
+    #[test]
+    fn decoder_resyncs_after_noise_and_corruption() {
+        let first = Packet::ping(1);
+        let second = Packet::telemetry(2, 7, -5, 9, 1200, 0x01);
+        let mut a = [0u8; MAX_FRAME_SIZE];
+        let mut b = [0u8; MAX_FRAME_SIZE];
+        let na = encode_packet_frame(&first, &mut a);
+        let nb = encode_packet_frame(&second, &mut b);
+
+        let mut decoder = FrameDecoder::new();
+        // Tail of a frame we joined halfway through, then idle delimiters.
+        assert!(matches!(
+            feed(&mut decoder, &[0x42, 0x13, 0x00]),
+            Some(Err(_))
+        ));
+        assert_eq!(feed(&mut decoder, &[0x00, 0x00]), None);
+        assert_eq!(feed(&mut decoder, &a[..na]), Some(Ok(first)));
+
+        let mut corrupt = b;
+        corrupt[nb / 2] ^= 0x01;
+        assert!(matches!(
+            feed(&mut decoder, &corrupt[..nb]),
+            Some(Err(FrameError::Packet(DecodeError::BadCrc)) | Err(FrameError::Cobs))
+        ));
+        assert_eq!(feed(&mut decoder, &b[..nb]), Some(Ok(second)));
+    }
+
+    #[test]
+    fn decoder_reports_overflow_then_recovers() {
+        let mut decoder = FrameDecoder::new();
+        let junk = [0x55u8; MAX_FRAME_SIZE + 10];
+        assert_eq!(feed(&mut decoder, &junk), None);
+        assert_eq!(feed(&mut decoder, &[0x00]), Some(Err(FrameError::Overflow)));
+
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let n = encode_packet_frame(&Packet::ping(3), &mut frame);
+        assert_eq!(feed(&mut decoder, &frame[..n]), Some(Ok(Packet::ping(3))));
+    }
+}
@@ -0,0 +1,305 @@
+//! Groups passive bus cycles into the ROM's FRED handshake: poll `FCF0`
+//! until ready, write the command to `FC80`, poll again, read the response
+//! from `FCF1` (`event_main` in `tcl202.asm`).
+//!
+//! The sniffer drops samples when its FIFO is full, so a trace can hold a
+//! command with no response or a response with no command. Both are
+//! reported as they happen rather than paired with a neighbour.
+
+use crate::trace_decode::TraceCycle;
+
+pub const FRED_COMMAND_ADDR: u8 = 0x80;
+pub const FRED_STATUS_ADDR: u8 = 0xF0;
+pub const FRED_RESPONSE_ADDR: u8 = 0xF1;
+/// Distinct `FCF0` values one transaction keeps.
+pub const STATUS_VALUES_MAX: usize = 4;
+
+/// A value read from `FCF0`, the lathe controller's status register. The
+/// ROM polls it before writing each FC80 command and again before reading
+/// the FCF1 response; traces show `0x7D`/`0x7C` (busy/ready) in feedback
+/// mode and `0x3D`/`0x3C` while a program runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FredStatus(pub u8);
+
+impl FredStatus {
+    /// Bit 0: the controller has not finished the current command.
+    pub fn busy(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    /// Bit 7, tested by the ROM's `event_gate` (`$93DC`) before it redraws
+    /// one digit group.
+    pub fn event_gate(self) -> bool {
+        self.0 & 0x80 != 0
+    }
+}
+
+/// The distinct `FCF0` values of one transaction, in the order first seen.
+/// Values beyond [`STATUS_VALUES_MAX`] are not kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatusValues {
+    values: [u8; STATUS_VALUES_MAX],
+    len: u8,
+}
+
+impl StatusValues {
+    pub const EMPTY: Self = Self {
+        values: [0; STATUS_VALUES_MAX],
+        len: 0,
+    };
+
+    fn insert(&mut self, value: u8) {
+        let len = self.len as usize;
+        if len < STATUS_VALUES_MAX && !self.values[..len].contains(&value) {
+            self.values[len] = value;
+            self.len += 1;
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.values[..self.len as usize]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FredTransaction {
+    pub cmd: u8,
+    /// `FCF0` reads after the previous transaction and before `cmd` was
+    /// written, including the ROM's `event_gate` checks.
+    pub pre_polls: u32,
+    /// `FCF0` reads between the command write and the response read.
+    pub post_polls: u32,
+    /// The `post_polls` that found the controller busy.
+    pub busy_polls: u32,
+    pub status_values: StatusValues,
+    /// `None` when another command was written before the response was
+    /// read: the response, or the next command's poll, was dropped.
+    pub response: Option<u8>,
+    /// Trace sample index of the first poll, or of the command write when
+    /// there was none.
+    pub first_sample: u64,
+    /// Trace sample index of the response read, or of the last cycle seen
+    /// before the transaction was abandoned.
+    pub last_sample: u64,
+}
+
+impl FredTransaction {
+    pub fn is_complete(&self) -> bool {
+        self.response.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FredEvent {
+    /// A command and its response, or an abandoned command with
+    /// `response: None`.
+    Transaction(FredTransaction),
+    /// An `FCF1` read with no command pending; the write was dropped.
+    OrphanResponse { response: u8, sample: u64 },
+}
+
+/// Polls seen before the command of the transaction they belong to.
+#[derive(Clone, Copy, Debug)]
+struct Lead {
+    polls: u32,
+    status_values: StatusValues,
+    first_sample: Option<u64>,
+}
+
+impl Lead {
+    const EMPTY: Self = Self {
+        polls: 0,
+        status_values: StatusValues::EMPTY,
+        first_sample: None,
+    };
+}
+
+/// Turns [`TraceCycle`]s into [`FredEvent`]s. No allocation, so it runs on
+/// the device as well as the host.
+#[derive(Clone, Copy, Debug)]
+pub struct FredTransactionAssembler {
+    lead: Lead,
+    pending: Option<FredTransaction>,
+    last_status: Option<FredStatus>,
+    status_polls: u32,
+}
+
+impl Default for FredTransactionAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FredTransactionAssembler {
+    pub const fn new() -> Self {
+        Self {
+            lead: Lead::EMPTY,
+            pending: None,
+            last_status: None,
+            status_polls: 0,
+        }
+    }
+
+    /// The last `FCF0` value read; `None` before the first poll.
+    pub fn last_status(&self) -> Option<FredStatus> {
+        self.last_status
+    }
+
+    /// `FCF0` reads seen so far.
+    pub fn status_polls(&self) -> u32 {
+        self.status_polls
+    }
+
+    /// Takes one bus cycle. Cycles other than the three handshake accesses
+    /// are ignored.
+    pub fn push(&mut self, sample_index: u64, cycle: TraceCycle) -> Option<FredEvent> {
+        match (cycle.addr, cycle.read) {
+            (FRED_STATUS_ADDR, true) => {
+                self.on_status(sample_index, FredStatus(cycle.data));
+                None
+            }
+            (FRED_COMMAND_ADDR, false) => self.on_command(sample_index, cycle.data),
+            (FRED_RESPONSE_ADDR, true) => Some(self.on_response(sample_index, cycle.data)),
+            _ => None,
+        }
+    }
+
+    fn on_status(&mut self, sample_index: u64, status: FredStatus) {
+        self.last_status = Some(status);
+        self.status_polls = self.status_polls.wrapping_add(1);
+        match &mut self.pending {
+            Some(pending) => {
+                pending.post_polls = pending.post_polls.saturating_add(1);
+                if status.busy() {
+                    pending.busy_polls = pending.busy_polls.saturating_add(1);
+                }
+                pending.status_values.insert(status.0);
+                pending.last_sample = sample_index;
+            }
+            None => {
+                self.lead.polls = self.lead.polls.saturating_add(1);
+                self.lead.status_values.insert(status.0);
+                self.lead.first_sample.get_or_insert(sample_index);
+            }
+        }
+    }
+
+    /// Starts a transaction, returning the one it abandons.
+    fn on_command(&mut self, sample_index: u64, cmd: u8) -> Option<FredEvent> {
+        let lead = core::mem::replace(&mut self.lead, Lead::EMPTY);
+        let abandoned = self.pending.replace(FredTransaction {
+            cmd,
+            pre_polls: lead.polls,
+            post_polls: 0,
+            busy_polls: 0,
+            status_values: lead.status_values,
+            response: None,
+            first_sample: lead.first_sample.unwrap_or(sample_index),
+            last_sample: sample_index,
+        });
+        abandoned.map(FredEvent::Transaction)
+    }
+
+    fn on_response(&mut self, sample_index: u64, response: u8) -> FredEvent {
+        let Some(mut transaction) = self.pending.take() else {
+            // The polls belonged to the transaction whose write was lost.
+            self.lead = Lead::EMPTY;
+            return FredEvent::OrphanResponse {
+                response,
+                sample: sample_index,
+            };
+        };
+        transaction.response = Some(response);
+        transaction.last_sample = sample_index;
+        FredEvent::Transaction(transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{FredEvent, FredStatus, FredTransaction, FredTransactionAssembler};
+    use crate::trace_decode::TraceCycle;
+
+    fn run(cycles: &[(u8, u8, bool)]) -> (FredTransactionAssembler, Vec<FredEvent>) {
+        let mut assembler = FredTransactionAssembler::new();
+        let events = cycles
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &(data, addr, read))| {
+                assembler.push(i as u64, TraceCycle { data, addr, read })
+            })
+            .collect();
+        (assembler, events)
+    }
+
+    fn transaction(event: &FredEvent) -> FredTransaction {
+        match event {
+            FredEvent::Transaction(t) => *t,
+            other => panic!("expected a transaction, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn assembles_the_rom_handshake() {
+        let (assembler, events) = run(&[
+            (0x7D, 0xF0, true),
+            (0x7C, 0xF0, true),
+            (0x03, 0x80, false),
+            (0x7D, 0xF0, true),
+            (0x7D, 0xF0, true),
+            (0x7C, 0xF0, true),
+            (0x7C, 0xF0, true),
+            (0x01, 0xF1, true),
+            // Not part of the handshake.
+            (0x55, 0x81, true),
+        ]);
+        assert_eq!(events.len(), 1);
+        let t = transaction(&events[0]);
+        assert_eq!((t.cmd, t.response), (0x03, Some(0x01)));
+        assert_eq!((t.pre_polls, t.post_polls, t.busy_polls), (2, 4, 2));
+        assert_eq!(t.status_values.as_slice(), [0x7D, 0x7C]);
+        assert_eq!((t.first_sample, t.last_sample), (0, 7));
+        assert!(t.is_complete());
+        assert_eq!(assembler.status_polls(), 6);
+        assert_eq!(assembler.last_status(), Some(FredStatus(0x7C)));
+    }
+
+    #[test]
+    fn reports_abandoned_commands_and_orphan_responses() {
+        let (_, events) = run(&[
+            // Response read dropped: `02` is abandoned when `01` is written.
+            (0x02, 0x80, false),
+            (0x7C, 0xF0, true),
+            (0x01, 0x80, false),
+            (0x34, 0xF1, true),
+            // Command write dropped.
+            (0x7C, 0xF0, true),
+            (0x56, 0xF1, true),
+        ]);
+        assert_eq!(events.len(), 3);
+
+        let abandoned = transaction(&events[0]);
+        assert_eq!((abandoned.cmd, abandoned.response), (0x02, None));
+        assert_eq!((abandoned.post_polls, abandoned.last_sample), (1, 1));
+        assert!(!abandoned.is_complete());
+
+        let next = transaction(&events[1]);
+        assert_eq!(
+            (next.cmd, next.response, next.pre_polls),
+            (0x01, Some(0x34), 0)
+        );
+        assert_eq!(next.first_sample, 2);
+
+        assert_eq!(
+            events[2],
+            FredEvent::OrphanResponse {
+                response: 0x56,
+                sample: 5,
+            }
+        );
+    }
+}
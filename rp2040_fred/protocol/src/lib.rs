@@ -1,5 +1,12 @@
 #![no_std]
 
+pub mod bcd;
 pub mod bridge_proto;
+pub mod command_map;
+pub mod config;
+mod crc;
+pub mod device_log;
 pub mod dro_decode;
-pub mod trace_decode;
\ No newline at end of file
+pub mod framing;
+pub mod fred_transaction;
+pub mod trace_decode;
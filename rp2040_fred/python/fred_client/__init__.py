@@ -2,9 +2,19 @@
 
 from __future__ import annotations
 
-from typing import Dict
-
-from ._fred_native import FredProtocolError, FredUsbError
+from typing import Dict, Optional
+
+from ._fred_native import (
+    FredCaptureActiveError,
+    FredCaptureInactiveError,
+    FredInvalidPayloadError,
+    FredNackError,
+    FredProtocolError,
+    FredStorageError,
+    FredUndecodableError,
+    FredUnsupportedError,
+    FredUsbError,
+)
 from ._fred_native import FredUsbClient as _NativeFredUsbClient
 
 
@@ -21,20 +31,47 @@ class FredUsbClient:
         pid: int,
         *,
         timeout_ms: int = 250,
-        x_counts_per_mm: float = 100.0,
-        z_counts_per_mm: float = 100.0,
+        x_counts_per_mm: Optional[float] = None,
+        z_counts_per_mm: Optional[float] = None,
+        x_inverted: Optional[bool] = None,
+        z_inverted: Optional[bool] = None,
+        x_zero_offset: Optional[int] = None,
+        z_zero_offset: Optional[int] = None,
+        units: Optional[str] = None,
+        x_mode: Optional[str] = None,
     ) -> None:
+        """Opens the device with a calibration profile.
+
+        Calibration arguments left at ``None`` use the value stored on the
+        device (``fredctl config set``), or the uncalibrated default if it
+        has none. Zero offsets are in counts, X as a radius. ``units``
+        ("metric" or "imperial") and ``x_mode`` ("diameter" or "radius")
+        override what the device reports; ``None`` follows it. Bad values
+        raise ``ValueError``.
+        """
         self.vid = vid
         self.pid = pid
         self.timeout_ms = timeout_ms
         self.x_counts_per_mm = x_counts_per_mm
         self.z_counts_per_mm = z_counts_per_mm
+        self.x_inverted = x_inverted
+        self.z_inverted = z_inverted
+        self.x_zero_offset = x_zero_offset
+        self.z_zero_offset = z_zero_offset
+        self.units = units
+        self.x_mode = x_mode
         self._inner = _NativeFredUsbClient(
             vid,
             pid,
             timeout_ms=timeout_ms,
             x_counts_per_mm=x_counts_per_mm,
             z_counts_per_mm=z_counts_per_mm,
+            x_inverted=x_inverted,
+            z_inverted=z_inverted,
+            x_zero_offset=x_zero_offset,
+            z_zero_offset=z_zero_offset,
+            units=units,
+            x_mode=x_mode,
         )
 
     def close(self) -> None:
@@ -53,6 +90,23 @@ class FredUsbClient:
         self.close()
         return False
 
+    def device_info(self) -> Optional[Dict[str, object]]:
+        """Identity reported by the HELLO handshake run when the client opened.
+
+        Returns ``None`` for firmware that predates the handshake.
+        """
+        info = self._inner.device_info()
+        return None if info is None else dict(info)
+
+    def protocol_downgrade(self) -> Optional[Dict[str, object]]:
+        """Set once the device has answered in bridge protocol v1 or v2.
+
+        Keys: protocol_version, packet_size, first_msg_type, message. Trace
+        data from such a device has no drop/stall counters or timestamps.
+        """
+        downgrade = self._inner.protocol_downgrade()
+        return None if downgrade is None else dict(downgrade)
+
     def enable_polling(self, period_ms: int = 25) -> None:
         self._inner.enable_polling(period_ms=period_ms)
 
@@ -68,6 +122,10 @@ class FredUsbClient:
     def refresh(self) -> Dict[str, object]:
         return dict(self._inner.refresh())
 
+    def snapshot(self) -> Dict[str, object]:
+        """Poll one reading without enabling the telemetry stream."""
+        return dict(self._inner.snapshot())
+
     def read_capture_samples(self, timeout_ms: int = 1) -> list[int]:
         raise NotImplementedError(
             "Passive capture is not exposed in the Rust-backed Python client"
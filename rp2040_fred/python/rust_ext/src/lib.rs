@@ -1,17 +1,29 @@
 #![allow(unexpected_cfgs)]
+// pyo3 0.22 `#[pymethods]` expansion trips this on every `PyResult` return.
+#![allow(clippy::useless_conversion)]
 
 use std::io;
 use std::time::Duration;
 
 use fredctl::monitor::{FredMonitorClient, MonitorSnapshot};
+use fredctl::transport::{nack_error, NackError};
 use pyo3::create_exception;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::{PyAny, PyDict, PyModule};
-use rp2040_fred_protocol::dro_decode::Calibration;
+use rp2040_fred_protocol::bridge_proto::{LinearUnits, XAxisMode};
+use rp2040_fred_protocol::dro_decode::{AxisCalibration, Calibration};
 
 create_exception!(_fred_native, FredProtocolError, PyRuntimeError);
 create_exception!(_fred_native, FredUsbError, PyRuntimeError);
+// One subclass per `NackReason`; unknown reasons raise the base class.
+create_exception!(_fred_native, FredNackError, FredProtocolError);
+create_exception!(_fred_native, FredInvalidPayloadError, FredNackError);
+create_exception!(_fred_native, FredUndecodableError, FredNackError);
+create_exception!(_fred_native, FredCaptureActiveError, FredNackError);
+create_exception!(_fred_native, FredCaptureInactiveError, FredNackError);
+create_exception!(_fred_native, FredUnsupportedError, FredNackError);
+create_exception!(_fred_native, FredStorageError, FredNackError);
 
 #[pyclass(unsendable)]
 struct FredUsbClient {
@@ -20,26 +32,57 @@ struct FredUsbClient {
 
 #[pymethods]
 impl FredUsbClient {
+    /// Calibration arguments left at `None` take the value stored on the
+    /// device. `units` (`"metric"`/`"imperial"`) and `x_mode`
+    /// (`"diameter"`/`"radius"`) override the device's `UNIT_CFG` when set.
     #[new]
-    #[pyo3(signature = (vid, pid, *, timeout_ms=250, x_counts_per_mm=100.0, z_counts_per_mm=100.0))]
+    #[pyo3(signature = (
+        vid,
+        pid,
+        *,
+        timeout_ms=250,
+        x_counts_per_mm=None,
+        z_counts_per_mm=None,
+        x_inverted=None,
+        z_inverted=None,
+        x_zero_offset=None,
+        z_zero_offset=None,
+        units=None,
+        x_mode=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         vid: u16,
         pid: u16,
         timeout_ms: u64,
-        x_counts_per_mm: f32,
-        z_counts_per_mm: f32,
+        x_counts_per_mm: Option<f32>,
+        z_counts_per_mm: Option<f32>,
+        x_inverted: Option<bool>,
+        z_inverted: Option<bool>,
+        x_zero_offset: Option<i32>,
+        z_zero_offset: Option<i32>,
+        units: Option<&str>,
+        x_mode: Option<&str>,
     ) -> PyResult<Self> {
-        let calibration = Calibration {
-            x_counts_per_mm,
-            z_counts_per_mm,
-        };
-        let inner = FredMonitorClient::open_with_options(
-            vid,
-            pid,
-            Duration::from_millis(timeout_ms),
-            calibration,
-        )
-        .map_err(map_io_error)?;
+        let x_counts_per_mm = x_counts_per_mm
+            .map(|v| counts_per_mm("x_counts_per_mm", v))
+            .transpose()?;
+        let z_counts_per_mm = z_counts_per_mm
+            .map(|v| counts_per_mm("z_counts_per_mm", v))
+            .transpose()?;
+        let units = units.map(parse_units).transpose()?;
+        let x_mode = x_mode.map(parse_x_mode).transpose()?;
+
+        let mut inner =
+            FredMonitorClient::open_with_options(vid, pid, Duration::from_millis(timeout_ms), None)
+                .map_err(map_io_error)?;
+        let mut profile = inner.profile();
+        let Calibration { x, z } = &mut profile.calibration;
+        override_axis(x, x_counts_per_mm, x_inverted, x_zero_offset);
+        override_axis(z, z_counts_per_mm, z_inverted, z_zero_offset);
+        profile.units = units;
+        profile.x_mode = x_mode;
+        inner.set_profile(profile);
         Ok(Self { inner: Some(inner) })
     }
 
@@ -57,6 +100,56 @@ impl FredUsbClient {
         snapshot_to_dict(py, snapshot)
     }
 
+    fn snapshot<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let polled = self.with_client(py, FredMonitorClient::snapshot)?;
+        let dict = snapshot_to_dict(py, polled.snapshot)?;
+        dict.set_item("rpm_raw", polled.rpm_raw)?;
+        dict.set_item("age_ms", polled.age_ms)?;
+        dict.set_item("valid", polled.valid)?;
+        Ok(dict)
+    }
+
+    /// `None` when the firmware predates the `HELLO` handshake.
+    fn device_info<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let client = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| FredUsbError::new_err("device not open"))?;
+        let Some(info) = client.device_info() else {
+            return Ok(None);
+        };
+        let [major, minor, patch] = info.firmware_version;
+        let dict = PyDict::new_bound(py);
+        dict.set_item("firmware_version", format!("{major}.{minor}.{patch}"))?;
+        dict.set_item("git_hash", info.git_hash_str())?;
+        dict.set_item("transport", info.transport.name())?;
+        dict.set_item("protocol_version", info.protocol_version)?;
+        dict.set_item("max_payload", info.max_payload)?;
+        dict.set_item("trace_layout_version", info.trace_layout_version)?;
+        dict.set_item("pin_map_id", info.pin_map_id)?;
+        let supported: Vec<u8> = info.supported.iter().map(|t| t as u8).collect();
+        dict.set_item("supported_msg_types", supported)?;
+        Ok(Some(dict))
+    }
+
+    /// `None` unless the device has answered in protocol v1 or v2, whose
+    /// packets are converted with some fields missing.
+    fn protocol_downgrade<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let client = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| FredUsbError::new_err("device not open"))?;
+        let Some(downgrade) = client.downgrade() else {
+            return Ok(None);
+        };
+        let dict = PyDict::new_bound(py);
+        dict.set_item("protocol_version", downgrade.version.protocol_version())?;
+        dict.set_item("packet_size", downgrade.version.packet_size())?;
+        dict.set_item("first_msg_type", downgrade.first_msg_type as u8)?;
+        dict.set_item("message", downgrade.to_string())?;
+        Ok(Some(dict))
+    }
+
     fn close(&mut self, py: Python<'_>) {
         if let Some(client) = self.inner.take() {
             py.allow_threads(move || client.close());
@@ -94,6 +187,53 @@ impl FredUsbClient {
     }
 }
 
+fn override_axis(
+    axis: &mut AxisCalibration,
+    counts_per_mm: Option<f32>,
+    inverted: Option<bool>,
+    zero_offset: Option<i32>,
+) {
+    if let Some(v) = counts_per_mm {
+        axis.counts_per_mm = v;
+    }
+    if let Some(v) = inverted {
+        axis.inverted = v;
+    }
+    if let Some(v) = zero_offset {
+        axis.zero_offset = v;
+    }
+}
+
+fn counts_per_mm(name: &str, value: f32) -> PyResult<f32> {
+    if value.is_finite() && value > 0.0 {
+        Ok(value)
+    } else {
+        Err(PyValueError::new_err(format!(
+            "{name} must be positive, got {value}"
+        )))
+    }
+}
+
+fn parse_units(name: &str) -> PyResult<LinearUnits> {
+    match name {
+        "metric" => Ok(LinearUnits::Metric),
+        "imperial" => Ok(LinearUnits::Imperial),
+        _ => Err(PyValueError::new_err(format!(
+            "units must be 'metric' or 'imperial', got {name:?}"
+        ))),
+    }
+}
+
+fn parse_x_mode(name: &str) -> PyResult<XAxisMode> {
+    match name {
+        "diameter" => Ok(XAxisMode::Diameter),
+        "radius" => Ok(XAxisMode::Radius),
+        _ => Err(PyValueError::new_err(format!(
+            "x_mode must be 'diameter' or 'radius', got {name:?}"
+        ))),
+    }
+}
+
 fn snapshot_to_dict<'py>(
     py: Python<'py>,
     snapshot: MonitorSnapshot,
@@ -106,13 +246,42 @@ fn snapshot_to_dict<'py>(
     dict.set_item("z_counts", snapshot.z_counts)?;
     dict.set_item("tick", snapshot.tick)?;
     dict.set_item("flags", snapshot.flags)?;
+    dict.set_item(
+        "units",
+        match snapshot.units.units {
+            LinearUnits::Metric => "metric",
+            LinearUnits::Imperial => "imperial",
+        },
+    )?;
+    dict.set_item(
+        "x_mode",
+        match snapshot.units.x_mode {
+            XAxisMode::Diameter => "diameter",
+            XAxisMode::Radius => "radius",
+        },
+    )?;
+    dict.set_item("x_display", snapshot.x_display())?;
+    dict.set_item("z_display", snapshot.z_display())?;
     Ok(dict)
 }
 
 fn map_io_error(err: io::Error) -> PyErr {
     let message = err.to_string();
+    if let Some(nack) = nack_error(&err) {
+        return match nack {
+            NackError::InvalidPayload { .. } => FredInvalidPayloadError::new_err(message),
+            NackError::Undecodable => FredUndecodableError::new_err(message),
+            NackError::CaptureActive { .. } => FredCaptureActiveError::new_err(message),
+            NackError::CaptureInactive { .. } => FredCaptureInactiveError::new_err(message),
+            NackError::Unsupported { .. } => FredUnsupportedError::new_err(message),
+            NackError::Storage { .. } => FredStorageError::new_err(message),
+            NackError::Other { .. } => FredNackError::new_err(message),
+        };
+    }
     match err.kind() {
-        io::ErrorKind::InvalidData => FredProtocolError::new_err(message),
+        io::ErrorKind::InvalidData | io::ErrorKind::Unsupported => {
+            FredProtocolError::new_err(message)
+        }
         _ => FredUsbError::new_err(message),
     }
 }
@@ -125,5 +294,27 @@ fn _fred_native(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
         py.get_type_bound::<FredProtocolError>(),
     )?;
     m.add("FredUsbError", py.get_type_bound::<FredUsbError>())?;
+    m.add("FredNackError", py.get_type_bound::<FredNackError>())?;
+    m.add(
+        "FredInvalidPayloadError",
+        py.get_type_bound::<FredInvalidPayloadError>(),
+    )?;
+    m.add(
+        "FredUndecodableError",
+        py.get_type_bound::<FredUndecodableError>(),
+    )?;
+    m.add(
+        "FredCaptureActiveError",
+        py.get_type_bound::<FredCaptureActiveError>(),
+    )?;
+    m.add(
+        "FredCaptureInactiveError",
+        py.get_type_bound::<FredCaptureInactiveError>(),
+    )?;
+    m.add(
+        "FredUnsupportedError",
+        py.get_type_bound::<FredUnsupportedError>(),
+    )?;
+    m.add("FredStorageError", py.get_type_bound::<FredStorageError>())?;
     Ok(())
 }